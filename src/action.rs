@@ -0,0 +1,1007 @@
+use crate::keys;
+use schemars::JsonSchema;
+use schemars::r#gen::SchemaSettings;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt;
+
+// Every action the planner may emit and the executor knows how to run.
+// The JSON schema generated from this enum, with the description of every
+// variant, is injected into the planning prompt and offered as the planner's
+// tools, so adding a variant here is all it takes to teach the model.
+// Actions with screen coordinates take an optional `monitor`; when it is set
+// the coordinates are relative to that monitor's top-left corner.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "action", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Action {
+    #[schemars(
+        description = "Activate the window matching the title and class; method is the keyboard shortcut cycled through windows with only when no window manager can activate it"
+    )]
+    WindowFocus {
+        title: String,
+        class: String,
+        method: FocusMethod,
+    },
+    #[schemars(description = "Move a window's top-left corner to a point of the screenshot")]
+    WindowMove {
+        title: String,
+        #[serde(default)]
+        class: String,
+        x: i32,
+        y: i32,
+    },
+    #[schemars(description = "Resize a window, in screenshot pixels")]
+    WindowResize {
+        title: String,
+        #[serde(default)]
+        class: String,
+        width: u32,
+        height: u32,
+    },
+    #[schemars(description = "Maximize a window")]
+    WindowMaximize {
+        title: String,
+        #[serde(default)]
+        class: String,
+    },
+    #[schemars(description = "Minimize a window")]
+    WindowMinimize {
+        title: String,
+        #[serde(default)]
+        class: String,
+    },
+    #[schemars(description = "Close a window as its close button would")]
+    WindowClose {
+        title: String,
+        #[serde(default)]
+        class: String,
+    },
+    #[schemars(description = "Move the mouse to a point of the screenshot")]
+    MouseMove {
+        x: i32,
+        y: i32,
+        monitor: Option<usize>,
+    },
+    #[schemars(description = "Click at the current mouse position")]
+    MouseClick { button: MouseButton },
+    #[schemars(description = "Move the mouse to a point of the screenshot and click there")]
+    ClickAt {
+        x: i32,
+        y: i32,
+        #[serde(default)]
+        button: MouseButton,
+        monitor: Option<usize>,
+    },
+    #[schemars(
+        description = "Click the centre of an element marked with its number on the screenshot"
+    )]
+    ClickElement {
+        id: usize,
+        #[serde(default)]
+        button: MouseButton,
+    },
+    #[schemars(description = "Double-click at the current mouse position")]
+    DoubleClick {
+        #[serde(default)]
+        button: MouseButton,
+    },
+    #[schemars(description = "Press a mouse button without releasing it")]
+    MouseDown {
+        #[serde(default)]
+        button: MouseButton,
+    },
+    #[schemars(description = "Release a mouse button pressed with mouse_down")]
+    MouseUp {
+        #[serde(default)]
+        button: MouseButton,
+    },
+    #[schemars(description = "Press at the start point, move to the end point and release")]
+    Drag {
+        from_x: i32,
+        from_y: i32,
+        to_x: i32,
+        to_y: i32,
+        #[serde(default)]
+        button: MouseButton,
+        monitor: Option<usize>,
+    },
+    // Scroll by `amount` wheel steps, at (x, y) if given, otherwise at the cursor
+    #[schemars(description = "Scroll by a number of wheel steps, at a point or at the cursor")]
+    Scroll {
+        direction: ScrollDirection,
+        amount: u32,
+        x: Option<i32>,
+        y: Option<i32>,
+        monitor: Option<usize>,
+    },
+    #[schemars(description = "Press and release a single key")]
+    KeyPress { key: String },
+    #[schemars(description = "Hold the leading keys and press the last one, e.g. control+t")]
+    KeyCombination { keys: Vec<String> },
+    #[schemars(description = "Type text into the focused element")]
+    TextInput { text: String },
+    #[schemars(description = "Put text on the clipboard")]
+    ClipboardSet { text: String },
+    // Read the clipboard text into the task memory
+    #[schemars(
+        description = "Read the text on the clipboard, e.g. after copying, so later steps can use it"
+    )]
+    ClipboardGet {},
+    #[schemars(
+        description = "Put text on the clipboard and paste it with control+v; faster than text_input for long text"
+    )]
+    PasteText { text: String },
+    #[schemars(description = "Wait for the given number of milliseconds")]
+    Wait { ms: u64 },
+    // Wait until more than `threshold` percent (default 0.5) of the screen,
+    // or of the region [x1, y1, x2, y2] if given, has changed
+    #[schemars(
+        description = "Wait until the screen, or a region of it, changes, e.g. after starting to load a page"
+    )]
+    WaitForChange {
+        region: Option<[i32; 4]>,
+        threshold: Option<f64>,
+        timeout_ms: u64,
+    },
+    // Wait until `frames` (default 3) consecutive captures are identical
+    #[schemars(description = "Wait until the screen stops changing, e.g. until a page has loaded")]
+    WaitForStable {
+        frames: Option<u32>,
+        timeout_ms: u64,
+    },
+    // Wait until a window whose title contains `title` is open
+    #[schemars(description = "Wait until a window with the given title is open")]
+    WaitForWindow { title: String, timeout_ms: u64 },
+    // Start an application by name or desktop ID, or run a command line,
+    // then wait up to `timeout_ms` (default 10000) for its window and focus it.
+    // Exactly one of name, desktop_id and command is given.
+    #[schemars(
+        description = "Start an application by name or desktop ID, or run a command, and focus its window"
+    )]
+    LaunchApp {
+        name: Option<String>,
+        desktop_id: Option<String>,
+        command: Option<String>,
+        timeout_ms: Option<u64>,
+    },
+    // Look at a region at full resolution; the next action aimed inside it is
+    // placed precisely in a second pass before it runs
+    #[schemars(
+        description = "Look at a region at full resolution so the next click inside it is placed precisely"
+    )]
+    Zoom { x1: i32, y1: i32, x2: i32, y2: i32 },
+    #[schemars(description = "Report that the task is finished")]
+    TaskDone { reason: String },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum FocusMethod {
+    AltTab,
+    SuperTab,
+}
+
+impl FocusMethod {
+    pub fn alternate(self) -> Self {
+        match self {
+            FocusMethod::AltTab => FocusMethod::SuperTab,
+            FocusMethod::SuperTab => FocusMethod::AltTab,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum MouseButton {
+    #[default]
+    Left,
+    Right,
+    Middle,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ScrollDirection {
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+// Longest wait the planner is allowed to request in a single action
+pub const MAX_WAIT_MS: u64 = 10_000;
+
+// Longest timeout of the wait_for_* actions
+pub const MAX_WAIT_TIMEOUT_MS: u64 = 60_000;
+
+// Defaults of the optional wait_for_change and wait_for_stable fields
+pub const DEFAULT_CHANGE_THRESHOLD: f64 = 0.5;
+pub const DEFAULT_STABLE_FRAMES: u32 = 3;
+
+// How long launch_app waits for the window of a started application
+pub const DEFAULT_LAUNCH_TIMEOUT_MS: u64 = 10_000;
+
+// Most wheel steps the planner may scroll in a single action
+pub const MAX_SCROLL_AMOUNT: u32 = 50;
+
+impl Action {
+    // The value of the "action" tag, also used as ActionResult::action_type
+    pub fn name(&self) -> &'static str {
+        match self {
+            Action::WindowFocus { .. } => "window_focus",
+            Action::WindowMove { .. } => "window_move",
+            Action::WindowResize { .. } => "window_resize",
+            Action::WindowMaximize { .. } => "window_maximize",
+            Action::WindowMinimize { .. } => "window_minimize",
+            Action::WindowClose { .. } => "window_close",
+            Action::MouseMove { .. } => "mouse_move",
+            Action::MouseClick { .. } => "mouse_click",
+            Action::ClickAt { .. } => "click_at",
+            Action::ClickElement { .. } => "click_element",
+            Action::DoubleClick { .. } => "double_click",
+            Action::MouseDown { .. } => "mouse_down",
+            Action::MouseUp { .. } => "mouse_up",
+            Action::Drag { .. } => "drag",
+            Action::Scroll { .. } => "scroll",
+            Action::KeyPress { .. } => "key_press",
+            Action::KeyCombination { .. } => "key_combination",
+            Action::TextInput { .. } => "text_input",
+            Action::ClipboardSet { .. } => "clipboard_set",
+            Action::ClipboardGet { .. } => "clipboard_get",
+            Action::PasteText { .. } => "paste_text",
+            Action::Wait { .. } => "wait",
+            Action::WaitForChange { .. } => "wait_for_change",
+            Action::WaitForStable { .. } => "wait_for_stable",
+            Action::WaitForWindow { .. } => "wait_for_window",
+            Action::LaunchApp { .. } => "launch_app",
+            Action::Zoom { .. } => "zoom",
+            Action::TaskDone { .. } => "task_done",
+        }
+    }
+
+    // The same action with every screen coordinate passed through `map`
+    // together with its target monitor, used to move points from screenshot
+    // pixels to global input coordinates. The result targets no monitor.
+    pub fn map_coordinates<E>(
+        &self,
+        map: impl Fn(Option<usize>, i32, i32) -> Result<(i32, i32), E>,
+    ) -> Result<Action, E> {
+        let mut action = self.clone();
+        match &mut action {
+            Action::MouseMove { x, y, monitor } | Action::ClickAt { x, y, monitor, .. } => {
+                (*x, *y) = map(*monitor, *x, *y)?;
+                *monitor = None;
+            }
+            Action::Drag {
+                from_x,
+                from_y,
+                to_x,
+                to_y,
+                monitor,
+                ..
+            } => {
+                (*from_x, *from_y) = map(*monitor, *from_x, *from_y)?;
+                (*to_x, *to_y) = map(*monitor, *to_x, *to_y)?;
+                *monitor = None;
+            }
+            Action::Scroll {
+                x: Some(x),
+                y: Some(y),
+                monitor,
+                ..
+            } => {
+                (*x, *y) = map(*monitor, *x, *y)?;
+                *monitor = None;
+            }
+            _ => {}
+        }
+        Ok(action)
+    }
+
+    // The point in screenshot pixels the action is aimed at, if it has one:
+    // where it clicks, moves to, starts a drag or scrolls. Monitor-relative
+    // points are left out.
+    pub fn target(&self) -> Option<(i32, i32)> {
+        match self {
+            Action::MouseMove {
+                x,
+                y,
+                monitor: None,
+            }
+            | Action::ClickAt {
+                x,
+                y,
+                monitor: None,
+                ..
+            }
+            | Action::Drag {
+                from_x: x,
+                from_y: y,
+                monitor: None,
+                ..
+            }
+            | Action::Scroll {
+                x: Some(x),
+                y: Some(y),
+                monitor: None,
+                ..
+            } => Some((*x, *y)),
+            _ => None,
+        }
+    }
+
+    // The same action aimed at another point; a drag keeps its end point
+    pub fn retarget(&self, new_x: i32, new_y: i32) -> Action {
+        let mut action = self.clone();
+        match &mut action {
+            Action::MouseMove { x, y, .. }
+            | Action::ClickAt { x, y, .. }
+            | Action::Drag {
+                from_x: x,
+                from_y: y,
+                ..
+            }
+            | Action::Scroll {
+                x: Some(x),
+                y: Some(y),
+                ..
+            } => (*x, *y) = (new_x, new_y),
+            _ => {}
+        }
+        action
+    }
+
+    // Semantic checks that the schema alone cannot express
+    fn validate(&self) -> Result<(), (&'static str, String)> {
+        match self {
+            Action::WindowFocus { title, .. }
+            | Action::WindowMove { title, .. }
+            | Action::WindowResize { title, .. }
+            | Action::WindowMaximize { title, .. }
+            | Action::WindowMinimize { title, .. }
+            | Action::WindowClose { title, .. }
+                if title.trim().is_empty() =>
+            {
+                Err(("title", "must not be empty".to_string()))
+            }
+            Action::WindowMove { x, y, .. } => check_coordinates(&[("x", *x), ("y", *y)]),
+            Action::WindowResize { width: 0, .. } => {
+                Err(("width", "must be at least 1".to_string()))
+            }
+            Action::WindowResize { height: 0, .. } => {
+                Err(("height", "must be at least 1".to_string()))
+            }
+            Action::MouseMove { x, y, .. } | Action::ClickAt { x, y, .. } => {
+                check_coordinates(&[("x", *x), ("y", *y)])
+            }
+            Action::Drag {
+                from_x,
+                from_y,
+                to_x,
+                to_y,
+                ..
+            } => check_coordinates(&[
+                ("from_x", *from_x),
+                ("from_y", *from_y),
+                ("to_x", *to_x),
+                ("to_y", *to_y),
+            ]),
+            Action::Scroll { amount, .. } if *amount == 0 || *amount > MAX_SCROLL_AMOUNT => Err((
+                "amount",
+                format!(
+                    "must be between 1 and {}, got {}",
+                    MAX_SCROLL_AMOUNT, amount
+                ),
+            )),
+            Action::Scroll {
+                x: Some(x),
+                y: Some(y),
+                ..
+            } => check_coordinates(&[("x", *x), ("y", *y)]),
+            Action::Scroll { x: Some(_), .. } => {
+                Err(("y", "must be given together with x".to_string()))
+            }
+            Action::Scroll { y: Some(_), .. } => {
+                Err(("x", "must be given together with y".to_string()))
+            }
+            Action::KeyPress { key } if key.trim().is_empty() => {
+                Err(("key", "must not be empty".to_string()))
+            }
+            Action::KeyPress { key } => check_keys("key", std::slice::from_ref(key)),
+            Action::KeyCombination { keys } if keys.len() < 2 => Err((
+                "keys",
+                format!("needs a modifier and a key, got {} entries", keys.len()),
+            )),
+            Action::KeyCombination { keys } => check_keys("keys", keys),
+            Action::PasteText { text } if text.is_empty() => {
+                Err(("text", "must not be empty".to_string()))
+            }
+            Action::Zoom { x1, x2, .. } if x1 >= x2 => Err((
+                "x2",
+                format!("must be greater than x1 ({}), got {}", x1, x2),
+            )),
+            Action::Zoom { y1, y2, .. } if y1 >= y2 => Err((
+                "y2",
+                format!("must be greater than y1 ({}), got {}", y1, y2),
+            )),
+            Action::Zoom { x1, y1, x2, y2 } => {
+                check_coordinates(&[("x1", *x1), ("y1", *y1), ("x2", *x2), ("y2", *y2)])
+            }
+            Action::Wait { ms } if *ms > MAX_WAIT_MS => {
+                Err(("ms", format!("must be at most {}, got {}", MAX_WAIT_MS, ms)))
+            }
+            Action::WaitForChange { timeout_ms, .. }
+            | Action::WaitForStable { timeout_ms, .. }
+            | Action::WaitForWindow { timeout_ms, .. }
+                if *timeout_ms > MAX_WAIT_TIMEOUT_MS =>
+            {
+                Err((
+                    "timeout_ms",
+                    format!(
+                        "must be at most {}, got {}",
+                        MAX_WAIT_TIMEOUT_MS, timeout_ms
+                    ),
+                ))
+            }
+            Action::WaitForChange {
+                threshold: Some(threshold),
+                ..
+            } if !(0.0..100.0).contains(threshold) => Err((
+                "threshold",
+                format!("must be a percentage below 100, got {}", threshold),
+            )),
+            Action::WaitForChange {
+                region: Some([x1, y1, x2, y2]),
+                ..
+            } if x1 >= x2 || y1 >= y2 => Err((
+                "region",
+                "must be [x1, y1, x2, y2] with x1 < x2 and y1 < y2".to_string(),
+            )),
+            Action::WaitForChange {
+                region: Some([x1, y1, x2, y2]),
+                ..
+            } => check_coordinates(&[
+                ("region", *x1),
+                ("region", *y1),
+                ("region", *x2),
+                ("region", *y2),
+            ]),
+            Action::WaitForStable {
+                frames: Some(frames),
+                ..
+            } if *frames < 2 => Err(("frames", format!("must be at least 2, got {}", frames))),
+            Action::WaitForWindow { title, .. } if title.trim().is_empty() => {
+                Err(("title", "must not be empty".to_string()))
+            }
+            Action::LaunchApp {
+                name,
+                desktop_id,
+                command,
+                ..
+            } if [name, desktop_id, command]
+                .iter()
+                .filter(|field| {
+                    field
+                        .as_deref()
+                        .is_some_and(|value| !value.trim().is_empty())
+                })
+                .count()
+                != 1 =>
+            {
+                Err((
+                    "name",
+                    "exactly one of name, desktop_id and command must be given".to_string(),
+                ))
+            }
+            Action::LaunchApp {
+                timeout_ms: Some(timeout_ms),
+                ..
+            } if *timeout_ms > MAX_WAIT_TIMEOUT_MS => Err((
+                "timeout_ms",
+                format!(
+                    "must be at most {}, got {}",
+                    MAX_WAIT_TIMEOUT_MS, timeout_ms
+                ),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn check_coordinates(coordinates: &[(&'static str, i32)]) -> Result<(), (&'static str, String)> {
+    match coordinates.iter().find(|(_, value)| *value < 0) {
+        Some((field, value)) => Err((field, format!("must not be negative, got {}", value))),
+        None => Ok(()),
+    }
+}
+
+// Key names are checked here so a typo is reported with its field before
+// anything is sent, rather than halfway through a combination
+fn check_keys(field: &'static str, names: &[String]) -> Result<(), (&'static str, String)> {
+    names
+        .iter()
+        .try_for_each(|name| keys::parse_key(name).map(|_| ()))
+        .map_err(|e| (field, e.to_string()))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ActionError {
+    // The response was not a JSON array at all
+    NotAnArray(String),
+    // A single entry of the array failed to parse or validate
+    Invalid {
+        index: usize,
+        action: Option<String>,
+        field: Option<String>,
+        message: String,
+    },
+}
+
+impl fmt::Display for ActionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ActionError::NotAnArray(message) => {
+                write!(f, "expected a JSON array of actions: {}", message)
+            }
+            ActionError::Invalid {
+                index,
+                action,
+                field,
+                message,
+            } => {
+                write!(f, "action #{}", index)?;
+                if let Some(action) = action {
+                    write!(f, " ({})", action)?;
+                }
+                if let Some(field) = field {
+                    write!(f, " field `{}`", field)?;
+                }
+                write!(f, ": {}", message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ActionError {}
+
+// JSON schema of a single action
+pub fn action_schema() -> Value {
+    serde_json::to_value(schemars::schema_for!(Action)).unwrap_or(Value::Null)
+}
+
+// JSON schema of the array the planner must return, pretty printed for prompts,
+// without the actions named in `left_out`. Key fields are described with the
+// vocabulary from the key table.
+pub fn action_list_schema(left_out: &[&str]) -> String {
+    let mut schema =
+        serde_json::to_value(schemars::schema_for!(Vec<Action>)).unwrap_or(Value::Null);
+    describe_key_fields(&mut schema, &keys::vocabulary());
+    leave_out_actions(&mut schema, left_out);
+    serde_json::to_string_pretty(&schema).unwrap_or_default()
+}
+
+// The fields of each action type as a standalone JSON schema, keyed by the
+// action name, for offering the actions as tools. The "action" tag is left
+// out since the tool name carries it, and definitions are inlined.
+pub fn action_parameter_schemas() -> Vec<(String, Value)> {
+    let settings = SchemaSettings::draft07().with(|settings| settings.inline_subschemas = true);
+    let schema = settings.into_generator().into_root_schema_for::<Action>();
+    let mut schema = serde_json::to_value(schema).unwrap_or(Value::Null);
+    describe_key_fields(&mut schema, &keys::vocabulary());
+
+    let Some(variants) = schema["oneOf"].as_array() else {
+        return Vec::new();
+    };
+    variants
+        .iter()
+        .filter_map(|variant| {
+            let name = variant["properties"]["action"]["enum"][0].as_str()?;
+            let mut parameters = variant.clone();
+            parameters["properties"].as_object_mut()?.remove("action");
+            if let Some(required) = parameters["required"].as_array_mut() {
+                required.retain(|field| field != "action");
+            }
+            Some((name.to_string(), parameters))
+        })
+        .collect()
+}
+
+// Drop the variants of the named actions wherever the schema lists them
+fn leave_out_actions(schema: &mut Value, left_out: &[&str]) {
+    match schema {
+        Value::Object(object) => {
+            if let Some(Value::Array(variants)) = object.get_mut("oneOf") {
+                variants.retain(|variant| {
+                    !variant["properties"]["action"]["enum"][0]
+                        .as_str()
+                        .is_some_and(|name| left_out.contains(&name))
+                });
+            }
+            for value in object.values_mut() {
+                leave_out_actions(value, left_out);
+            }
+        }
+        Value::Array(items) => {
+            for item in items {
+                leave_out_actions(item, left_out);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn describe_key_fields(schema: &mut Value, description: &str) {
+    match schema {
+        Value::Object(object) => {
+            if let Some(Value::Object(properties)) = object.get_mut("properties") {
+                for field in ["key", "keys"] {
+                    if let Some(Value::Object(property)) = properties.get_mut(field) {
+                        property.insert(
+                            "description".to_string(),
+                            Value::String(description.to_string()),
+                        );
+                    }
+                }
+            }
+            for value in object.values_mut() {
+                describe_key_fields(value, description);
+            }
+        }
+        Value::Array(items) => {
+            for item in items {
+                describe_key_fields(item, description);
+            }
+        }
+        _ => {}
+    }
+}
+
+// Parse a planner response into typed actions, reporting the first bad entry
+pub fn parse_actions(text: &str) -> Result<Vec<Action>, ActionError> {
+    let values = match serde_json::from_str::<Value>(text) {
+        Ok(Value::Array(values)) => values,
+        Ok(other) => {
+            return Err(ActionError::NotAnArray(format!(
+                "got {}",
+                json_type(&other)
+            )));
+        }
+        Err(e) => return Err(ActionError::NotAnArray(e.to_string())),
+    };
+
+    let schema = action_schema();
+    values
+        .iter()
+        .enumerate()
+        .map(|(index, value)| parse_action(index, value, &schema))
+        .collect()
+}
+
+// Parse a single action object, e.g. the arguments of a tool call with the
+// "action" tag added back
+pub fn parse_single_action(value: &Value) -> Result<Action, ActionError> {
+    parse_action(0, value, &action_schema())
+}
+
+fn parse_action(index: usize, value: &Value, schema: &Value) -> Result<Action, ActionError> {
+    let invalid =
+        |action: Option<&str>, field: Option<&str>, message: String| ActionError::Invalid {
+            index,
+            action: action.map(str::to_string),
+            field: field.map(str::to_string),
+            message,
+        };
+
+    let Some(object) = value.as_object() else {
+        return Err(invalid(
+            None,
+            None,
+            format!("expected an object, got {}", json_type(value)),
+        ));
+    };
+    let Some(name) = object.get("action").and_then(Value::as_str) else {
+        return Err(invalid(
+            None,
+            Some("action"),
+            "missing or not a string".to_string(),
+        ));
+    };
+    let Some(variant) = find_variant(schema, name) else {
+        return Err(invalid(
+            Some(name),
+            Some("action"),
+            format!("unknown action type `{}`", name),
+        ));
+    };
+
+    // Check fields against the schema first so errors can name the field;
+    // serde's own messages for tagged enums usually cannot.
+    if let Err((field, message)) = check_object(object, variant, schema) {
+        return Err(invalid(Some(name), Some(&field), message));
+    }
+
+    let action = serde_json::from_value::<Action>(value.clone())
+        .map_err(|e| invalid(Some(name), None, e.to_string()))?;
+    action
+        .validate()
+        .map_err(|(field, message)| invalid(Some(name), Some(field), message))?;
+    Ok(action)
+}
+
+// Find the oneOf branch whose "action" tag matches the given name
+fn find_variant<'a>(schema: &'a Value, name: &str) -> Option<&'a Value> {
+    schema["oneOf"].as_array()?.iter().find(|variant| {
+        variant["properties"]["action"]["enum"]
+            .as_array()
+            .is_some_and(|tags| tags.iter().any(|tag| tag == name))
+    })
+}
+
+fn check_object(
+    object: &serde_json::Map<String, Value>,
+    variant: &Value,
+    root: &Value,
+) -> Result<(), (String, String)> {
+    let properties = variant["properties"].as_object();
+
+    if let Some(required) = variant["required"].as_array() {
+        for field in required.iter().filter_map(Value::as_str) {
+            if !object.contains_key(field) {
+                return Err((field.to_string(), "missing required field".to_string()));
+            }
+        }
+    }
+
+    for (field, value) in object {
+        let Some(field_schema) = properties.and_then(|p| p.get(field)) else {
+            return Err((field.clone(), "unknown field".to_string()));
+        };
+        check_value(value, field_schema, root).map_err(|message| (field.clone(), message))?;
+    }
+
+    Ok(())
+}
+
+fn check_value(value: &Value, schema: &Value, root: &Value) -> Result<(), String> {
+    // Resolve "#/definitions/Name" references
+    if let Some(reference) = schema["$ref"].as_str() {
+        let name = reference.rsplit('/').next().unwrap_or_default();
+        return check_value(value, &root["definitions"][name], root);
+    }
+
+    // Fields with a default wrap their reference in allOf
+    if let Some(schemas) = schema["allOf"].as_array() {
+        for schema in schemas {
+            check_value(value, schema, root)?;
+        }
+    }
+
+    if let Some(allowed) = schema["enum"].as_array() {
+        if !allowed.contains(value) {
+            let options: Vec<String> = allowed.iter().map(Value::to_string).collect();
+            return Err(format!(
+                "expected one of {}, got {}",
+                options.join(", "),
+                value
+            ));
+        }
+        return Ok(());
+    }
+
+    // Optional fields have a list of types, e.g. ["integer", "null"]
+    let types: Vec<&str> = match &schema["type"] {
+        Value::String(name) => vec![name.as_str()],
+        Value::Array(names) => names.iter().filter_map(Value::as_str).collect(),
+        _ => Vec::new(),
+    };
+    if !types.is_empty() && !types.iter().any(|name| has_type(value, name)) {
+        return Err(format!(
+            "expected {}, got {}",
+            types.join(" or "),
+            json_type(value)
+        ));
+    }
+
+    if let (Some(items), Some(item_schema)) = (value.as_array(), schema.get("items")) {
+        for (i, item) in items.iter().enumerate() {
+            check_value(item, item_schema, root).map_err(|e| format!("item {}: {}", i, e))?;
+        }
+    }
+
+    if let Some(minimum) = schema["minimum"].as_f64()
+        && value.as_f64().is_some_and(|n| n < minimum)
+    {
+        return Err(format!("must be at least {}, got {}", minimum, value));
+    }
+
+    Ok(())
+}
+
+fn has_type(value: &Value, name: &str) -> bool {
+    match name {
+        "string" => value.is_string(),
+        "integer" => {
+            value.is_i64() || value.is_u64() || value.as_f64().is_some_and(|n| n.fract() == 0.0)
+        }
+        "number" => value.is_number(),
+        "boolean" => value.is_boolean(),
+        "array" => value.is_array(),
+        "object" => value.is_object(),
+        "null" => value.is_null(),
+        _ => true,
+    }
+}
+
+fn json_type(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+// A short, valid plan used as the example in the planning prompt
+pub fn example_plan() -> Vec<Action> {
+    vec![
+        Action::WindowFocus {
+            title: "Google Chrome".to_string(),
+            class: "chrome".to_string(),
+            method: FocusMethod::SuperTab,
+        },
+        Action::Wait { ms: 500 },
+        Action::KeyCombination {
+            keys: vec!["control".to_string(), "t".to_string()],
+        },
+        Action::Wait { ms: 500 },
+        Action::TextInput {
+            text: "google.com".to_string(),
+        },
+        Action::Wait { ms: 200 },
+        Action::KeyPress {
+            key: "return".to_string(),
+        },
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The field a single-action response fails on
+    fn invalid_field(json: &str) -> Option<String> {
+        match parse_actions(&format!("[{}]", json)) {
+            Err(ActionError::Invalid { field, .. }) => field,
+            other => panic!("expected an invalid action, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parses_a_plan_and_names_the_bad_field() {
+        let plan = serde_json::to_string(&example_plan()).unwrap();
+        assert_eq!(parse_actions(&plan).unwrap(), example_plan());
+        assert!(matches!(
+            parse_actions(r#"{"action": "wait", "ms": 100}"#),
+            Err(ActionError::NotAnArray(_))
+        ));
+        assert!(matches!(
+            parse_actions("not json"),
+            Err(ActionError::NotAnArray(_))
+        ));
+
+        let error =
+            parse_actions(r#"[{"action": "wait", "ms": 100}, {"action": "click_at", "x": 1}]"#)
+                .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "action #1 (click_at) field `y`: missing required field"
+        );
+
+        for (json, field) in [
+            (r#"{"ms": 100}"#, "action"),
+            (r#"{"action": "fly"}"#, "action"),
+            (r#"{"action": "wait", "ms": 100, "extra": 1}"#, "extra"),
+            (r#"{"action": "wait", "ms": "soon"}"#, "ms"),
+            (r#"{"action": "mouse_click", "button": "side"}"#, "button"),
+            (
+                r#"{"action": "key_combination", "keys": ["ctrl", 1]}"#,
+                "keys",
+            ),
+            // Checks the schema cannot express
+            (r#"{"action": "mouse_move", "x": -1, "y": 5}"#, "x"),
+            (r#"{"action": "wait", "ms": 600000}"#, "ms"),
+            (
+                r#"{"action": "window_focus", "title": " ", "class": "x", "method": "alt_tab"}"#,
+                "title",
+            ),
+            (r#"{"action": "key_combination", "keys": ["ctrl"]}"#, "keys"),
+            (
+                r#"{"action": "zoom", "x1": 10, "y1": 0, "x2": 5, "y2": 9}"#,
+                "x2",
+            ),
+        ] {
+            assert_eq!(invalid_field(json).as_deref(), Some(field), "{}", json);
+        }
+    }
+
+    #[test]
+    fn checks_the_bounds_of_pointer_actions() {
+        let valid = r#"[
+            {"action": "click_at", "x": 0, "y": 0},
+            {"action": "double_click"},
+            {"action": "mouse_down", "button": "right"},
+            {"action": "mouse_up", "button": "right"},
+            {"action": "drag", "from_x": 1, "from_y": 2, "to_x": 30, "to_y": 40},
+            {"action": "scroll", "direction": "down", "amount": 50, "x": 10, "y": 10},
+            {"action": "scroll", "direction": "up", "amount": 1}
+        ]"#;
+        let actions = parse_actions(valid).unwrap();
+        assert_eq!(
+            actions[1],
+            Action::DoubleClick {
+                button: MouseButton::Left
+            }
+        );
+
+        for (json, field) in [
+            (r#"{"action": "click_at", "x": 5, "y": -1}"#, "y"),
+            (r#"{"action": "double_click", "button": "back"}"#, "button"),
+            (r#"{"action": "mouse_down", "button": 1}"#, "button"),
+            (
+                r#"{"action": "drag", "from_x": 1, "from_y": 2, "to_x": -30, "to_y": 40}"#,
+                "to_x",
+            ),
+            (
+                r#"{"action": "drag", "from_x": 1, "from_y": 2, "to_x": 3}"#,
+                "to_y",
+            ),
+            (
+                r#"{"action": "scroll", "direction": "down", "amount": 0}"#,
+                "amount",
+            ),
+            (
+                r#"{"action": "scroll", "direction": "down", "amount": 51}"#,
+                "amount",
+            ),
+            (
+                r#"{"action": "scroll", "direction": "down", "amount": -1}"#,
+                "amount",
+            ),
+            (
+                r#"{"action": "scroll", "direction": "sideways", "amount": 1}"#,
+                "direction",
+            ),
+            (
+                r#"{"action": "scroll", "direction": "down", "amount": 1, "x": 5}"#,
+                "y",
+            ),
+            (
+                r#"{"action": "scroll", "direction": "down", "amount": 1, "y": 5}"#,
+                "x",
+            ),
+            (
+                r#"{"action": "scroll", "direction": "down", "amount": 1, "x": -5, "y": 5}"#,
+                "x",
+            ),
+        ] {
+            assert_eq!(invalid_field(json).as_deref(), Some(field), "{}", json);
+        }
+    }
+
+    #[test]
+    fn unknown_key_names_are_rejected_with_their_field() {
+        assert!(parse_actions(r#"[{"action": "key_press", "key": "Page_Up"}]"#).is_ok());
+        assert_eq!(
+            invalid_field(r#"{"action": "key_press", "key": "hyper"}"#).as_deref(),
+            Some("key")
+        );
+        let error = parse_actions(r#"[{"action": "key_combination", "keys": ["ctrl", "hyper"]}]"#)
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "action #0 (key_combination) field `keys`: unknown key `hyper`"
+        );
+    }
+}
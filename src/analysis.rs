@@ -0,0 +1,229 @@
+use async_openai::types::{ResponseFormat, ResponseFormatJsonSchema};
+use schemars::JsonSchema;
+use schemars::r#gen::SchemaSettings;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt;
+
+// What the analysis stage reports about the current screenshot. Coordinates
+// are in screenshot pixels, like everything else the model answers with.
+// The default analysis knows nothing about the screen.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ScreenAnalysis {
+    // Current application/window context
+    pub context: String,
+    // Visible UI elements
+    pub ui_elements: Vec<UiElement>,
+    pub state: ScreenState,
+    // Potential issues that could block the task
+    pub challenges: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct UiElement {
+    // Element type, e.g. "button", "input", "menu"
+    #[serde(rename = "type")]
+    pub kind: String,
+    // [x1, y1, x2, y2]: left, top, right and bottom edges
+    pub coords: [i32; 4],
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ScreenState {
+    pub focused_element: Option<String>,
+    pub selected_text: Option<String>,
+    pub active_window: String,
+    pub window_title: String,
+    pub window_class: String,
+    // Window that needs to be focused for the task
+    pub target_window: Option<String>,
+}
+
+impl UiElement {
+    pub fn center(&self) -> (i32, i32) {
+        let [x1, y1, x2, y2] = self.coords;
+        ((x1 + x2) / 2, (y1 + y2) / 2)
+    }
+}
+
+impl ScreenAnalysis {
+    // Checks the schema cannot express, against the screenshot size
+    pub fn validate(&self, width: u32, height: u32) -> Result<(), AnalysisError> {
+        for (index, element) in self.ui_elements.iter().enumerate() {
+            let [x1, y1, x2, y2] = element.coords;
+            let invalid = |message: String| {
+                Err(AnalysisError::Invalid(format!(
+                    "ui_elements[{}].coords {:?}: {}",
+                    index, element.coords, message
+                )))
+            };
+            if x1 > x2 || y1 > y2 {
+                return invalid("expected [left, top, right, bottom]".to_string());
+            }
+            if x1 < 0 || y1 < 0 || x2 > width as i32 || y2 > height as i32 {
+                return invalid(format!("outside the {}x{} screenshot", width, height));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AnalysisError {
+    // The response is not JSON or does not match the ScreenAnalysis shape
+    Malformed(String),
+    // The response parsed but describes an impossible screen
+    Invalid(String),
+}
+
+impl fmt::Display for AnalysisError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AnalysisError::Malformed(message) => write!(f, "malformed analysis: {}", message),
+            AnalysisError::Invalid(message) => write!(f, "invalid analysis: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for AnalysisError {}
+
+// Parse an analysis response, tolerating markdown code fences around it
+pub fn parse_analysis(text: &str) -> Result<ScreenAnalysis, AnalysisError> {
+    let text = text
+        .trim()
+        .trim_start_matches("```json")
+        .trim_start_matches("```")
+        .trim_end_matches("```")
+        .trim();
+    serde_json::from_str(text).map_err(|e| AnalysisError::Malformed(e.to_string()))
+}
+
+// JSON schema of the analysis with every definition inlined, as providers'
+// structured output modes expect
+pub fn analysis_schema() -> Value {
+    let settings = SchemaSettings::draft07().with(|settings| settings.inline_subschemas = true);
+    let schema = settings
+        .into_generator()
+        .into_root_schema_for::<ScreenAnalysis>();
+    serde_json::to_value(schema).unwrap_or(Value::Null)
+}
+
+// How the analysis request asks the provider to shape its reply
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseMode {
+    // Constrain the reply to the ScreenAnalysis schema
+    JsonSchema,
+    // Only require a JSON object
+    JsonObject,
+    // Plain text; the prompt alone asks for JSON
+    Text,
+}
+
+impl ResponseMode {
+    // Parse a RESPONSE_FORMAT value: json_schema, json_object or text
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "json_schema" => Some(ResponseMode::JsonSchema),
+            "json_object" => Some(ResponseMode::JsonObject),
+            "text" => Some(ResponseMode::Text),
+            _ => None,
+        }
+    }
+
+    pub fn response_format(self) -> Option<ResponseFormat> {
+        match self {
+            ResponseMode::JsonSchema => Some(ResponseFormat::JsonSchema {
+                json_schema: ResponseFormatJsonSchema {
+                    description: Some("Analysis of the current screenshot".to_string()),
+                    name: "screen_analysis".to_string(),
+                    schema: Some(analysis_schema()),
+                    strict: None,
+                },
+            }),
+            ResponseMode::JsonObject => Some(ResponseFormat::JsonObject),
+            ResponseMode::Text => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ANALYSIS: &str = r#"{
+        "context": "A text editor",
+        "ui_elements": [{"type": "button", "coords": [10, 10, 50, 30]}],
+        "state": {
+            "focused_element": null,
+            "selected_text": null,
+            "active_window": "notes.txt - Editor",
+            "window_title": "notes.txt - Editor",
+            "window_class": "editor",
+            "target_window": null
+        },
+        "challenges": []
+    }"#;
+
+    #[test]
+    fn parses_plain_and_fenced_analyses() {
+        let analysis = parse_analysis(ANALYSIS).unwrap();
+        assert_eq!(analysis.ui_elements[0].center(), (30, 20));
+        assert_eq!(analysis.state.active_window, "notes.txt - Editor");
+        let fenced = format!("```json\n{}\n```", ANALYSIS);
+        assert_eq!(parse_analysis(&fenced).unwrap(), analysis);
+        assert_eq!(
+            parse_analysis(&format!("```{}```", ANALYSIS)).unwrap(),
+            analysis
+        );
+
+        for malformed in [
+            "The screen shows an editor",
+            r#"{"context": "A text editor"}"#,
+            &ANALYSIS.replace("[10, 10, 50, 30]", "[10, 10, 50]"),
+        ] {
+            assert!(matches!(
+                parse_analysis(malformed),
+                Err(AnalysisError::Malformed(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn rejects_elements_off_the_screenshot() {
+        let analysis = parse_analysis(ANALYSIS).unwrap();
+        assert!(analysis.validate(100, 100).is_ok());
+        // The element reaches x = 50
+        assert!(matches!(
+            analysis.validate(40, 100),
+            Err(AnalysisError::Invalid(_))
+        ));
+        for coords in ["[50, 10, 10, 30]", "[-1, 10, 50, 30]", "[10, 30, 50, 10]"] {
+            let analysis = parse_analysis(&ANALYSIS.replace("[10, 10, 50, 30]", coords)).unwrap();
+            assert!(analysis.validate(100, 100).is_err(), "{}", coords);
+        }
+    }
+
+    #[test]
+    fn picks_the_response_format() {
+        assert_eq!(
+            ResponseMode::from_name("json_schema"),
+            Some(ResponseMode::JsonSchema)
+        );
+        assert_eq!(
+            ResponseMode::from_name("json_object"),
+            Some(ResponseMode::JsonObject)
+        );
+        assert_eq!(ResponseMode::from_name("text"), Some(ResponseMode::Text));
+        assert_eq!(ResponseMode::from_name("JSON"), None);
+
+        assert!(matches!(
+            ResponseMode::JsonSchema.response_format(),
+            Some(ResponseFormat::JsonSchema { json_schema }) if json_schema.schema.is_some()
+        ));
+        assert!(matches!(
+            ResponseMode::JsonObject.response_format(),
+            Some(ResponseFormat::JsonObject)
+        ));
+        assert!(ResponseMode::Text.response_format().is_none());
+    }
+}
@@ -0,0 +1,65 @@
+use std::fmt;
+
+// Reading and writing the text of the system clipboard. Text set through it
+// stays available to other applications while the agent runs.
+pub trait Clipboard {
+    fn get_text(&mut self) -> Result<String, ClipboardError>;
+    fn set_text(&mut self, text: &str) -> Result<(), ClipboardError>;
+}
+
+#[derive(Debug)]
+pub enum ClipboardError {
+    // The clipboard is empty or holds something other than text
+    NoText,
+    Access(arboard::Error),
+}
+
+impl fmt::Display for ClipboardError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClipboardError::NoText => write!(f, "the clipboard holds no text"),
+            ClipboardError::Access(e) => write!(f, "could not access the clipboard: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ClipboardError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ClipboardError::NoText => None,
+            ClipboardError::Access(e) => Some(e),
+        }
+    }
+}
+
+impl From<arboard::Error> for ClipboardError {
+    fn from(e: arboard::Error) -> Self {
+        match e {
+            arboard::Error::ContentNotAvailable => ClipboardError::NoText,
+            e => ClipboardError::Access(e),
+        }
+    }
+}
+
+// The clipboard of the desktop session, through arboard
+pub struct SystemClipboard {
+    clipboard: arboard::Clipboard,
+}
+
+impl SystemClipboard {
+    pub fn new() -> Result<Self, ClipboardError> {
+        Ok(SystemClipboard {
+            clipboard: arboard::Clipboard::new()?,
+        })
+    }
+}
+
+impl Clipboard for SystemClipboard {
+    fn get_text(&mut self) -> Result<String, ClipboardError> {
+        Ok(self.clipboard.get_text()?)
+    }
+
+    fn set_text(&mut self, text: &str) -> Result<(), ClipboardError> {
+        Ok(self.clipboard.set_text(text)?)
+    }
+}
@@ -0,0 +1,310 @@
+use crate::screen::MonitorInfo;
+use serde::{Deserialize, Serialize};
+
+// The pixel spaces one iteration deals with:
+//   image   - the downscaled screenshot the model looks at and answers in
+//   capture - the physical pixels the screen source returned
+//   logical - the coordinates the input backend moves the mouse in
+// On a HiDPI display the capture is larger than the logical screen, so a
+// point has to go through both the resize factor and the display scale.
+// When only part of the desktop is captured (one of several monitors) the
+// origin is where that part starts in the global logical space.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct CoordinateSpace {
+    pub image_width: u32,
+    pub image_height: u32,
+    pub capture_width: u32,
+    pub capture_height: u32,
+    pub logical_width: i32,
+    pub logical_height: i32,
+    #[serde(default)]
+    pub origin_x: i32,
+    #[serde(default)]
+    pub origin_y: i32,
+}
+
+impl CoordinateSpace {
+    // Space for a capture that is downscaled by an integer factor before it
+    // is sent to the model, the same way `image::resize` is called on it
+    pub fn new(capture: (u32, u32), resize_factor: u32, logical: (i32, i32)) -> Self {
+        let resize_factor = resize_factor.max(1);
+        CoordinateSpace {
+            image_width: (capture.0 / resize_factor).max(1),
+            image_height: (capture.1 / resize_factor).max(1),
+            capture_width: capture.0.max(1),
+            capture_height: capture.1.max(1),
+            logical_width: logical.0.max(1),
+            logical_height: logical.1.max(1),
+            origin_x: 0,
+            origin_y: 0,
+        }
+    }
+
+    // Place the captured area at (x, y) of the global logical space
+    pub fn with_origin(mut self, x: i32, y: i32) -> Self {
+        self.origin_x = x;
+        self.origin_y = y;
+        self
+    }
+
+    // Capture pixels per screenshot pixel
+    pub fn resize_factor(&self) -> (f64, f64) {
+        (
+            self.capture_width as f64 / self.image_width as f64,
+            self.capture_height as f64 / self.image_height as f64,
+        )
+    }
+
+    // Capture pixels per logical pixel, 2.0 on a typical HiDPI display
+    pub fn display_scale(&self) -> (f64, f64) {
+        (
+            self.capture_width as f64 / self.logical_width as f64,
+            self.capture_height as f64 / self.logical_height as f64,
+        )
+    }
+
+    // Convert a point the model gave in screenshot pixels to input coordinates
+    pub fn image_to_logical(&self, x: i32, y: i32) -> (i32, i32) {
+        (
+            self.origin_x + map_axis(x, self.image_width as i32, self.logical_width),
+            self.origin_y + map_axis(y, self.image_height as i32, self.logical_height),
+        )
+    }
+
+    // Convert a size in screenshot pixels, e.g. of a window, to input pixels
+    pub fn image_to_logical_size(&self, width: u32, height: u32) -> (u32, u32) {
+        (
+            scale_size(width, self.image_width as i32, self.logical_width),
+            scale_size(height, self.image_height as i32, self.logical_height),
+        )
+    }
+
+    // Convert a size in input pixels to screenshot pixels
+    pub fn logical_to_image_size(&self, width: u32, height: u32) -> (u32, u32) {
+        (
+            scale_size(width, self.logical_width, self.image_width as i32),
+            scale_size(height, self.logical_height, self.image_height as i32),
+        )
+    }
+
+    // Convert an input coordinate to the screenshot pixel that shows it
+    pub fn logical_to_image(&self, x: i32, y: i32) -> (i32, i32) {
+        (
+            map_axis(
+                x - self.origin_x,
+                self.logical_width,
+                self.image_width as i32,
+            ),
+            map_axis(
+                y - self.origin_y,
+                self.logical_height,
+                self.image_height as i32,
+            ),
+        )
+    }
+
+    // Convert a point given relative to a monitor's top-left corner, at the
+    // scale of the screenshot, to input coordinates. This works for monitors
+    // outside the captured area too.
+    pub fn monitor_to_logical(&self, monitor: &MonitorInfo, x: i32, y: i32) -> (i32, i32) {
+        let scale = |value: i32, image: u32, logical: i32, limit: u32| {
+            let scaled = ((value as f64 + 0.5) * logical as f64 / image as f64).floor() as i32;
+            scaled.clamp(0, limit.max(1) as i32 - 1)
+        };
+        (
+            monitor.x + scale(x, self.image_width, self.logical_width, monitor.width),
+            monitor.y + scale(y, self.image_height, self.logical_height, monitor.height),
+        )
+    }
+
+    // Convert a screenshot pixel to the full-resolution capture pixel
+    pub fn image_to_capture(&self, x: i32, y: i32) -> (i32, i32) {
+        (
+            map_axis(x, self.image_width as i32, self.capture_width as i32),
+            map_axis(y, self.image_height as i32, self.capture_height as i32),
+        )
+    }
+
+    // Convert a full-resolution capture pixel to the screenshot pixel showing it
+    pub fn capture_to_image(&self, x: i32, y: i32) -> (i32, i32) {
+        (
+            map_axis(x, self.capture_width as i32, self.image_width as i32),
+            map_axis(y, self.capture_height as i32, self.image_height as i32),
+        )
+    }
+
+    // Convert a full-resolution capture pixel to input coordinates
+    pub fn capture_to_logical(&self, x: i32, y: i32) -> (i32, i32) {
+        (
+            self.origin_x + map_axis(x, self.capture_width as i32, self.logical_width),
+            self.origin_y + map_axis(y, self.capture_height as i32, self.logical_height),
+        )
+    }
+
+    // Tell the model which space to answer in
+    pub fn describe(&self) -> String {
+        let (scale_x, _) = self.display_scale();
+        format!(
+            "The screenshot is {}x{} pixels with (0,0) at the top-left corner. Give every \
+             coordinate in screenshot pixels; they are converted to the {}x{} screen \
+             (captured at {}x{}, display scale {:.2}) before execution.",
+            self.image_width,
+            self.image_height,
+            self.logical_width,
+            self.logical_height,
+            self.capture_width,
+            self.capture_height,
+            scale_x
+        )
+    }
+
+    // List the monitors with their desktop bounds and where they appear in
+    // the screenshot, so the model can tell the screens apart
+    pub fn describe_monitors(&self, monitors: &[MonitorInfo]) -> String {
+        let mut lines = Vec::new();
+        for monitor in monitors {
+            let mut line = format!(
+                "- monitor {} \"{}\"{}: {}x{} at desktop offset ({}, {})",
+                monitor.index,
+                monitor.name,
+                if monitor.primary { " (primary)" } else { "" },
+                monitor.width,
+                monitor.height,
+                monitor.x,
+                monitor.y
+            );
+            if monitor.captured {
+                let (x1, y1) = self.logical_to_image(monitor.x, monitor.y);
+                let (x2, y2) = self.logical_to_image(
+                    monitor.x + monitor.width as i32 - 1,
+                    monitor.y + monitor.height as i32 - 1,
+                );
+                line.push_str(&format!(
+                    ", screenshot pixels ({}, {}) to ({}, {})",
+                    x1, y1, x2, y2
+                ));
+            } else {
+                line.push_str(", not in the screenshot");
+            }
+            lines.push(line);
+        }
+        lines.join("\n")
+    }
+}
+
+// Map a pixel from an axis of `from` pixels onto one of `to` pixels, through
+// the pixel centre so repeated conversions do not drift, clamped to the axis
+fn map_axis(value: i32, from: i32, to: i32) -> i32 {
+    let mapped = ((value as f64 + 0.5) * to as f64 / from as f64).floor() as i32;
+    mapped.clamp(0, to - 1)
+}
+
+// Scale a length from an axis of `from` pixels onto one of `to` pixels
+fn scale_size(value: u32, from: i32, to: i32) -> u32 {
+    (value as f64 * to as f64 / from as f64).round() as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn downscaled_capture_at_native_scale() {
+        let space = CoordinateSpace::new((1920, 1080), 3, (1920, 1080));
+        assert_eq!((space.image_width, space.image_height), (640, 360));
+        assert_eq!(space.resize_factor(), (3.0, 3.0));
+        assert_eq!(space.display_scale(), (1.0, 1.0));
+
+        assert_eq!(space.image_to_logical(0, 0), (1, 1));
+        assert_eq!(space.image_to_logical(100, 50), (301, 151));
+        assert_eq!(space.image_to_logical(639, 359), (1918, 1078));
+    }
+
+    #[test]
+    fn hidpi_capture_maps_to_logical_pixels() {
+        let space = CoordinateSpace::new((3840, 2160), 3, (1920, 1080));
+        assert_eq!((space.image_width, space.image_height), (1280, 720));
+        assert_eq!(space.display_scale(), (2.0, 2.0));
+
+        // The centre of the screenshot is the centre of the logical screen
+        assert_eq!(space.image_to_logical(640, 360), (960, 540));
+        assert_eq!(space.image_to_capture(640, 360), (1921, 1081));
+
+        // Sizes scale without the pixel-centre offset
+        assert_eq!(space.image_to_logical_size(640, 360), (960, 540));
+        assert_eq!(space.logical_to_image_size(960, 540), (640, 360));
+    }
+
+    #[test]
+    fn capture_pixels_are_finer_than_screenshot_pixels() {
+        let space = CoordinateSpace::new((3840, 2160), 3, (1920, 1080)).with_origin(1920, 0);
+        assert_eq!(space.capture_to_logical(1921, 1081), (1920 + 960, 540));
+        assert_eq!(space.capture_to_logical(1923, 1083), (1920 + 961, 541));
+
+        // Points between two screenshot pixels can still be told apart
+        let (x, y) = space.image_to_capture(640, 360);
+        assert_ne!(
+            space.capture_to_logical(x, y),
+            space.capture_to_logical(x + 2, y + 2)
+        );
+    }
+
+    #[test]
+    fn uneven_sizes_truncate_like_the_resize() {
+        let space = CoordinateSpace::new((1366, 768), 3, (1366, 768));
+        assert_eq!((space.image_width, space.image_height), (455, 256));
+        assert_eq!(space.image_to_logical(454, 255), (1364, 766));
+    }
+
+    #[test]
+    fn points_outside_the_image_are_clamped() {
+        let space = CoordinateSpace::new((1920, 1080), 3, (1920, 1080));
+        assert_eq!(space.image_to_logical(-10, -10), (0, 0));
+        assert_eq!(space.image_to_logical(5000, 5000), (1919, 1079));
+    }
+
+    #[test]
+    fn round_trip_stays_within_one_screenshot_pixel() {
+        let space = CoordinateSpace::new((2880, 1800), 3, (1440, 900));
+        for (x, y) in [(0, 0), (17, 93), (480, 300), (959, 599)] {
+            let (lx, ly) = space.image_to_logical(x, y);
+            assert_eq!(space.logical_to_image(lx, ly), (x, y));
+        }
+    }
+
+    #[test]
+    fn origin_offsets_a_secondary_monitor() {
+        let space = CoordinateSpace::new((1920, 1080), 3, (1920, 1080)).with_origin(2560, 0);
+        assert_eq!(space.image_to_logical(0, 0), (2561, 1));
+        assert_eq!(space.image_to_logical(5000, 5000), (4479, 1079));
+        assert_eq!(space.logical_to_image(2561, 1), (0, 0));
+    }
+
+    #[test]
+    fn monitor_relative_points_use_the_screenshot_scale() {
+        let space = CoordinateSpace::new((4480, 1440), 4, (4480, 1440));
+        let right = MonitorInfo {
+            index: 1,
+            name: "HDMI-1".to_string(),
+            x: 2560,
+            y: 0,
+            width: 1920,
+            height: 1080,
+            primary: false,
+            captured: true,
+        };
+
+        // The same point whether given globally or relative to the monitor
+        assert_eq!(space.monitor_to_logical(&right, 100, 50), (2962, 202));
+        assert_eq!(space.image_to_logical(640 + 100, 50), (2962, 202));
+
+        // Clamped to the monitor rather than the whole desktop
+        assert_eq!(space.monitor_to_logical(&right, 1000, 1000), (4479, 1079));
+    }
+
+    #[test]
+    fn zero_sizes_do_not_divide_by_zero() {
+        let space = CoordinateSpace::new((0, 0), 0, (0, 0));
+        assert_eq!(space.image_to_logical(10, 10), (0, 0));
+    }
+}
@@ -0,0 +1,142 @@
+use crate::coords::CoordinateSpace;
+use crate::marks::group_cells;
+use image::RgbaImage;
+use serde::Serialize;
+
+// Side of the square cells changed pixels are grouped into, in pixels
+const CELL: u32 = 8;
+
+// Largest channel difference still taken for the same colour, so
+// compression noise and dithering do not count as a change
+const PIXEL_THRESHOLD: u8 = 32;
+
+// Changed pixels a cell needs before it counts, which skips a blinking caret
+// at the edge of a cell and similar specks
+const CELL_MIN_PIXELS: u32 = 2;
+
+// What changed on the screen between two captures of it
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ScreenDiff {
+    // Share of the pixels that changed, in percent
+    pub changed_percent: f64,
+    // Bounding boxes [x1, y1, x2, y2] of the changed areas
+    pub regions: Vec<[i32; 4]>,
+}
+
+impl ScreenDiff {
+    pub fn is_unchanged(&self) -> bool {
+        self.regions.is_empty()
+    }
+
+    // Whether a changed area overlaps the box grown by `margin` on every side
+    pub fn changed_near(&self, area: [i32; 4], margin: i32) -> bool {
+        let [x1, y1, x2, y2] = area;
+        let (x1, y1, x2, y2) = (x1 - margin, y1 - margin, x2 + margin, y2 + margin);
+        self.regions
+            .iter()
+            .any(|&[rx1, ry1, rx2, ry2]| rx1 <= x2 && rx2 >= x1 && ry1 <= y2 && ry2 >= y1)
+    }
+
+    // Move the regions of a diff between two captures into screenshot pixels
+    pub fn to_image(mut self, space: &CoordinateSpace) -> Self {
+        for region in &mut self.regions {
+            let (x1, y1) = space.capture_to_image(region[0], region[1]);
+            let (x2, y2) = space.capture_to_image(region[2], region[3]);
+            *region = [x1, y1, x2, y2];
+        }
+        self
+    }
+
+    pub fn describe(&self) -> String {
+        format!(
+            "{:.1}% of the screen changed in {} regions",
+            self.changed_percent,
+            self.regions.len()
+        )
+    }
+}
+
+// Compare two captures. Frames of different sizes, e.g. after a monitor
+// was reconfigured, count as entirely changed.
+pub fn compare(before: &RgbaImage, after: &RgbaImage) -> ScreenDiff {
+    let (width, height) = after.dimensions();
+    if before.dimensions() != after.dimensions() {
+        return ScreenDiff {
+            changed_percent: 100.0,
+            regions: vec![[0, 0, width as i32 - 1, height as i32 - 1]],
+        };
+    }
+    if width == 0 || height == 0 {
+        return ScreenDiff {
+            changed_percent: 0.0,
+            regions: Vec::new(),
+        };
+    }
+
+    let (cols, rows) = (width.div_ceil(CELL), height.div_ceil(CELL));
+    let mut changes = vec![0u32; (cols * rows) as usize];
+    let mut changed_pixels = 0u64;
+    for (x, y, after_pixel) in after.enumerate_pixels() {
+        let before_pixel = before.get_pixel(x, y);
+        let differs = (0..3).any(|channel| {
+            before_pixel.0[channel].abs_diff(after_pixel.0[channel]) > PIXEL_THRESHOLD
+        });
+        if differs {
+            changed_pixels += 1;
+            changes[((y / CELL) * cols + x / CELL) as usize] += 1;
+        }
+    }
+    let changed: Vec<bool> = changes
+        .iter()
+        .map(|&count| count >= CELL_MIN_PIXELS)
+        .collect();
+
+    let regions = group_cells(&changed, cols, rows)
+        .into_iter()
+        .map(|[min_col, min_row, max_col, max_row]| {
+            [
+                (min_col * CELL) as i32,
+                (min_row * CELL) as i32,
+                (((max_col + 1) * CELL).min(width) - 1) as i32,
+                (((max_row + 1) * CELL).min(height) - 1) as i32,
+            ]
+        })
+        .collect();
+    ScreenDiff {
+        changed_percent: changed_pixels as f64 * 100.0 / (width as u64 * height as u64) as f64,
+        regions,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::draw;
+    use image::Rgba;
+
+    #[test]
+    fn identical_frames_are_unchanged() {
+        let image = RgbaImage::from_pixel(64, 48, Rgba([40, 40, 40, 255]));
+        let diff = compare(&image, &image);
+        assert!(diff.is_unchanged());
+        assert_eq!(diff.changed_percent, 0.0);
+    }
+
+    #[test]
+    fn changed_area_is_boxed_and_measured() {
+        let before = RgbaImage::from_pixel(100, 100, Rgba([255, 255, 255, 255]));
+        let mut after = before.clone();
+        draw::fill(&mut after, [20, 30, 30, 40], Rgba([0, 0, 0, 255]));
+        // Slight noise elsewhere is ignored
+        after.put_pixel(80, 80, Rgba([250, 250, 250, 255]));
+
+        let diff = compare(&before, &after);
+        assert_eq!(diff.regions.len(), 1);
+        let [x1, y1, x2, y2] = diff.regions[0];
+        assert!(x1 <= 20 && y1 <= 30 && x2 >= 29 && y2 >= 39);
+        assert!((diff.changed_percent - 1.0).abs() < 1e-9);
+
+        assert!(diff.changed_near([35, 30, 45, 40], 10));
+        assert!(!diff.changed_near([70, 70, 90, 90], 10));
+    }
+}
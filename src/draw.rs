@@ -0,0 +1,117 @@
+use image::{Rgba, RgbaImage};
+
+// Small drawing helpers for the overlays put on screenshots before the
+// model sees them. Rectangles are [x1, y1, x2, y2] with x2 and y2 exclusive,
+// and everything is clipped to the image.
+
+// 3x5 bitmaps of the digits, one row per entry, most significant bit on the left
+const DIGITS: [[u8; 5]; 10] = [
+    [0b111, 0b101, 0b101, 0b101, 0b111],
+    [0b010, 0b110, 0b010, 0b010, 0b111],
+    [0b111, 0b001, 0b111, 0b100, 0b111],
+    [0b111, 0b001, 0b111, 0b001, 0b111],
+    [0b101, 0b101, 0b111, 0b001, 0b001],
+    [0b111, 0b100, 0b111, 0b001, 0b111],
+    [0b111, 0b100, 0b111, 0b101, 0b111],
+    [0b111, 0b001, 0b010, 0b010, 0b010],
+    [0b111, 0b101, 0b111, 0b101, 0b111],
+    [0b111, 0b101, 0b111, 0b001, 0b111],
+];
+
+// Size of a digit pixel in image pixels
+const DIGIT_SCALE: i32 = 2;
+
+const WHITE: Rgba<u8> = Rgba([255, 255, 255, 255]);
+
+// Colour of the grid lines and their labels
+const GRID_COLOR: Rgba<u8> = Rgba([255, 0, 160, 255]);
+
+pub fn fill(image: &mut RgbaImage, [x1, y1, x2, y2]: [i32; 4], color: Rgba<u8>) {
+    let (width, height) = (image.width() as i32, image.height() as i32);
+    for y in y1.max(0)..y2.min(height) {
+        for x in x1.max(0)..x2.min(width) {
+            image.put_pixel(x as u32, y as u32, color);
+        }
+    }
+}
+
+// Like `fill`, but mixes the colour with what is underneath so the
+// screenshot stays readable
+pub fn blend(image: &mut RgbaImage, [x1, y1, x2, y2]: [i32; 4], color: Rgba<u8>, alpha: f32) {
+    let (width, height) = (image.width() as i32, image.height() as i32);
+    for y in y1.max(0)..y2.min(height) {
+        for x in x1.max(0)..x2.min(width) {
+            let pixel = image.get_pixel_mut(x as u32, y as u32);
+            for channel in 0..3 {
+                let mixed = pixel[channel] as f32 * (1.0 - alpha) + color[channel] as f32 * alpha;
+                pixel[channel] = mixed.round() as u8;
+            }
+        }
+    }
+}
+
+pub fn outline(image: &mut RgbaImage, [x1, y1, x2, y2]: [i32; 4], color: Rgba<u8>) {
+    fill(image, [x1, y1, x2, y1 + 1], color);
+    fill(image, [x1, y2 - 1, x2, y2], color);
+    fill(image, [x1, y1, x1 + 1, y2], color);
+    fill(image, [x2 - 1, y1, x2, y2], color);
+}
+
+// Mark a point with a cross inside a square, `radius` pixels from its centre
+pub fn crosshair(image: &mut RgbaImage, x: i32, y: i32, radius: i32, color: Rgba<u8>) {
+    outline(
+        image,
+        [x - radius, y - radius, x + radius + 1, y + radius + 1],
+        color,
+    );
+    fill(image, [x - radius, y, x + radius + 1, y + 1], color);
+    fill(image, [x, y - radius, x + 1, y + radius + 1], color);
+}
+
+// Width and height of the tag `tag` draws for a number
+pub fn tag_size(number: usize) -> (i32, i32) {
+    let digits = number.to_string().len() as i32;
+    (digits * 4 * DIGIT_SCALE + DIGIT_SCALE, 7 * DIGIT_SCALE)
+}
+
+// Draw a number in white on a filled tag with its top-left corner at (x, y)
+pub fn tag(image: &mut RgbaImage, x: i32, y: i32, number: usize, color: Rgba<u8>) {
+    let (width, height) = tag_size(number);
+    fill(image, [x, y, x + width, y + height], color);
+    for (index, digit) in number.to_string().bytes().enumerate() {
+        let digit_x = x + DIGIT_SCALE + index as i32 * 4 * DIGIT_SCALE;
+        draw_digit(image, digit_x, y + DIGIT_SCALE, (digit - b'0') as usize);
+    }
+}
+
+fn draw_digit(image: &mut RgbaImage, x: i32, y: i32, digit: usize) {
+    for (row, bits) in DIGITS[digit].iter().enumerate() {
+        for col in 0..3 {
+            if bits & (0b100 >> col) != 0 {
+                let (px, py) = (x + col * DIGIT_SCALE, y + row as i32 * DIGIT_SCALE);
+                fill(image, [px, py, px + DIGIT_SCALE, py + DIGIT_SCALE], WHITE);
+            }
+        }
+    }
+}
+
+// Draw a line every `spacing` pixels, each labelled with its coordinate:
+// x along the top edge and y along the left edge
+pub fn grid(image: &mut RgbaImage, spacing: u32) {
+    let spacing = spacing.max(1) as usize;
+    let (width, height) = (image.width() as i32, image.height() as i32);
+    for x in (spacing..width as usize).step_by(spacing) {
+        blend(image, [x as i32, 0, x as i32 + 1, height], GRID_COLOR, 0.5);
+    }
+    for y in (spacing..height as usize).step_by(spacing) {
+        blend(image, [0, y as i32, width, y as i32 + 1], GRID_COLOR, 0.5);
+    }
+
+    // Labels go on top of the lines, just right of or below them
+    for x in (spacing..width as usize).step_by(spacing) {
+        tag(image, x as i32 + 1, 0, x, GRID_COLOR);
+    }
+    for y in (spacing..height as usize).step_by(spacing) {
+        tag(image, 0, y as i32 + 1, y, GRID_COLOR);
+    }
+}
@@ -0,0 +1,531 @@
+use crate::input::InputBackend;
+use crate::keys::{self, UnknownKey};
+use enigo::{Axis, Button, Direction, InputError, InputResult, Key};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use xcb::{x, xtest};
+
+// Distance in pixels from a screen edge within which the pointer counts as
+// being in a corner
+const CORNER_MARGIN: i32 = 2;
+
+// Time between two looks at the pointer for the corner failsafe
+const POINTER_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// Characters typed at once, so a stop can cut long text short
+const TEXT_CHUNK: usize = 16;
+
+// X11 core event codes of the releases sent through XTEST
+const KEY_RELEASE: u8 = 3;
+const BUTTON_RELEASE: u8 = 5;
+
+// Raised by the hotkey or the corner failsafe to take the mouse and keyboard
+// back from the agent. It also requests a pause, so the task is left paused
+// and can be resumed; the reason is kept until the pause is carried out.
+#[derive(Debug, Clone)]
+pub struct EmergencyStop {
+    is_paused: Arc<Mutex<bool>>,
+    reason: Arc<Mutex<Option<String>>>,
+    // Where the agent last put the pointer, so the agent moving it into a
+    // corner does not count as the operator doing so
+    placed: Arc<Mutex<Option<(i32, i32)>>>,
+}
+
+impl EmergencyStop {
+    pub fn new(is_paused: Arc<Mutex<bool>>) -> Self {
+        EmergencyStop {
+            is_paused,
+            reason: Arc::new(Mutex::new(None)),
+            placed: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub fn trigger(&self, reason: &str) {
+        let mut current = self.reason.lock().unwrap();
+        if current.is_none() {
+            println!("\nEMERGENCY STOP: {}", reason);
+            *current = Some(reason.to_string());
+        }
+        *self.is_paused.lock().unwrap() = true;
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.reason.lock().unwrap().is_some()
+    }
+
+    // Hand over the reason of a stop that was not handled yet; input is
+    // refused until then
+    pub fn take(&self) -> Option<String> {
+        self.reason.lock().unwrap().take()
+    }
+}
+
+#[derive(Debug)]
+pub enum EmergencyError {
+    InvalidHotkey(String),
+    Key(UnknownKey),
+    Connection(xcb::ConnError),
+    X11(xcb::Error),
+}
+
+impl fmt::Display for EmergencyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EmergencyError::InvalidHotkey(message) => write!(f, "invalid hotkey: {}", message),
+            EmergencyError::Key(e) => write!(f, "invalid hotkey: {}", e),
+            EmergencyError::Connection(e) => write!(f, "could not connect to X11: {}", e),
+            EmergencyError::X11(e) => write!(f, "X11 request failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for EmergencyError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            EmergencyError::InvalidHotkey(_) => None,
+            EmergencyError::Key(e) => Some(e),
+            EmergencyError::Connection(e) => Some(e),
+            EmergencyError::X11(e) => Some(e),
+        }
+    }
+}
+
+impl From<xcb::ConnError> for EmergencyError {
+    fn from(e: xcb::ConnError) -> Self {
+        EmergencyError::Connection(e)
+    }
+}
+
+impl From<xcb::Error> for EmergencyError {
+    fn from(e: xcb::Error) -> Self {
+        EmergencyError::X11(e)
+    }
+}
+
+// A key with modifiers, grabbed for the whole desktop
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hotkey {
+    pub name: String,
+    modifiers: x::ModMask,
+    keysym: u32,
+}
+
+impl Hotkey {
+    // Parse modifiers and one key joined with '+', e.g. "control+shift+escape",
+    // using the key names of actions
+    pub fn parse(spec: &str) -> Result<Self, EmergencyError> {
+        let mut modifiers = x::ModMask::empty();
+        let mut keysym = None;
+        for name in spec.split('+') {
+            match keys::parse_key(name).map_err(EmergencyError::Key)? {
+                Key::Control => modifiers |= x::ModMask::CONTROL,
+                Key::Shift => modifiers |= x::ModMask::SHIFT,
+                Key::Alt => modifiers |= x::ModMask::N1,
+                Key::Meta => modifiers |= x::ModMask::N4,
+                key if keysym.is_none() => {
+                    keysym = Some(key_to_keysym(key).ok_or_else(|| {
+                        EmergencyError::InvalidHotkey(format!("`{}` cannot be grabbed", name))
+                    })?);
+                }
+                _ => {
+                    return Err(EmergencyError::InvalidHotkey(
+                        "only one key besides the modifiers may be given".to_string(),
+                    ));
+                }
+            }
+        }
+        let keysym = keysym.ok_or_else(|| {
+            EmergencyError::InvalidHotkey("a key besides the modifiers is needed".to_string())
+        })?;
+        Ok(Hotkey {
+            name: spec.to_string(),
+            modifiers,
+            keysym,
+        })
+    }
+}
+
+// X11 keysym of a key; Latin-1 characters are their own keysyms
+fn key_to_keysym(key: Key) -> Option<u32> {
+    const FUNCTION_KEYS: [Key; 12] = [
+        Key::F1,
+        Key::F2,
+        Key::F3,
+        Key::F4,
+        Key::F5,
+        Key::F6,
+        Key::F7,
+        Key::F8,
+        Key::F9,
+        Key::F10,
+        Key::F11,
+        Key::F12,
+    ];
+    if let Some(index) = FUNCTION_KEYS.iter().position(|f| *f == key) {
+        return Some(0xffbe + index as u32);
+    }
+    Some(match key {
+        Key::Unicode(c) if (' '..='~').contains(&c) => c as u32,
+        Key::Escape => 0xff1b,
+        Key::Pause => 0xff13,
+        Key::ScrollLock => 0xff14,
+        Key::PrintScr => 0xff61,
+        Key::Backspace => 0xff08,
+        Key::Tab => 0xff09,
+        Key::Return => 0xff0d,
+        Key::Space => 0x20,
+        Key::Delete => 0xffff,
+        Key::Insert => 0xff63,
+        Key::Home => 0xff50,
+        Key::End => 0xff57,
+        _ => return None,
+    })
+}
+
+// Connection of a watcher thread to `display`, or DISPLAY, with its root
+// window and size. XTEST is used to release inputs when it is available.
+fn connect(
+    display: Option<&str>,
+) -> Result<(xcb::Connection, x::Window, (i32, i32)), EmergencyError> {
+    let (connection, screen_number) =
+        xcb::Connection::connect_with_extensions(display, &[], &[xcb::Extension::Test])?;
+    let screen = connection
+        .get_setup()
+        .roots()
+        .nth(screen_number as usize)
+        .ok_or(EmergencyError::Connection(
+            xcb::ConnError::ClosedInvalidScreen,
+        ))?;
+    let (root, size) = (
+        screen.root(),
+        (
+            screen.width_in_pixels() as i32,
+            screen.height_in_pixels() as i32,
+        ),
+    );
+    Ok((connection, root, size))
+}
+
+// Trigger the stop and let go of every key and mouse button held down, at
+// once rather than when the agent next sends input. Keys the operator holds
+// are released as well, which does no harm.
+fn stop_now(stop: &EmergencyStop, connection: &xcb::Connection, root: x::Window, reason: &str) {
+    stop.trigger(reason);
+    if let Err(e) = release_held(connection, root) {
+        println!("Warning: Could not release the held inputs: {}", e);
+    }
+}
+
+fn release_held(connection: &xcb::Connection, root: x::Window) -> Result<(), EmergencyError> {
+    let keymap = connection.wait_for_reply(connection.send_request(&x::QueryKeymap {}))?;
+    let pointer =
+        connection.wait_for_reply(connection.send_request(&x::QueryPointer { window: root }))?;
+    let mut releases = Vec::new();
+    for (index, bits) in keymap.keys().iter().enumerate() {
+        for bit in 0..8 {
+            if bits & (1 << bit) != 0 {
+                releases.push((KEY_RELEASE, (index * 8 + bit) as u8));
+            }
+        }
+    }
+    let buttons = [
+        x::KeyButMask::BUTTON1,
+        x::KeyButMask::BUTTON2,
+        x::KeyButMask::BUTTON3,
+        x::KeyButMask::BUTTON4,
+        x::KeyButMask::BUTTON5,
+    ];
+    for (index, mask) in buttons.into_iter().enumerate() {
+        if pointer.mask().contains(mask) {
+            releases.push((BUTTON_RELEASE, index as u8 + 1));
+        }
+    }
+    for (r#type, detail) in releases {
+        connection
+            .send_and_check_request(&xtest::FakeInput {
+                r#type,
+                detail,
+                time: x::CURRENT_TIME,
+                root,
+                root_x: 0,
+                root_y: 0,
+                deviceid: 0,
+            })
+            .map_err(|e| EmergencyError::X11(xcb::Error::Protocol(e)))?;
+    }
+    Ok(())
+}
+
+// Grab the hotkey on the root window and trigger the stop whenever it is
+// pressed, from a thread of its own with its own connection. The grab fails
+// if another client already holds the combination.
+pub fn watch_hotkey(
+    hotkey: &Hotkey,
+    stop: EmergencyStop,
+    display: Option<&str>,
+) -> Result<(), EmergencyError> {
+    let (connection, root, _) = connect(display)?;
+    let setup = connection.get_setup();
+    let (min_keycode, max_keycode) = (setup.min_keycode(), setup.max_keycode());
+    let mapping = connection.wait_for_reply(connection.send_request(&x::GetKeyboardMapping {
+        first_keycode: min_keycode,
+        count: max_keycode - min_keycode + 1,
+    }))?;
+    let per_keycode = (mapping.keysyms_per_keycode() as usize).max(1);
+    let keycodes: Vec<x::Keycode> = mapping
+        .keysyms()
+        .chunks(per_keycode)
+        .enumerate()
+        .filter(|(_, keysyms)| keysyms.contains(&hotkey.keysym))
+        .map(|(index, _)| min_keycode + index as u8)
+        .collect();
+    if keycodes.is_empty() {
+        return Err(EmergencyError::InvalidHotkey(format!(
+            "no key on this keyboard produces {}",
+            hotkey.name
+        )));
+    }
+
+    // Caps Lock and Num Lock change the modifier state, so the hotkey is
+    // grabbed with and without them
+    for keycode in keycodes {
+        for locks in [
+            x::ModMask::empty(),
+            x::ModMask::LOCK,
+            x::ModMask::N2,
+            x::ModMask::LOCK | x::ModMask::N2,
+        ] {
+            connection
+                .send_and_check_request(&x::GrabKey {
+                    owner_events: true,
+                    grab_window: root,
+                    modifiers: hotkey.modifiers | locks,
+                    key: keycode,
+                    pointer_mode: x::GrabMode::Async,
+                    keyboard_mode: x::GrabMode::Async,
+                })
+                .map_err(|e| EmergencyError::X11(xcb::Error::Protocol(e)))?;
+        }
+    }
+
+    let name = hotkey.name.clone();
+    thread::spawn(move || {
+        loop {
+            match connection.wait_for_event() {
+                Ok(xcb::Event::X(x::Event::KeyPress(_))) => {
+                    stop_now(&stop, &connection, root, &format!("{} was pressed", name));
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    println!("Error: The emergency stop hotkey stopped working: {}", e);
+                    break;
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+// Watch the pointer from a thread of its own and trigger the stop when the
+// operator moves it into a corner of the screen, whatever the agent is busy
+// with. The pointer resting in a corner, or put there by the agent, does not
+// count.
+pub fn watch_corners(stop: EmergencyStop, display: Option<&str>) -> Result<(), EmergencyError> {
+    let (connection, root, size) = connect(display)?;
+    thread::spawn(move || {
+        let mut last_position = None;
+        loop {
+            thread::sleep(POINTER_POLL_INTERVAL);
+            let pointer = match connection
+                .wait_for_reply(connection.send_request(&x::QueryPointer { window: root }))
+            {
+                Ok(pointer) => pointer,
+                Err(e) => {
+                    println!("Error: The corner failsafe stopped working: {}", e);
+                    break;
+                }
+            };
+            let (x, y) = (pointer.root_x() as i32, pointer.root_y() as i32);
+            let moved = last_position.is_some_and(|last| last != (x, y));
+            if moved
+                && in_corner(x, y, size)
+                && *stop.placed.lock().unwrap() != Some((x, y))
+                && !stop.is_triggered()
+            {
+                stop_now(
+                    &stop,
+                    &connection,
+                    root,
+                    &format!("the mouse was moved into a corner at ({}, {})", x, y),
+                );
+            }
+            last_position = Some((x, y));
+        }
+    });
+    Ok(())
+}
+
+// Whether a point is in a corner of a display of the given size
+pub fn in_corner(x: i32, y: i32, (width, height): (i32, i32)) -> bool {
+    let near_x = x <= CORNER_MARGIN || x >= width - 1 - CORNER_MARGIN;
+    let near_y = y <= CORNER_MARGIN || y >= height - 1 - CORNER_MARGIN;
+    near_x && near_y
+}
+
+// Input backend that refuses input once the emergency stop was triggered.
+// Releasing keys and buttons always goes through.
+pub struct FailsafeBackend {
+    inner: Box<dyn InputBackend>,
+    stop: EmergencyStop,
+}
+
+impl FailsafeBackend {
+    pub fn new(inner: Box<dyn InputBackend>, stop: EmergencyStop) -> Self {
+        FailsafeBackend { inner, stop }
+    }
+
+    fn check(&self) -> InputResult<()> {
+        if self.stop.is_triggered() {
+            return Err(InputError::Simulate("emergency stop"));
+        }
+        Ok(())
+    }
+}
+
+impl InputBackend for FailsafeBackend {
+    fn move_mouse(&mut self, x: i32, y: i32) -> InputResult<()> {
+        self.check()?;
+        // Noted before the move so the corner watcher never sees it first
+        *self.stop.placed.lock().unwrap() = Some((x, y));
+        self.inner.move_mouse(x, y)
+    }
+
+    fn button(&mut self, button: Button, direction: Direction) -> InputResult<()> {
+        if direction != Direction::Release {
+            self.check()?;
+        }
+        self.inner.button(button, direction)
+    }
+
+    fn key(&mut self, key: Key, direction: Direction) -> InputResult<()> {
+        if direction != Direction::Release {
+            self.check()?;
+        }
+        self.inner.key(key, direction)
+    }
+
+    fn text(&mut self, text: &str) -> InputResult<()> {
+        let chars: Vec<char> = text.chars().collect();
+        for chunk in chars.chunks(TEXT_CHUNK) {
+            self.check()?;
+            self.inner.text(&chunk.iter().collect::<String>())?;
+        }
+        Ok(())
+    }
+
+    fn scroll(&mut self, amount: i32, axis: Axis) -> InputResult<()> {
+        self.check()?;
+        self.inner.scroll(amount, axis)
+    }
+
+    fn main_display(&self) -> InputResult<(i32, i32)> {
+        self.inner.main_display()
+    }
+
+    fn location(&self) -> InputResult<(i32, i32)> {
+        self.inner.location()
+    }
+
+    fn release_all(&mut self) -> InputResult<()> {
+        self.inner.release_all()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::RecordingBackend;
+
+    #[test]
+    fn parses_hotkeys() {
+        let hotkey = Hotkey::parse("control+shift+escape").unwrap();
+        assert_eq!(hotkey.modifiers, x::ModMask::CONTROL | x::ModMask::SHIFT);
+        assert_eq!(hotkey.keysym, 0xff1b);
+        assert_eq!(Hotkey::parse("super+F12").unwrap().keysym, 0xffc9);
+        assert!(Hotkey::parse("control+shift").is_err());
+        assert!(Hotkey::parse("a+b").is_err());
+    }
+
+    #[test]
+    fn stop_refuses_input_but_releases() {
+        let stop = EmergencyStop::new(Arc::new(Mutex::new(false)));
+        let mut input =
+            FailsafeBackend::new(Box::new(RecordingBackend::new(100, 100)), stop.clone());
+        input.move_mouse(99, 0).unwrap();
+        input.key(Key::Shift, Direction::Press).unwrap();
+        assert_eq!(*stop.placed.lock().unwrap(), Some((99, 0)));
+        assert!(!stop.is_triggered());
+
+        stop.trigger("test");
+        assert!(input.text("hello").is_err());
+        assert!(input.key(Key::Shift, Direction::Release).is_ok());
+        assert!(*stop.is_paused.lock().unwrap());
+        assert_eq!(stop.take().as_deref(), Some("test"));
+        assert!(input.text("hello").is_ok());
+
+        assert!(in_corner(0, 1, (1920, 1080)));
+        assert!(in_corner(1919, 1079, (1920, 1080)));
+        assert!(!in_corner(0, 500, (1920, 1080)));
+    }
+
+    // Triggers the stop once it has typed anything, like an operator
+    // pressing the hotkey while text is being typed
+    struct Typist {
+        stop: EmergencyStop,
+        typed: Arc<Mutex<String>>,
+    }
+
+    impl InputBackend for Typist {
+        fn move_mouse(&mut self, _: i32, _: i32) -> InputResult<()> {
+            Ok(())
+        }
+        fn button(&mut self, _: Button, _: Direction) -> InputResult<()> {
+            Ok(())
+        }
+        fn key(&mut self, _: Key, _: Direction) -> InputResult<()> {
+            Ok(())
+        }
+        fn text(&mut self, text: &str) -> InputResult<()> {
+            self.typed.lock().unwrap().push_str(text);
+            self.stop.trigger("test");
+            Ok(())
+        }
+        fn scroll(&mut self, _: i32, _: Axis) -> InputResult<()> {
+            Ok(())
+        }
+        fn main_display(&self) -> InputResult<(i32, i32)> {
+            Ok((100, 100))
+        }
+        fn location(&self) -> InputResult<(i32, i32)> {
+            Ok((0, 0))
+        }
+        fn release_all(&mut self) -> InputResult<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn stop_cuts_text_short() {
+        let stop = EmergencyStop::new(Arc::new(Mutex::new(false)));
+        let typed = Arc::new(Mutex::new(String::new()));
+        let typist = Typist {
+            stop: stop.clone(),
+            typed: typed.clone(),
+        };
+        let mut input = FailsafeBackend::new(Box::new(typist), stop);
+        assert!(input.text(&"é".repeat(40)).is_err());
+        assert_eq!(*typed.lock().unwrap(), "é".repeat(TEXT_CHUNK));
+    }
+}
@@ -0,0 +1,155 @@
+use crate::action::ActionError;
+use crate::analysis::AnalysisError;
+use crate::keys::UnknownKey;
+use crate::llm::LlmError;
+use crate::marks::UnknownMark;
+use crate::policy::PolicyError;
+use crate::screen::CaptureError;
+use crate::window::WindowError;
+use enigo::{InputError, NewConError};
+use image::ImageError;
+use std::fmt;
+use std::io;
+
+// Everything that can go wrong in one pass of the agent loop. Each stage
+// decides how to recover; only setup failures end the program.
+#[derive(Debug)]
+pub enum Error {
+    Capture(CaptureError),
+    Llm(LlmError),
+    Analysis(AnalysisError),
+    Input(InputError),
+    InputConnection(NewConError),
+    Key(UnknownKey),
+    Mark(UnknownMark),
+    Plan(ActionError),
+    Window(WindowError),
+    Policy(PolicyError),
+    Io(io::Error),
+    Image(ImageError),
+    Json(serde_json::Error),
+    // A setting the agent cannot run without is missing or invalid
+    Config(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Capture(e) => write!(f, "{}", e),
+            Error::Llm(e) => write!(f, "{}", e),
+            Error::Analysis(e) => write!(f, "{}", e),
+            Error::Input(e) => write!(f, "input failed: {}", e),
+            Error::InputConnection(e) => write!(f, "could not connect to input devices: {}", e),
+            Error::Key(e) => write!(f, "{}", e),
+            Error::Mark(e) => write!(f, "{}", e),
+            Error::Plan(e) => write!(f, "invalid action plan: {}", e),
+            Error::Window(e) => write!(f, "window management failed: {}", e),
+            Error::Policy(e) => write!(f, "{}", e),
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::Image(e) => write!(f, "image error: {}", e),
+            Error::Json(e) => write!(f, "invalid JSON: {}", e),
+            Error::Config(message) => write!(f, "configuration error: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Capture(e) => Some(e),
+            Error::Llm(e) => Some(e),
+            Error::Analysis(e) => Some(e),
+            Error::Input(e) => Some(e),
+            Error::InputConnection(e) => Some(e),
+            Error::Key(e) => Some(e),
+            Error::Mark(e) => Some(e),
+            Error::Plan(e) => Some(e),
+            Error::Window(e) => Some(e),
+            Error::Policy(e) => Some(e),
+            Error::Io(e) => Some(e),
+            Error::Image(e) => Some(e),
+            Error::Json(e) => Some(e),
+            Error::Config(_) => None,
+        }
+    }
+}
+
+impl From<CaptureError> for Error {
+    fn from(e: CaptureError) -> Self {
+        Error::Capture(e)
+    }
+}
+
+impl From<LlmError> for Error {
+    fn from(e: LlmError) -> Self {
+        Error::Llm(e)
+    }
+}
+
+impl From<AnalysisError> for Error {
+    fn from(e: AnalysisError) -> Self {
+        Error::Analysis(e)
+    }
+}
+
+impl From<InputError> for Error {
+    fn from(e: InputError) -> Self {
+        Error::Input(e)
+    }
+}
+
+impl From<NewConError> for Error {
+    fn from(e: NewConError) -> Self {
+        Error::InputConnection(e)
+    }
+}
+
+impl From<UnknownKey> for Error {
+    fn from(e: UnknownKey) -> Self {
+        Error::Key(e)
+    }
+}
+
+impl From<UnknownMark> for Error {
+    fn from(e: UnknownMark) -> Self {
+        Error::Mark(e)
+    }
+}
+
+impl From<ActionError> for Error {
+    fn from(e: ActionError) -> Self {
+        Error::Plan(e)
+    }
+}
+
+impl From<WindowError> for Error {
+    fn from(e: WindowError) -> Self {
+        Error::Window(e)
+    }
+}
+
+impl From<PolicyError> for Error {
+    fn from(e: PolicyError) -> Self {
+        Error::Policy(e)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<ImageError> for Error {
+    fn from(e: ImageError) -> Self {
+        Error::Image(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Json(e)
+    }
+}
@@ -0,0 +1,659 @@
+use crate::action::{Action, FocusMethod, MouseButton, ScrollDirection};
+use crate::analysis::{ScreenAnalysis, UiElement};
+use crate::coords::CoordinateSpace;
+use crate::diff::ScreenDiff;
+use crate::error;
+use crate::input::InputBackend;
+use crate::keys::parse_key;
+use crate::marks::{self, Mark, UnknownMark};
+use crate::state::{ActionResult, TaskState};
+use enigo::{Axis, Button, Direction, InputResult, Key};
+use std::{thread::sleep, time::Duration};
+
+// Number of intermediate moves between the start and end of a drag
+const DRAG_STEPS: i32 = 10;
+
+// Distance in screenshot pixels from the expected area within which a change
+// still counts as the action's effect
+const NEAR_MARGIN: i32 = 24;
+
+// What an action visibly did: the diff between captures taken before and
+// after it, and the area it was expected to change, in screenshot pixels.
+// The area is the clicked element for a click and the focused one for typing.
+pub struct Effect {
+    pub diff: ScreenDiff,
+    pub area: Option<[i32; 4]>,
+}
+
+// Function to send the input events for an action. Verification is done
+// separately so callers can capture the screen in between.
+pub fn perform_action(action: &Action, input: &mut dyn InputBackend) -> error::Result<()> {
+    match action {
+        Action::WindowFocus {
+            title,
+            class,
+            method,
+        } => {
+            println!("Focusing window: {} ({}) using {:?}", title, class, method);
+            cycle_window_focus(input, *method)?;
+            Ok(())
+        }
+        Action::WindowMove { title, .. }
+        | Action::WindowResize { title, .. }
+        | Action::WindowMaximize { title, .. }
+        | Action::WindowMinimize { title, .. }
+        | Action::WindowClose { title, .. } => {
+            // Nothing to send; the window manager is asked afterwards
+            println!("{} on window: {}", action.name(), title);
+            Ok(())
+        }
+        Action::MouseMove { x, y, .. } => {
+            println!("Moving mouse to ({}, {})", x, y);
+            input.move_mouse(*x, *y)?;
+            Ok(())
+        }
+        Action::MouseClick { button } => {
+            println!("Clicking {:?} mouse button", button);
+            input.button(enigo_button(*button), Direction::Click)?;
+            Ok(())
+        }
+        Action::ClickAt { x, y, button, .. } => {
+            println!("Clicking {:?} mouse button at ({}, {})", button, x, y);
+            input.move_mouse(*x, *y)?;
+            sleep(Duration::from_millis(50));
+            input.button(enigo_button(*button), Direction::Click)?;
+            Ok(())
+        }
+        Action::ClickElement { id, .. } => {
+            // Resolved to a click_at with `resolve_element` before it gets here
+            Err(UnknownMark(*id).into())
+        }
+        Action::DoubleClick { button } => {
+            println!("Double-clicking {:?} mouse button", button);
+            input.button(enigo_button(*button), Direction::Click)?;
+            sleep(Duration::from_millis(50));
+            input.button(enigo_button(*button), Direction::Click)?;
+            Ok(())
+        }
+        Action::MouseDown { button } => {
+            println!("Pressing {:?} mouse button", button);
+            input.button(enigo_button(*button), Direction::Press)?;
+            Ok(())
+        }
+        Action::MouseUp { button } => {
+            println!("Releasing {:?} mouse button", button);
+            input.button(enigo_button(*button), Direction::Release)?;
+            Ok(())
+        }
+        Action::Drag {
+            from_x,
+            from_y,
+            to_x,
+            to_y,
+            button,
+            ..
+        } => {
+            println!(
+                "Dragging {:?} mouse button from ({}, {}) to ({}, {})",
+                button, from_x, from_y, to_x, to_y
+            );
+            input.move_mouse(*from_x, *from_y)?;
+            sleep(Duration::from_millis(50));
+            input.button(enigo_button(*button), Direction::Press)?;
+
+            // Move in steps; many toolkits ignore a drag that jumps straight to the end
+            for step in 1..=DRAG_STEPS {
+                sleep(Duration::from_millis(20));
+                input.move_mouse(
+                    from_x + (to_x - from_x) * step / DRAG_STEPS,
+                    from_y + (to_y - from_y) * step / DRAG_STEPS,
+                )?;
+            }
+
+            sleep(Duration::from_millis(50));
+            input.button(enigo_button(*button), Direction::Release)?;
+            Ok(())
+        }
+        Action::Scroll {
+            direction,
+            amount,
+            x,
+            y,
+            ..
+        } => {
+            println!("Scrolling {:?} by {}", direction, amount);
+            if let (Some(x), Some(y)) = (x, y) {
+                input.move_mouse(*x, *y)?;
+                sleep(Duration::from_millis(50));
+            }
+
+            // Positive amounts scroll down and to the right
+            let amount = *amount as i32;
+            match direction {
+                ScrollDirection::Up => input.scroll(-amount, Axis::Vertical)?,
+                ScrollDirection::Down => input.scroll(amount, Axis::Vertical)?,
+                ScrollDirection::Left => input.scroll(-amount, Axis::Horizontal)?,
+                ScrollDirection::Right => input.scroll(amount, Axis::Horizontal)?,
+            }
+            Ok(())
+        }
+        Action::KeyPress { key } => {
+            println!("Pressing key: {}", key);
+            input.key(parse_key(key)?, Direction::Click)?;
+            Ok(())
+        }
+        Action::KeyCombination { keys } => {
+            println!("Pressing key combination: {:?}", keys);
+
+            // Resolve every name before pressing anything, so an unknown key
+            // cannot leave the modifiers held
+            let keys = keys
+                .iter()
+                .map(|name| parse_key(name))
+                .collect::<Result<Vec<Key>, _>>()?;
+            let Some((last_key, held_keys)) = keys.split_last() else {
+                return Ok(());
+            };
+
+            // Hold the modifiers, and any other leading keys, first
+            for key in held_keys {
+                input.key(*key, Direction::Press)?;
+            }
+
+            // Small delay to ensure modifier keys are registered
+            sleep(Duration::from_millis(50));
+
+            // Press the last key
+            input.key(*last_key, Direction::Click)?;
+
+            // Small delay to ensure the key combination is registered
+            sleep(Duration::from_millis(50));
+
+            for key in held_keys.iter().rev() {
+                input.key(*key, Direction::Release)?;
+            }
+            Ok(())
+        }
+        Action::TextInput { text } => {
+            println!("Typing text: {}", text);
+            input.text(text)?;
+            Ok(())
+        }
+        Action::ClipboardSet { .. } | Action::ClipboardGet { .. } => {
+            // Nothing to send; the clipboard is used afterwards
+            println!("{}", action.name());
+            Ok(())
+        }
+        Action::PasteText { text } => {
+            // The text was put on the clipboard before this is called
+            println!("Pasting text: {}", text);
+            input.key(Key::Control, Direction::Press)?;
+            sleep(Duration::from_millis(50));
+            input.key(Key::Unicode('v'), Direction::Click)?;
+            sleep(Duration::from_millis(50));
+            input.key(Key::Control, Direction::Release)?;
+            Ok(())
+        }
+        Action::Wait { ms } => {
+            // Nothing to send; the wait happens afterwards, where a pause can
+            // cut it short
+            println!("Waiting for {}ms", ms);
+            Ok(())
+        }
+        Action::WaitForChange { timeout_ms, .. }
+        | Action::WaitForStable { timeout_ms, .. }
+        | Action::WaitForWindow { timeout_ms, .. } => {
+            // Nothing to send; the condition is polled on the screen afterwards
+            println!("{} for up to {}ms", action.name(), timeout_ms);
+            Ok(())
+        }
+        Action::LaunchApp { .. } => {
+            // Nothing to send; the application is started afterwards
+            println!("Launching an application");
+            Ok(())
+        }
+        Action::Zoom { x1, y1, x2, y2 } => {
+            // Nothing to send; the zoom refines the next action
+            println!("Zooming into ({}, {})-({}, {})", x1, y1, x2, y2);
+            Ok(())
+        }
+        Action::TaskDone { .. } => Ok(()),
+    }
+}
+
+// Function to turn a click on a marked element into a click at its centre,
+// in screenshot pixels like the marks. Other actions are returned unchanged.
+pub fn resolve_element(action: &Action, marks: &[Mark]) -> Result<Action, UnknownMark> {
+    match action {
+        Action::ClickElement { id, button } => {
+            let (x, y) = marks::find(marks, *id)?.center();
+            println!("Element {} is at ({}, {})", id, x, y);
+            Ok(Action::ClickAt {
+                x,
+                y,
+                button: *button,
+                monitor: None,
+            })
+        }
+        _ => Ok(action.clone()),
+    }
+}
+
+// Function to find the smallest analysed UI element containing a point
+pub fn element_at(analysis: &ScreenAnalysis, x: i32, y: i32) -> Option<[i32; 4]> {
+    analysis
+        .ui_elements
+        .iter()
+        .map(|element| element.coords)
+        .filter(|[x1, y1, x2, y2]| (*x1..=*x2).contains(&x) && (*y1..=*y2).contains(&y))
+        .min_by_key(|[x1, y1, x2, y2]| (x2 - x1) as i64 * (y2 - y1) as i64)
+}
+
+// Function to tell whether an action is checked against a screen diff; the
+// others need not change anything visible
+pub fn expects_change(action: &Action) -> bool {
+    matches!(
+        action,
+        Action::MouseClick { .. }
+            | Action::ClickAt { .. }
+            | Action::ClickElement { .. }
+            | Action::DoubleClick { .. }
+            | Action::Drag { .. }
+            | Action::Scroll { .. }
+            | Action::TextInput { .. }
+            | Action::PasteText { .. }
+    )
+}
+
+// Function to check an action against what it did to the screen. Without
+// an effect there is nothing to check against and the action is trusted.
+fn check_effect(action: &Action, effect: Option<&Effect>) -> Result<(), String> {
+    let Some(Effect { diff, area }) = effect else {
+        return Ok(());
+    };
+    let changed_near_area = |verb: &str| match area {
+        Some(area) if !diff.changed_near(*area, NEAR_MARGIN) => Err(format!(
+            "{} changed nothing near ({}, {})-({}, {}); {}",
+            verb,
+            area[0],
+            area[1],
+            area[2],
+            area[3],
+            diff.describe()
+        )),
+        _ => Ok(()),
+    };
+    match action {
+        // A click inside an element must change something near it; a click
+        // on empty space may legitimately do nothing
+        Action::MouseClick { .. }
+        | Action::ClickAt { .. }
+        | Action::ClickElement { .. }
+        | Action::DoubleClick { .. } => changed_near_area("The click"),
+        Action::Drag { .. } | Action::Scroll { .. } if diff.is_unchanged() => Err(format!(
+            "The {} changed nothing on the screen",
+            action.name()
+        )),
+        Action::TextInput { .. } if diff.is_unchanged() => {
+            Err("The typed text did not appear anywhere on the screen".to_string())
+        }
+        Action::TextInput { .. } => changed_near_area("Typing"),
+        Action::PasteText { .. } if diff.is_unchanged() => {
+            Err("The pasted text did not appear anywhere on the screen".to_string())
+        }
+        Action::PasteText { .. } => changed_near_area("Pasting"),
+        // Hovering, pressing a button and keys like shortcuts need not show
+        _ => Ok(()),
+    }
+}
+
+// Function to verify if an action was successful
+pub fn verify_action(
+    action: &Action,
+    analysis: &ScreenAnalysis,
+    effect: Option<&Effect>,
+    task_state: &mut TaskState,
+) -> ActionResult {
+    let mut result = ActionResult::new(action.name());
+
+    // Verify based on action type
+    match action {
+        Action::WindowFocus { title, .. } => {
+            let active_window = &analysis.state.active_window;
+            if active_window.is_empty() {
+                result.error_message = Some("Could not determine active window".to_string());
+            } else if active_window.to_lowercase().contains(&title.to_lowercase()) {
+                result = result.success();
+            } else {
+                result.error_message = Some(format!(
+                    "Window focus failed. Expected: {}, Got: {}",
+                    title, active_window
+                ));
+            }
+        }
+        Action::MouseMove { .. }
+        | Action::MouseClick { .. }
+        | Action::ClickAt { .. }
+        | Action::ClickElement { .. }
+        | Action::DoubleClick { .. }
+        | Action::MouseDown { .. }
+        | Action::MouseUp { .. }
+        | Action::Drag { .. }
+        | Action::Scroll { .. }
+        | Action::KeyPress { .. }
+        | Action::KeyCombination { .. }
+        | Action::TextInput { .. }
+        | Action::PasteText { .. } => {
+            // Mouse and keyboard actions are checked against the screen diff
+            match check_effect(action, effect) {
+                Ok(()) => result = result.success(),
+                Err(message) => result.error_message = Some(message),
+            }
+        }
+        Action::Wait { .. }
+        | Action::WaitForChange { .. }
+        | Action::WaitForStable { .. }
+        | Action::WaitForWindow { .. }
+        | Action::LaunchApp { .. }
+        | Action::ClipboardSet { .. }
+        | Action::ClipboardGet { .. }
+        | Action::WindowMove { .. }
+        | Action::WindowResize { .. }
+        | Action::WindowMaximize { .. }
+        | Action::WindowMinimize { .. }
+        | Action::WindowClose { .. }
+        | Action::Zoom { .. } => {
+            // Waits, launches, clipboard and window actions are checked by
+            // the code that carries them out; zoom actions always succeed
+            result = result.success();
+        }
+        Action::TaskDone { .. } => {
+            // Task done actions always succeed
+            result = result.success();
+        }
+    }
+
+    // Add the result to the task state
+    task_state.action_results.push(result.clone());
+
+    result
+}
+
+// Function to cycle window focus with the given key combination
+pub fn cycle_window_focus(input: &mut dyn InputBackend, method: FocusMethod) -> InputResult<()> {
+    let modifier = match method {
+        FocusMethod::AltTab => Key::Alt,
+        FocusMethod::SuperTab => Key::Meta,
+    };
+    input.key(modifier, Direction::Press)?;
+    sleep(Duration::from_millis(100));
+    input.key(Key::Tab, Direction::Click)?;
+    sleep(Duration::from_millis(100));
+    input.key(modifier, Direction::Release)
+}
+
+pub fn enigo_button(button: MouseButton) -> Button {
+    match button {
+        MouseButton::Left => Button::Left,
+        MouseButton::Right => Button::Right,
+        MouseButton::Middle => Button::Middle,
+    }
+}
+
+// Function to retry a failed action with adjusted parameters. The action and
+// the analysis are in screenshot pixels; `space` maps them to the input.
+// `observe` captures the action's effect so far, and is called again after
+// the retry.
+pub fn retry_action(
+    action: &Action,
+    analysis: &ScreenAnalysis,
+    task_state: &mut TaskState,
+    input: &mut dyn InputBackend,
+    space: &CoordinateSpace,
+    observe: &mut dyn FnMut() -> Option<Effect>,
+) -> ActionResult {
+    let mut result = verify_action(action, analysis, observe().as_ref(), task_state);
+
+    // If the action failed and we haven't retried too many times, try again with adjustments
+    if !result.success && result.retry_count < 3 {
+        let retry_count = result.clone().increment_retry().retry_count;
+
+        // Adjust the action based on the previous failure
+        match action {
+            Action::WindowFocus { method, .. } => {
+                // Try a different method for window focus
+                let new_method = method.alternate();
+                println!("Retrying window focus with method: {:?}", new_method);
+                if let Err(e) = cycle_window_focus(input, new_method) {
+                    println!("Window focus retry failed: {}", e);
+                }
+            }
+            Action::MouseMove { x, y, .. } => {
+                if let Some((new_x, new_y)) = closest_element_center(analysis, *x, *y) {
+                    println!("Adjusting mouse coordinates to ({}, {})", new_x, new_y);
+                    let (new_x, new_y) = space.image_to_logical(new_x, new_y);
+                    if let Err(e) = input.move_mouse(new_x, new_y) {
+                        println!("Mouse adjustment failed: {}", e);
+                    }
+                }
+            }
+            _ => {
+                // For other actions, just wait a bit longer and try again
+                sleep(Duration::from_millis(500));
+            }
+        }
+
+        // Verify the action again after retry
+        result = verify_action(action, analysis, observe().as_ref(), task_state);
+        result.retry_count = retry_count;
+    }
+
+    result
+}
+
+// Function to find the UI element centre closest to the given coordinates
+fn closest_element_center(analysis: &ScreenAnalysis, x: i32, y: i32) -> Option<(i32, i32)> {
+    analysis
+        .ui_elements
+        .iter()
+        .map(UiElement::center)
+        .min_by_key(|(center_x, center_y)| {
+            // Squared distance to the target
+            (*center_x as i64 - x as i64).pow(2) + (*center_y as i64 - y as i64).pow(2)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::ScreenState;
+    use crate::input::{InputEvent, RecordingBackend};
+
+    fn key(key: Key, direction: Direction) -> InputEvent {
+        InputEvent::Key { key, direction }
+    }
+
+    fn events(action: &Action) -> Vec<InputEvent> {
+        let mut input = RecordingBackend::new(100, 100);
+        perform_action(action, &mut input).unwrap();
+        input.take_events()
+    }
+
+    #[test]
+    fn sends_keys_and_text() {
+        let combination = Action::KeyCombination {
+            keys: vec!["ctrl".to_string(), "shift".to_string(), "T".to_string()],
+        };
+        assert_eq!(
+            events(&combination),
+            [
+                key(Key::Control, Direction::Press),
+                key(Key::Shift, Direction::Press),
+                key(Key::Unicode('t'), Direction::Click),
+                key(Key::Shift, Direction::Release),
+                key(Key::Control, Direction::Release),
+            ]
+        );
+        let paste = Action::PasteText {
+            text: "hello".to_string(),
+        };
+        assert_eq!(
+            events(&paste),
+            [
+                key(Key::Control, Direction::Press),
+                key(Key::Unicode('v'), Direction::Click),
+                key(Key::Control, Direction::Release),
+            ]
+        );
+        let typing = Action::TextInput {
+            text: "hello".to_string(),
+        };
+        assert_eq!(events(&typing), [InputEvent::Text("hello".to_string())]);
+
+        // An unknown key fails before anything is held
+        let mut input = RecordingBackend::new(100, 100);
+        let unknown = Action::KeyCombination {
+            keys: vec!["ctrl".to_string(), "hyper".to_string()],
+        };
+        assert!(perform_action(&unknown, &mut input).is_err());
+        assert!(input.events().is_empty());
+    }
+
+    #[test]
+    fn sends_pointer_actions() {
+        let mouse = |x, y| InputEvent::MouseMove { x, y };
+        let button = |button, direction| InputEvent::Button { button, direction };
+
+        let click = Action::ClickAt {
+            x: 10,
+            y: 20,
+            button: MouseButton::Right,
+            monitor: None,
+        };
+        assert_eq!(
+            events(&click),
+            [mouse(10, 20), button(Button::Right, Direction::Click)]
+        );
+        let double_click = Action::DoubleClick {
+            button: MouseButton::Left,
+        };
+        assert_eq!(
+            events(&double_click),
+            [
+                button(Button::Left, Direction::Click),
+                button(Button::Left, Direction::Click)
+            ]
+        );
+        let down = Action::MouseDown {
+            button: MouseButton::Middle,
+        };
+        let up = Action::MouseUp {
+            button: MouseButton::Middle,
+        };
+        assert_eq!(events(&down), [button(Button::Middle, Direction::Press)]);
+        assert_eq!(events(&up), [button(Button::Middle, Direction::Release)]);
+
+        // A drag presses at the start, moves there in steps and releases
+        let drag = Action::Drag {
+            from_x: 0,
+            from_y: 0,
+            to_x: 100,
+            to_y: 50,
+            button: MouseButton::Left,
+            monitor: None,
+        };
+        let sent = events(&drag);
+        assert_eq!(sent.len(), DRAG_STEPS as usize + 3);
+        assert_eq!(
+            sent[..3],
+            [
+                mouse(0, 0),
+                button(Button::Left, Direction::Press),
+                mouse(10, 5)
+            ]
+        );
+        assert_eq!(
+            sent[sent.len() - 2..],
+            [mouse(100, 50), button(Button::Left, Direction::Release)]
+        );
+
+        // Up and left scroll by negative amounts, at the cursor unless placed
+        let scroll = |direction, x, y| Action::Scroll {
+            direction,
+            amount: 3,
+            x,
+            y,
+            monitor: None,
+        };
+        assert_eq!(
+            events(&scroll(ScrollDirection::Up, None, None)),
+            [InputEvent::Scroll {
+                amount: -3,
+                axis: Axis::Vertical
+            }]
+        );
+        assert_eq!(
+            events(&scroll(ScrollDirection::Right, Some(7), Some(8))),
+            [
+                mouse(7, 8),
+                InputEvent::Scroll {
+                    amount: 3,
+                    axis: Axis::Horizontal
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn retries_window_focus_with_the_other_method() {
+        let mut analysis = ScreenAnalysis {
+            state: ScreenState {
+                active_window: "Terminal".to_string(),
+                ..ScreenState::default()
+            },
+            ..ScreenAnalysis::default()
+        };
+        let space = CoordinateSpace::new((100, 100), 1, (100, 100));
+        let mut task_state = TaskState::new();
+        let mut input = RecordingBackend::new(100, 100);
+        let focus = Action::WindowFocus {
+            title: "Firefox".to_string(),
+            class: "firefox".to_string(),
+            method: FocusMethod::AltTab,
+        };
+
+        let result = retry_action(
+            &focus,
+            &analysis,
+            &mut task_state,
+            &mut input,
+            &space,
+            &mut || None,
+        );
+        assert!(!result.success);
+        assert_eq!(result.retry_count, 1);
+        assert_eq!(task_state.action_results.len(), 2);
+        assert_eq!(
+            input.take_events(),
+            [
+                key(Key::Meta, Direction::Press),
+                key(Key::Tab, Direction::Click),
+                key(Key::Meta, Direction::Release),
+            ]
+        );
+
+        // Once the window is active nothing is retried
+        analysis.state.active_window = "Mozilla Firefox".to_string();
+        let result = retry_action(
+            &focus,
+            &analysis,
+            &mut task_state,
+            &mut input,
+            &space,
+            &mut || None,
+        );
+        assert!(result.success);
+        assert_eq!(result.retry_count, 0);
+        assert!(input.events().is_empty());
+    }
+}
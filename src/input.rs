@@ -0,0 +1,222 @@
+use enigo::{
+    Axis, Button, Coordinate, Direction, Enigo, InputResult, Key, Keyboard, Mouse, NewConError,
+    Settings,
+};
+
+// Everything the executor needs from a mouse and keyboard. The enigo backend
+// drives the real devices; the recording backend only logs what would happen,
+// which lets the executor run on machines without a display.
+pub trait InputBackend {
+    fn move_mouse(&mut self, x: i32, y: i32) -> InputResult<()>;
+    fn button(&mut self, button: Button, direction: Direction) -> InputResult<()>;
+    fn key(&mut self, key: Key, direction: Direction) -> InputResult<()>;
+    fn text(&mut self, text: &str) -> InputResult<()>;
+    fn scroll(&mut self, amount: i32, axis: Axis) -> InputResult<()>;
+    fn main_display(&self) -> InputResult<(i32, i32)>;
+    fn location(&self) -> InputResult<(i32, i32)>;
+
+    // Release every key and button that is still held down
+    fn release_all(&mut self) -> InputResult<()>;
+}
+
+// Modifiers are always released, even if we never saw them pressed, so a
+// pause can recover from a combination interrupted halfway through.
+const MODIFIERS: [Key; 4] = [Key::Control, Key::Alt, Key::Shift, Key::Meta];
+
+// Keys and buttons currently pressed through a backend
+#[derive(Debug, Clone, Default)]
+struct HeldInputs {
+    keys: Vec<Key>,
+    buttons: Vec<Button>,
+}
+
+impl HeldInputs {
+    fn key(&mut self, key: Key, direction: Direction) {
+        match direction {
+            Direction::Press if !self.keys.contains(&key) => self.keys.push(key),
+            Direction::Release => self.keys.retain(|held| *held != key),
+            _ => {}
+        }
+    }
+
+    fn button(&mut self, button: Button, direction: Direction) {
+        match direction {
+            Direction::Press if !self.buttons.contains(&button) => self.buttons.push(button),
+            Direction::Release => self.buttons.retain(|held| *held != button),
+            _ => {}
+        }
+    }
+
+    // Drain the held inputs, adding the modifiers that were not tracked
+    fn take(&mut self) -> (Vec<Key>, Vec<Button>) {
+        let mut keys = std::mem::take(&mut self.keys);
+        for modifier in MODIFIERS {
+            if !keys.contains(&modifier) {
+                keys.push(modifier);
+            }
+        }
+        (keys, std::mem::take(&mut self.buttons))
+    }
+}
+
+pub struct EnigoBackend {
+    enigo: Enigo,
+    held: HeldInputs,
+}
+
+impl EnigoBackend {
+    // Drive the given X display, or the one DISPLAY names
+    pub fn new(display: Option<&str>) -> Result<Self, NewConError> {
+        let settings = Settings {
+            x11_display: display.map(str::to_string),
+            ..Settings::default()
+        };
+        Ok(EnigoBackend {
+            enigo: Enigo::new(&settings)?,
+            held: HeldInputs::default(),
+        })
+    }
+}
+
+impl InputBackend for EnigoBackend {
+    fn move_mouse(&mut self, x: i32, y: i32) -> InputResult<()> {
+        self.enigo.move_mouse(x, y, Coordinate::Abs)
+    }
+
+    fn button(&mut self, button: Button, direction: Direction) -> InputResult<()> {
+        self.enigo.button(button, direction)?;
+        self.held.button(button, direction);
+        Ok(())
+    }
+
+    fn key(&mut self, key: Key, direction: Direction) -> InputResult<()> {
+        self.enigo.key(key, direction)?;
+        self.held.key(key, direction);
+        Ok(())
+    }
+
+    fn text(&mut self, text: &str) -> InputResult<()> {
+        self.enigo.text(text)
+    }
+
+    fn scroll(&mut self, amount: i32, axis: Axis) -> InputResult<()> {
+        self.enigo.scroll(amount, axis)
+    }
+
+    fn main_display(&self) -> InputResult<(i32, i32)> {
+        self.enigo.main_display()
+    }
+
+    fn location(&self) -> InputResult<(i32, i32)> {
+        self.enigo.location()
+    }
+
+    fn release_all(&mut self) -> InputResult<()> {
+        let (keys, buttons) = self.held.take();
+        for key in keys {
+            self.enigo.key(key, Direction::Release)?;
+        }
+        for button in buttons {
+            self.enigo.button(button, Direction::Release)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum InputEvent {
+    MouseMove {
+        x: i32,
+        y: i32,
+    },
+    Button {
+        button: Button,
+        direction: Direction,
+    },
+    Key {
+        key: Key,
+        direction: Direction,
+    },
+    Text(String),
+    Scroll {
+        amount: i32,
+        axis: Axis,
+    },
+}
+
+// In-memory backend that records every event instead of sending it
+#[derive(Debug, Clone)]
+pub struct RecordingBackend {
+    events: Vec<InputEvent>,
+    held: HeldInputs,
+    display: (i32, i32),
+    cursor: (i32, i32),
+}
+
+impl RecordingBackend {
+    pub fn new(width: i32, height: i32) -> Self {
+        RecordingBackend {
+            events: Vec::new(),
+            held: HeldInputs::default(),
+            display: (width, height),
+            cursor: (0, 0),
+        }
+    }
+
+    pub fn events(&self) -> &[InputEvent] {
+        &self.events
+    }
+
+    pub fn take_events(&mut self) -> Vec<InputEvent> {
+        std::mem::take(&mut self.events)
+    }
+}
+
+impl InputBackend for RecordingBackend {
+    fn move_mouse(&mut self, x: i32, y: i32) -> InputResult<()> {
+        self.cursor = (x, y);
+        self.events.push(InputEvent::MouseMove { x, y });
+        Ok(())
+    }
+
+    fn button(&mut self, button: Button, direction: Direction) -> InputResult<()> {
+        self.held.button(button, direction);
+        self.events.push(InputEvent::Button { button, direction });
+        Ok(())
+    }
+
+    fn key(&mut self, key: Key, direction: Direction) -> InputResult<()> {
+        self.held.key(key, direction);
+        self.events.push(InputEvent::Key { key, direction });
+        Ok(())
+    }
+
+    fn text(&mut self, text: &str) -> InputResult<()> {
+        self.events.push(InputEvent::Text(text.to_string()));
+        Ok(())
+    }
+
+    fn scroll(&mut self, amount: i32, axis: Axis) -> InputResult<()> {
+        self.events.push(InputEvent::Scroll { amount, axis });
+        Ok(())
+    }
+
+    fn main_display(&self) -> InputResult<(i32, i32)> {
+        Ok(self.display)
+    }
+
+    fn location(&self) -> InputResult<(i32, i32)> {
+        Ok(self.cursor)
+    }
+
+    fn release_all(&mut self) -> InputResult<()> {
+        let (keys, buttons) = self.held.take();
+        for key in keys {
+            self.key(key, Direction::Release)?;
+        }
+        for button in buttons {
+            self.button(button, Direction::Release)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,190 @@
+use enigo::Key;
+use std::fmt;
+
+// Every named key the planner may use, with its aliases. The first name is
+// the one shown in the prompt. Names are matched case-insensitively and
+// without '_', '-' or spaces, so "Page_Up", "page-up" and "pageup" agree.
+// Single characters (letters, digits, punctuation), F1-F24 and raw keycodes
+// are parsed separately in `parse_key`.
+pub const NAMED_KEYS: &[(&[&str], Key)] = &[
+    // Modifiers
+    (&["control", "ctrl"], Key::Control),
+    (&["alt", "option"], Key::Alt),
+    (&["shift"], Key::Shift),
+    (
+        &["meta", "super", "windows", "win", "cmd", "command"],
+        Key::Meta,
+    ),
+    // Editing
+    (&["return", "enter"], Key::Return),
+    (&["tab"], Key::Tab),
+    (&["escape", "esc"], Key::Escape),
+    (&["space", "spacebar"], Key::Space),
+    (&["backspace"], Key::Backspace),
+    (&["delete", "del"], Key::Delete),
+    (&["insert", "ins"], Key::Insert),
+    // Navigation
+    (&["up", "uparrow", "arrowup"], Key::UpArrow),
+    (&["down", "downarrow", "arrowdown"], Key::DownArrow),
+    (&["left", "leftarrow", "arrowleft"], Key::LeftArrow),
+    (&["right", "rightarrow", "arrowright"], Key::RightArrow),
+    (&["home"], Key::Home),
+    (&["end"], Key::End),
+    (&["pageup", "pgup"], Key::PageUp),
+    (&["pagedown", "pgdn"], Key::PageDown),
+    // Locks and system keys
+    (&["capslock"], Key::CapsLock),
+    (&["numlock"], Key::Numlock),
+    (&["scrolllock"], Key::ScrollLock),
+    (&["printscreen", "print", "prtsc"], Key::PrintScr),
+    (&["pause"], Key::Pause),
+    // Media
+    (&["volumeup"], Key::VolumeUp),
+    (&["volumedown"], Key::VolumeDown),
+    (&["volumemute", "mute"], Key::VolumeMute),
+    (&["micmute"], Key::MicMute),
+    (&["playpause", "mediaplaypause"], Key::MediaPlayPause),
+    (&["nexttrack", "medianext"], Key::MediaNextTrack),
+    (&["prevtrack", "mediaprev"], Key::MediaPrevTrack),
+    (&["mediastop"], Key::MediaStop),
+    // Punctuation by name, for when the character itself is awkward in JSON
+    (&["comma"], Key::Unicode(',')),
+    (&["period", "dot"], Key::Unicode('.')),
+    (&["slash"], Key::Unicode('/')),
+    (&["backslash"], Key::Unicode('\\')),
+    (&["semicolon"], Key::Unicode(';')),
+    (&["apostrophe", "quote"], Key::Unicode('\'')),
+    (&["grave", "backtick"], Key::Unicode('`')),
+    (&["minus", "dash"], Key::Unicode('-')),
+    (&["equal", "equals"], Key::Unicode('=')),
+    (&["plus"], Key::Unicode('+')),
+    (&["bracketleft", "leftbracket"], Key::Unicode('[')),
+    (&["bracketright", "rightbracket"], Key::Unicode(']')),
+];
+
+const FUNCTION_KEYS: [Key; 24] = [
+    Key::F1,
+    Key::F2,
+    Key::F3,
+    Key::F4,
+    Key::F5,
+    Key::F6,
+    Key::F7,
+    Key::F8,
+    Key::F9,
+    Key::F10,
+    Key::F11,
+    Key::F12,
+    Key::F13,
+    Key::F14,
+    Key::F15,
+    Key::F16,
+    Key::F17,
+    Key::F18,
+    Key::F19,
+    Key::F20,
+    Key::F21,
+    Key::F22,
+    Key::F23,
+    Key::F24,
+];
+
+// Prefix for raw platform keycodes (X keysyms on Linux), e.g. "code:0xff61"
+const RAW_PREFIX: &str = "code:";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnknownKey(pub String);
+
+impl fmt::Display for UnknownKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown key `{}`", self.0)
+    }
+}
+
+impl std::error::Error for UnknownKey {}
+
+// Parse a key name from an action into an enigo key
+pub fn parse_key(name: &str) -> Result<Key, UnknownKey> {
+    let unknown = || UnknownKey(name.to_string());
+    let trimmed = name.trim();
+
+    // A single character stands for itself; letters are sent lowercase so
+    // a combination like control+T does not also need shift
+    let mut chars = trimmed.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        return Ok(Key::Unicode(c.to_ascii_lowercase()));
+    }
+
+    let lower = trimmed.to_lowercase();
+    if let Some(code) = lower.strip_prefix(RAW_PREFIX) {
+        let code = match code.strip_prefix("0x") {
+            Some(hex) => u32::from_str_radix(hex, 16),
+            None => code.parse(),
+        };
+        return code.map(Key::Other).map_err(|_| unknown());
+    }
+
+    let normalized: String = lower
+        .chars()
+        .filter(|c| !matches!(c, '_' | '-' | ' '))
+        .collect();
+
+    if let Some(number) = normalized.strip_prefix('f') {
+        if let Ok(number) = number.parse::<usize>() {
+            return match number {
+                1..=24 => Ok(FUNCTION_KEYS[number - 1]),
+                _ => Err(unknown()),
+            };
+        }
+    }
+
+    NAMED_KEYS
+        .iter()
+        .find(|(names, _)| names.contains(&normalized.as_str()))
+        .map(|(_, key)| *key)
+        .ok_or_else(unknown)
+}
+
+// Description of the key vocabulary, generated from the table for the prompt
+pub fn vocabulary() -> String {
+    let names: Vec<&str> = NAMED_KEYS.iter().map(|(names, _)| names[0]).collect();
+    format!(
+        "Key names are case-insensitive: any single letter, digit or punctuation character, \
+         f1-f24, {}, or {}<n> for a raw keycode",
+        names.join(", "),
+        RAW_PREFIX
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_names_aliases_and_characters() {
+        assert_eq!(parse_key("ctrl"), Ok(Key::Control));
+        assert_eq!(parse_key("Control"), Ok(Key::Control));
+        assert_eq!(parse_key("super"), Ok(Key::Meta));
+        assert_eq!(parse_key("Page_Up"), Ok(Key::PageUp));
+        assert_eq!(parse_key("page-up"), Ok(Key::PageUp));
+        assert_eq!(parse_key(" ENTER "), Ok(Key::Return));
+        assert_eq!(parse_key("F12"), Ok(Key::F12));
+        assert_eq!(parse_key("T"), Ok(Key::Unicode('t')));
+        assert_eq!(parse_key("comma"), Ok(Key::Unicode(',')));
+        assert_eq!(parse_key("code:0xff61"), Ok(Key::Other(0xff61)));
+        assert_eq!(parse_key("code:65"), Ok(Key::Other(65)));
+
+        for unknown in ["", "f25", "f0", "hyper", "code:xyz", "ctrl+t"] {
+            assert_eq!(parse_key(unknown), Err(UnknownKey(unknown.to_string())));
+        }
+    }
+
+    #[test]
+    fn every_named_key_parses_to_itself() {
+        for (names, key) in NAMED_KEYS {
+            for name in *names {
+                assert_eq!(parse_key(name), Ok(*key), "{}", name);
+            }
+        }
+    }
+}
@@ -0,0 +1,409 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+// An application found in a .desktop file of the XDG data directories
+#[derive(Debug, Clone, PartialEq)]
+pub struct DesktopEntry {
+    // File name without .desktop, e.g. "firefox" or "org.gnome.Terminal"
+    pub id: String,
+    pub name: String,
+    pub exec: String,
+    // WM_CLASS the application's windows are expected to have
+    pub wm_class: Option<String>,
+}
+
+impl DesktopEntry {
+    // The command line of the Exec key, without its field codes
+    pub fn command(&self) -> Result<Vec<String>, LaunchError> {
+        split_command(&self.exec)
+    }
+}
+
+// What to start: an application by name or desktop ID, or a command line
+#[derive(Debug, Clone, PartialEq)]
+pub enum LaunchTarget {
+    Entry(DesktopEntry),
+    Command(Vec<String>),
+}
+
+impl LaunchTarget {
+    // Names the allowlist may list for this target
+    fn names(&self) -> Vec<String> {
+        match self {
+            LaunchTarget::Entry(entry) => vec![entry.id.clone(), entry.name.clone()],
+            LaunchTarget::Command(args) => args.first().cloned().into_iter().collect(),
+        }
+    }
+
+    // Text the window of the started application is likely to carry in its
+    // title or class
+    pub fn window_hints(&self) -> Vec<String> {
+        match self {
+            LaunchTarget::Entry(entry) => [Some(entry.id.clone()), entry.wm_class.clone()]
+                .into_iter()
+                .flatten()
+                .chain([entry.name.clone()])
+                .collect(),
+            LaunchTarget::Command(args) => args
+                .first()
+                .and_then(|program| Path::new(program).file_name())
+                .map(|name| name.to_string_lossy().into_owned())
+                .into_iter()
+                .collect(),
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        match self {
+            LaunchTarget::Entry(entry) => format!("{} ({}.desktop)", entry.name, entry.id),
+            LaunchTarget::Command(args) => args.join(" "),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum LaunchError {
+    NotAllowed(String),
+    NotFound(String),
+    InvalidCommand(String),
+    Spawn(io::Error),
+}
+
+impl fmt::Display for LaunchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LaunchError::NotAllowed(target) => {
+                write!(f, "{} is not in the LAUNCH_ALLOW list", target)
+            }
+            LaunchError::NotFound(name) => write!(f, "no application named {}", name),
+            LaunchError::InvalidCommand(message) => write!(f, "invalid command: {}", message),
+            LaunchError::Spawn(e) => write!(f, "could not start the process: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for LaunchError {}
+
+// The applications the agent may start, from a comma-separated LAUNCH_ALLOW
+// value of desktop IDs, application names or programs. "*" allows anything;
+// an empty list allows nothing. A command given as a path is allowed by that
+// path, or by a listed program that PATH resolves to the same file, never by
+// its file name alone.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Allowlist {
+    entries: Vec<String>,
+}
+
+impl Allowlist {
+    pub fn parse(value: &str) -> Self {
+        Allowlist {
+            entries: value
+                .split(',')
+                .map(|entry| entry.trim().to_string())
+                .filter(|entry| !entry.is_empty())
+                .collect(),
+        }
+    }
+
+    pub fn allows(&self, target: &LaunchTarget) -> bool {
+        if self.entries.iter().any(|entry| entry == "*") {
+            return true;
+        }
+        match target {
+            LaunchTarget::Command(args) if args.first().is_some_and(|p| p.contains('/')) => {
+                let program = same_file(Path::new(&args[0]));
+                self.entries.iter().any(|entry| {
+                    let listed = if entry.contains('/') {
+                        Some(PathBuf::from(entry))
+                    } else {
+                        find_in_path(entry)
+                    };
+                    listed.is_some_and(|listed| same_file(&listed) == program)
+                })
+            }
+            _ => target.names().iter().any(|name| {
+                self.entries
+                    .iter()
+                    .any(|entry| entry.to_lowercase() == name.to_lowercase())
+            }),
+        }
+    }
+}
+
+// The program a bare command name runs, searched for in PATH
+fn find_in_path(name: &str) -> Option<PathBuf> {
+    let paths = std::env::var_os("PATH")?;
+    std::env::split_paths(&paths)
+        .map(|dir| dir.join(name))
+        .find(|path| path.is_file())
+}
+
+// A path with its links resolved, so two paths to the same program compare
+// equal; paths that do not exist are kept as they are
+fn same_file(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+// Directories holding .desktop files, most important first, as the XDG base
+// directory specification defines them
+pub fn application_dirs() -> Vec<PathBuf> {
+    let home = std::env::var("HOME").unwrap_or_default();
+    let data_home = std::env::var("XDG_DATA_HOME")
+        .ok()
+        .filter(|dir| !dir.is_empty())
+        .unwrap_or_else(|| format!("{}/.local/share", home));
+    let data_dirs = std::env::var("XDG_DATA_DIRS")
+        .ok()
+        .filter(|dirs| !dirs.is_empty())
+        .unwrap_or_else(|| "/usr/local/share:/usr/share".to_string());
+
+    std::iter::once(data_home.as_str())
+        .chain(data_dirs.split(':'))
+        .filter(|dir| !dir.is_empty())
+        .map(|dir| Path::new(dir).join("applications"))
+        .collect()
+}
+
+// Every launchable application in the given directories. An ID found in an
+// earlier directory hides the same ID in later ones.
+pub fn desktop_entries(dirs: &[PathBuf]) -> Vec<DesktopEntry> {
+    let mut entries: HashMap<String, Option<DesktopEntry>> = HashMap::new();
+    for dir in dirs {
+        for (id, path) in desktop_files(dir, "") {
+            if entries.contains_key(&id) {
+                continue;
+            }
+            let entry = fs::read_to_string(&path)
+                .ok()
+                .and_then(|contents| parse_desktop_entry(&id, &contents));
+            // Hidden entries still hide the ones after them
+            entries.insert(id, entry);
+        }
+    }
+    let mut entries: Vec<DesktopEntry> = entries.into_values().flatten().collect();
+    entries.sort_by(|a, b| a.id.cmp(&b.id));
+    entries
+}
+
+// The .desktop files under a directory with their IDs; files in
+// subdirectories get the subdirectory names as a dash-separated prefix
+fn desktop_files(dir: &Path, prefix: &str) -> Vec<(String, PathBuf)> {
+    let Ok(read_dir) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut files = Vec::new();
+    for path in read_dir
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+    {
+        let Some(file_name) = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+        else {
+            continue;
+        };
+        if path.is_dir() {
+            files.extend(desktop_files(&path, &format!("{}{}-", prefix, file_name)));
+        } else if let Some(stem) = file_name.strip_suffix(".desktop") {
+            files.push((format!("{}{}", prefix, stem), path));
+        }
+    }
+    files
+}
+
+// Read the [Desktop Entry] group of a .desktop file. Entries that are not
+// applications, or are hidden, are skipped.
+pub fn parse_desktop_entry(id: &str, contents: &str) -> Option<DesktopEntry> {
+    let mut in_group = false;
+    let mut keys = HashMap::new();
+    for line in contents.lines().map(str::trim) {
+        if line.starts_with('[') {
+            in_group = line == "[Desktop Entry]";
+        } else if in_group && !line.starts_with('#') {
+            if let Some((key, value)) = line.split_once('=') {
+                // Localized keys like Name[de] are left out
+                keys.entry(key.trim())
+                    .or_insert_with(|| value.trim().to_string());
+            }
+        }
+    }
+
+    let flag = |key: &str| keys.get(key).is_some_and(|value| value == "true");
+    if keys.get("Type").map(String::as_str) != Some("Application")
+        || flag("Hidden")
+        || flag("NoDisplay")
+    {
+        return None;
+    }
+    Some(DesktopEntry {
+        id: id.to_string(),
+        name: keys.get("Name")?.clone(),
+        exec: keys.get("Exec")?.clone(),
+        wm_class: keys.get("StartupWMClass").cloned(),
+    })
+}
+
+// Find an application by desktop ID, with or without .desktop, or by name:
+// an exact name first, then a name containing the given one
+pub fn find_entry<'a>(entries: &'a [DesktopEntry], name: &str) -> Option<&'a DesktopEntry> {
+    let name = name.trim_end_matches(".desktop").to_lowercase();
+    entries
+        .iter()
+        .find(|entry| entry.id.to_lowercase() == name)
+        .or_else(|| {
+            entries
+                .iter()
+                .find(|entry| entry.name.to_lowercase() == name)
+        })
+        .or_else(|| {
+            entries
+                .iter()
+                .find(|entry| entry.name.to_lowercase().contains(&name))
+        })
+}
+
+// Split a command line into arguments. Double quotes group words and a
+// backslash escapes the next character; the field codes of Exec keys
+// (%f, %U, ...) are dropped since nothing is opened with the application.
+pub fn split_command(command: &str) -> Result<Vec<String>, LaunchError> {
+    let mut args = Vec::new();
+    let mut current = String::new();
+    let mut in_word = false;
+    let mut quoted = false;
+    let mut chars = command.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                in_word = true;
+            }
+            '\\' => {
+                let escaped = chars
+                    .next()
+                    .ok_or_else(|| LaunchError::InvalidCommand("trailing backslash".to_string()))?;
+                current.push(escaped);
+                in_word = true;
+            }
+            '%' => match chars.next() {
+                Some('%') => current.push('%'),
+                Some(_) => {}
+                None => {
+                    return Err(LaunchError::InvalidCommand(
+                        "trailing % in the command".to_string(),
+                    ));
+                }
+            },
+            c if c.is_whitespace() && !quoted => {
+                if in_word || !current.is_empty() {
+                    args.push(std::mem::take(&mut current));
+                }
+                in_word = false;
+            }
+            c => {
+                current.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if quoted {
+        return Err(LaunchError::InvalidCommand(
+            "unterminated quote".to_string(),
+        ));
+    }
+    if in_word || !current.is_empty() {
+        args.push(current);
+    }
+    if args.is_empty() {
+        return Err(LaunchError::InvalidCommand("empty command".to_string()));
+    }
+    Ok(args)
+}
+
+// Start a process that outlives the agent: it gets its own process group,
+// so a Ctrl+C in the agent's terminal does not reach it, and no standard
+// streams. It opens its windows on `display` if given. A thread reaps it
+// once it exits.
+pub fn spawn_detached(args: &[String], display: Option<&str>) -> Result<u32, LaunchError> {
+    let (program, args) = args
+        .split_first()
+        .ok_or_else(|| LaunchError::InvalidCommand("empty command".to_string()))?;
+    let mut command = Command::new(program);
+    if let Some(display) = display {
+        command.env("DISPLAY", display);
+    }
+    let mut child = command
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .process_group(0)
+        .spawn()
+        .map_err(LaunchError::Spawn)?;
+    let pid = child.id();
+    std::thread::spawn(move || child.wait());
+    Ok(pid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_an_application_entry() {
+        let contents = "\
+[Desktop Entry]
+Type=Application
+Name=Firefox Web Browser
+Name[de]=Firefox-Webbrowser
+Exec=firefox %u
+StartupWMClass=firefox
+
+[Desktop Action new-window]
+Name=New Window
+Exec=firefox --new-window %u
+";
+        let entry = parse_desktop_entry("firefox", contents).unwrap();
+        assert_eq!(entry.name, "Firefox Web Browser");
+        assert_eq!(entry.command().unwrap(), ["firefox"]);
+        assert_eq!(entry.wm_class.as_deref(), Some("firefox"));
+
+        let hidden = contents.replace("Type=Application", "Type=Application\nNoDisplay=true");
+        assert_eq!(parse_desktop_entry("firefox", &hidden), None);
+
+        let entries = [entry];
+        assert!(find_entry(&entries, "firefox.desktop").is_some());
+        assert!(find_entry(&entries, "web browser").is_some());
+        assert!(find_entry(&entries, "chromium").is_none());
+    }
+
+    #[test]
+    fn splits_commands_and_checks_the_allowlist() {
+        assert_eq!(
+            split_command(r#"sh -c "echo 100%%" --title=a\ b %F"#).unwrap(),
+            ["sh", "-c", "echo 100%", "--title=a b"]
+        );
+        assert!(split_command("\"unterminated").is_err());
+
+        let command = LaunchTarget::Command(vec!["gedit".to_string()]);
+        assert!(Allowlist::parse("firefox, gedit").allows(&command));
+        assert!(!Allowlist::parse("firefox").allows(&command));
+        assert!(!Allowlist::parse("").allows(&command));
+        assert!(Allowlist::parse("*").allows(&command));
+
+        // Paths need the same path, or a name PATH resolves to that file
+        let command = LaunchTarget::Command(vec!["/opt/tools/gedit".to_string()]);
+        assert!(Allowlist::parse("/opt/tools/gedit").allows(&command));
+        assert!(!Allowlist::parse("gedit").allows(&command));
+        let shell = LaunchTarget::Command(vec!["/bin/sh".to_string()]);
+        assert!(Allowlist::parse("sh").allows(&shell));
+        assert!(
+            !Allowlist::parse("sh")
+                .allows(&LaunchTarget::Command(vec!["/tmp/anything/sh".to_string()]))
+        );
+    }
+}
@@ -0,0 +1,21 @@
+pub mod action;
+pub mod analysis;
+pub mod clipboard;
+pub mod coords;
+pub mod diff;
+pub mod draw;
+pub mod emergency;
+pub mod error;
+pub mod executor;
+pub mod input;
+pub mod keys;
+pub mod launch;
+pub mod llm;
+pub mod marks;
+pub mod planner;
+pub mod policy;
+pub mod screen;
+pub mod session;
+pub mod state;
+pub mod wait;
+pub mod window;
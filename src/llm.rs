@@ -0,0 +1,445 @@
+use async_openai::error::OpenAIError;
+use async_openai::types::{
+    ChatCompletionMessageToolCall, ChatCompletionToolType, CreateChatCompletionRequest,
+    CreateChatCompletionResponse, FunctionCall,
+};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+// The model calls the agent loop makes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    Analysis,
+    AnalysisRepair,
+    Planning,
+    SelfInstruction,
+    Verify,
+    Zoom,
+}
+
+// Identifies a single model call within a run. Stages that hold a
+// conversation with the model number its turns from 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Call {
+    pub stage: Stage,
+    pub iteration: usize,
+    pub turn: usize,
+}
+
+impl Call {
+    pub fn new(stage: Stage, iteration: usize) -> Self {
+        Call {
+            stage,
+            iteration,
+            turn: 0,
+        }
+    }
+
+    pub fn with_turn(mut self, turn: usize) -> Self {
+        self.turn = turn;
+        self
+    }
+}
+
+// What the model answered: text, calls to the tools it was offered, or both
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Reply {
+    pub content: Option<String>,
+    pub tool_calls: Vec<ChatCompletionMessageToolCall>,
+}
+
+#[async_trait]
+pub trait LlmClient: Send + Sync {
+    // Send a chat completion request and return the model's reply
+    async fn reply(
+        &self,
+        call: Call,
+        request: CreateChatCompletionRequest,
+    ) -> Result<Reply, LlmError>;
+
+    // Send a chat completion request and return the text of the reply
+    async fn complete(
+        &self,
+        call: Call,
+        request: CreateChatCompletionRequest,
+    ) -> Result<String, LlmError> {
+        self.reply(call, request)
+            .await?
+            .content
+            .ok_or(LlmError::EmptyResponse)
+    }
+}
+
+#[derive(Debug)]
+pub enum LlmError {
+    Api(OpenAIError),
+    // The endpoint answered with an error status
+    Status { status: u16, message: String },
+    EmptyResponse,
+    ScriptExhausted(Call),
+    InvalidScript(String),
+}
+
+impl fmt::Display for LlmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LlmError::Api(e) => write!(f, "model request failed: {}", e),
+            LlmError::Status { status, message } => {
+                write!(f, "model request failed with HTTP {}: {}", status, message)
+            }
+            LlmError::EmptyResponse => write!(f, "model returned no content"),
+            LlmError::ScriptExhausted(call) => write!(
+                f,
+                "no scripted response for {:?} in iteration {} turn {}",
+                call.stage, call.iteration, call.turn
+            ),
+            LlmError::InvalidScript(message) => write!(f, "invalid model script: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for LlmError {}
+
+impl LlmError {
+    // Whether sending the same request again might succeed: rate limits,
+    // server errors and failed connections. A rejected or malformed request
+    // fails the same way every time.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            LlmError::Status { status, .. } => *status == 429 || *status >= 500,
+            LlmError::Api(OpenAIError::Reqwest(_)) | LlmError::Api(OpenAIError::StreamError(_)) => {
+                true
+            }
+            LlmError::Api(_) | LlmError::EmptyResponse => false,
+            LlmError::ScriptExhausted(_) | LlmError::InvalidScript(_) => false,
+        }
+    }
+}
+
+impl From<OpenAIError> for LlmError {
+    fn from(e: OpenAIError) -> Self {
+        LlmError::Api(e)
+    }
+}
+
+// Any OpenAI-compatible chat completion endpoint (OpenAI, OpenRouter, ...).
+// The request is sent directly rather than through async_openai's client,
+// which drops the HTTP status of failed requests and retries rate limits on
+// its own.
+pub struct OpenAiClient {
+    http: reqwest::Client,
+    url: String,
+    api_key: String,
+}
+
+impl OpenAiClient {
+    pub fn new(api_base: &str, api_key: &str) -> Self {
+        OpenAiClient {
+            http: reqwest::Client::new(),
+            url: format!("{}/chat/completions", api_base.trim_end_matches('/')),
+            api_key: api_key.to_string(),
+        }
+    }
+}
+
+// The message of an error response, {"error": {"message": ...}} for
+// OpenAI-compatible endpoints, or the body itself otherwise
+fn error_message(body: &[u8]) -> String {
+    serde_json::from_slice::<Value>(body)
+        .ok()
+        .and_then(|error| error["error"]["message"].as_str().map(str::to_string))
+        .unwrap_or_else(|| String::from_utf8_lossy(body).trim().to_string())
+}
+
+#[async_trait]
+impl LlmClient for OpenAiClient {
+    async fn reply(
+        &self,
+        _call: Call,
+        request: CreateChatCompletionRequest,
+    ) -> Result<Reply, LlmError> {
+        let response = self
+            .http
+            .post(&self.url)
+            .bearer_auth(&self.api_key)
+            .json(&request)
+            .send()
+            .await
+            .map_err(OpenAIError::Reqwest)?;
+        let status = response.status();
+        let body = response.bytes().await.map_err(OpenAIError::Reqwest)?;
+        if !status.is_success() {
+            return Err(LlmError::Status {
+                status: status.as_u16(),
+                message: error_message(&body),
+            });
+        }
+        let response: CreateChatCompletionResponse =
+            serde_json::from_slice(&body).map_err(OpenAIError::JSONDeserialize)?;
+        let message = response
+            .choices
+            .into_iter()
+            .next()
+            .map(|choice| choice.message)
+            .ok_or(LlmError::EmptyResponse)?;
+        let reply = Reply {
+            content: message.content,
+            tool_calls: message.tool_calls.unwrap_or_default(),
+        };
+        if reply.content.is_none() && reply.tool_calls.is_empty() {
+            return Err(LlmError::EmptyResponse);
+        }
+        Ok(reply)
+    }
+}
+
+// How often and how patiently to retry a failed model call
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    // Delay before the given retry (1-based), doubling each time
+    pub fn backoff(&self, retry: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2_u32.saturating_pow(retry.saturating_sub(1)))
+            .min(self.max_backoff)
+    }
+}
+
+// Wraps another client and retries transient failures with exponential backoff
+pub struct RetryingClient {
+    inner: Box<dyn LlmClient>,
+    policy: RetryPolicy,
+}
+
+impl RetryingClient {
+    pub fn new(inner: Box<dyn LlmClient>, policy: RetryPolicy) -> Self {
+        RetryingClient { inner, policy }
+    }
+}
+
+#[async_trait]
+impl LlmClient for RetryingClient {
+    async fn reply(
+        &self,
+        call: Call,
+        request: CreateChatCompletionRequest,
+    ) -> Result<Reply, LlmError> {
+        let mut attempt = 1;
+        loop {
+            match self.inner.reply(call, request.clone()).await {
+                Ok(response) => return Ok(response),
+                Err(e) if e.is_transient() && attempt < self.policy.max_attempts => {
+                    let backoff = self.policy.backoff(attempt);
+                    println!(
+                        "Warning: {:?} request failed ({}), retrying in {:?} ({}/{})",
+                        call.stage, e, backoff, attempt, self.policy.max_attempts
+                    );
+                    tokio::time::sleep(backoff).await;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+// Offline stand-in that replays canned responses keyed by stage, iteration
+// and turn. Every request it receives is kept for later inspection.
+#[derive(Default)]
+pub struct ScriptedClient {
+    responses: HashMap<Stage, HashMap<(usize, usize), String>>,
+    defaults: HashMap<Stage, String>,
+    requests: Mutex<Vec<(Call, CreateChatCompletionRequest)>>,
+}
+
+impl ScriptedClient {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Respond with `response` to the given stage in the given iteration
+    pub fn with_response(self, stage: Stage, iteration: usize, response: &str) -> Self {
+        self.with_turn_response(stage, iteration, 0, response)
+    }
+
+    // Respond with `response` to a later turn of the stage's conversation
+    pub fn with_turn_response(
+        mut self,
+        stage: Stage,
+        iteration: usize,
+        turn: usize,
+        response: &str,
+    ) -> Self {
+        self.responses
+            .entry(stage)
+            .or_default()
+            .insert((iteration, turn), response.to_string());
+        self
+    }
+
+    // Respond with `response` to the stage whenever no iteration matches
+    pub fn with_default(mut self, stage: Stage, response: &str) -> Self {
+        self.defaults.insert(stage, response.to_string());
+        self
+    }
+
+    // Load a script of the form
+    //   { "analysis": [<iteration 0>, <iteration 1>, ...],
+    //     "planning": { "0": ..., "3": ..., "3.1": ..., "default": ... } }
+    // where "3.1" is the second turn of iteration 3. Responses may be strings
+    // or any JSON value, which is sent serialized. An object with a
+    // "tool_calls" list of {"name", "arguments"} is replayed as tool calls.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, LlmError> {
+        let text = std::fs::read_to_string(path.as_ref())
+            .map_err(|e| LlmError::InvalidScript(format!("{}: {}", path.as_ref().display(), e)))?;
+        Self::from_json(&text)
+    }
+
+    pub fn from_json(text: &str) -> Result<Self, LlmError> {
+        let script: HashMap<Stage, Value> =
+            serde_json::from_str(text).map_err(|e| LlmError::InvalidScript(e.to_string()))?;
+
+        let mut client = ScriptedClient::new();
+        for (stage, entries) in script {
+            match entries {
+                Value::Array(items) => {
+                    for (iteration, item) in items.iter().enumerate() {
+                        client = client.with_response(stage, iteration, &response_text(item));
+                    }
+                }
+                Value::Object(items) => {
+                    for (key, item) in &items {
+                        if key == "default" {
+                            client = client.with_default(stage, &response_text(item));
+                            continue;
+                        }
+                        let (iteration, turn) = key.split_once('.').unwrap_or((key, "0"));
+                        let (Ok(iteration), Ok(turn)) = (iteration.parse(), turn.parse()) else {
+                            return Err(LlmError::InvalidScript(format!(
+                                "{:?}: `{}` is not an iteration or iteration.turn number",
+                                stage, key
+                            )));
+                        };
+                        client =
+                            client.with_turn_response(stage, iteration, turn, &response_text(item));
+                    }
+                }
+                other => client = client.with_default(stage, &response_text(&other)),
+            }
+        }
+        Ok(client)
+    }
+
+    // All requests received so far, in order
+    pub fn requests(&self) -> Vec<(Call, CreateChatCompletionRequest)> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+fn response_text(value: &Value) -> String {
+    match value {
+        Value::String(text) => text.clone(),
+        other => other.to_string(),
+    }
+}
+
+// Turn a scripted response into a reply, replaying tool calls if it has any
+fn scripted_reply(call: Call, text: &str) -> Reply {
+    let script = match serde_json::from_str::<Value>(text) {
+        Ok(script @ Value::Object(_)) if script["tool_calls"].is_array() => script,
+        _ => {
+            return Reply {
+                content: Some(text.to_string()),
+                tool_calls: Vec::new(),
+            };
+        }
+    };
+
+    let tool_calls = script["tool_calls"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .enumerate()
+        .map(|(index, tool_call)| ChatCompletionMessageToolCall {
+            id: format!("call_{}_{}_{}", call.iteration, call.turn, index),
+            r#type: ChatCompletionToolType::Function,
+            function: FunctionCall {
+                name: tool_call["name"].as_str().unwrap_or_default().to_string(),
+                arguments: match &tool_call["arguments"] {
+                    Value::Null => "{}".to_string(),
+                    arguments => response_text(arguments),
+                },
+            },
+        })
+        .collect();
+    Reply {
+        content: script["content"].as_str().map(str::to_string),
+        tool_calls,
+    }
+}
+
+#[async_trait]
+impl LlmClient for ScriptedClient {
+    async fn reply(
+        &self,
+        call: Call,
+        request: CreateChatCompletionRequest,
+    ) -> Result<Reply, LlmError> {
+        self.requests.lock().unwrap().push((call, request));
+
+        self.responses
+            .get(&call.stage)
+            .and_then(|responses| responses.get(&(call.iteration, call.turn)))
+            .or_else(|| self.defaults.get(&call.stage))
+            .map(|text| scripted_reply(call, text))
+            .ok_or(LlmError::ScriptExhausted(call))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retries_only_transient_failures() {
+        let status = |status| LlmError::Status {
+            status,
+            message: String::new(),
+        };
+        assert!(status(429).is_transient());
+        assert!(status(503).is_transient());
+        assert!(!status(400).is_transient());
+        assert!(!status(401).is_transient());
+        assert!(LlmError::Api(OpenAIError::StreamError("reset".to_string())).is_transient());
+        let invalid = serde_json::from_str::<Value>("{").unwrap_err();
+        assert!(!LlmError::Api(OpenAIError::JSONDeserialize(invalid)).is_transient());
+
+        assert_eq!(
+            error_message(br#"{"error": {"message": "bad key", "code": 401}}"#),
+            "bad key"
+        );
+        assert_eq!(error_message(b"Bad Gateway\n"), "Bad Gateway");
+    }
+}
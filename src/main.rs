@@ -1,269 +1,45 @@
-use async_openai::Client;
-use async_openai::config::OpenAIConfig;
 use async_openai::types::{
+    ChatCompletionRequestAssistantMessageArgs, ChatCompletionRequestMessage,
     ChatCompletionRequestMessageContentPartImageArgs,
     ChatCompletionRequestMessageContentPartTextArgs, ChatCompletionRequestUserMessageArgs,
+    ChatCompletionRequestUserMessageContentPart, ChatCompletionToolChoiceOption,
     CreateChatCompletionRequestArgs, ImageDetail, ImageUrlArgs,
 };
+use automation::action::{self, Action};
+use automation::analysis::{ResponseMode, ScreenAnalysis, parse_analysis};
+use automation::clipboard::{Clipboard, SystemClipboard};
+use automation::coords::CoordinateSpace;
+use automation::diff;
+use automation::draw;
+use automation::emergency::{self, EmergencyStop, FailsafeBackend, Hotkey};
+use automation::error::{self, Error};
+use automation::executor::{self, Effect, retry_action};
+use automation::input::{EnigoBackend, InputBackend, RecordingBackend};
+use automation::launch::{self, Allowlist, LaunchError, LaunchTarget};
+use automation::llm::{
+    Call, LlmClient, LlmError, OpenAiClient, RetryPolicy, RetryingClient, ScriptedClient, Stage,
+};
+use automation::marks::{self, Mark, MarkSource};
+use automation::planner::{self, PlannerMode};
+use automation::policy::Policy;
+use automation::screen::{self, CaptureError, MonitorInfo, ScreenSource};
+use automation::session::Session;
+use automation::state::{ActionResult, TaskState};
+use automation::wait;
+use automation::window::{
+    self, WindowError, WindowGoal, WindowInfo, WindowManager, X11WindowManager,
+};
 use base64::Engine;
 use chrono::Local;
-use enigo::{Button, Coordinate, Direction, Enigo, Key, Keyboard, Mouse, Settings};
-use fs_extra::dir;
 use image::imageops::FilterType;
-use image::{GenericImageView, ImageFormat, ImageReader};
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use image::{DynamicImage, GenericImageView, ImageFormat, ImageReader, Rgba};
 use std::fs;
 use std::io::{self, BufRead, Write};
 use std::path::Path;
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use std::time::Instant;
 use std::{thread::sleep, time::Duration};
-use xcap::Monitor;
-
-#[derive(Debug, Serialize, Deserialize, Clone)]
-struct TaskState {
-    status: String,      // "in_progress", "completed", "paused", "failed", "task_done"
-    attempts: u32,       // Number of attempts made
-    last_action: String, // Last action taken
-    success_criteria: Vec<String>, // Criteria for task completion
-    memory: HashMap<String, String>, // Persistent memory across iterations
-    feedback: Vec<String>, // Feedback from previous attempts
-    start_time: i64,     // Unix timestamp when task started
-    last_update: i64,    // Unix timestamp of last update
-    action_results: Vec<ActionResult>, // Results of previous actions
-}
-
-#[derive(Debug, Serialize, Deserialize, Clone)]
-struct ActionResult {
-    action_type: String,           // Type of action performed
-    success: bool,                 // Whether the action was successful
-    timestamp: i64,                // When the action was performed
-    error_message: Option<String>, // Error message if the action failed
-    retry_count: u32,              // Number of retries attempted
-}
-
-impl ActionResult {
-    fn new(action_type: &str) -> Self {
-        ActionResult {
-            action_type: action_type.to_string(),
-            success: false,
-            timestamp: SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_secs() as i64,
-            error_message: None,
-            retry_count: 0,
-        }
-    }
-
-    fn success(mut self) -> Self {
-        self.success = true;
-        self
-    }
-
-    fn with_error(mut self, error: &str) -> Self {
-        self.error_message = Some(error.to_string());
-        self
-    }
-
-    fn increment_retry(mut self) -> Self {
-        self.retry_count += 1;
-        self
-    }
-}
-
-impl TaskState {
-    fn new() -> Self {
-        TaskState {
-            status: "in_progress".to_string(),
-            attempts: 0,
-            last_action: String::new(),
-            success_criteria: vec![
-                "Task completed".to_string(),
-                "Information found".to_string(),
-                "Research complete".to_string(),
-                "Task done".to_string(), // Added new success criterion
-            ],
-            memory: HashMap::new(),
-            feedback: Vec::new(),
-            start_time: SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_secs() as i64,
-            last_update: SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_secs() as i64,
-            action_results: Vec::new(),
-        }
-    }
-
-    fn update(&mut self, analysis: &str, actions: &str) {
-        self.attempts += 1;
-        self.last_update = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs() as i64;
-
-        // Parse the last action from actions JSON
-        if let Ok(actions_json) = serde_json::from_str::<Vec<serde_json::Value>>(actions) {
-            if let Some(last_action) = actions_json.last() {
-                if let Some(action_type) = last_action["action"].as_str() {
-                    self.last_action = action_type.to_string();
-                }
-            }
-        }
-
-        // Update memory based on analysis
-        if let Ok(analysis_json) = serde_json::from_str::<serde_json::Value>(analysis) {
-            if let Some(context) = analysis_json["context"].as_str() {
-                self.memory
-                    .insert("last_context".to_string(), context.to_string());
-            }
-            if let Some(state) = analysis_json["state"].as_object() {
-                if let Some(window_title) = state["window_title"].as_str() {
-                    self.memory
-                        .insert("last_window".to_string(), window_title.to_string());
-                }
-            }
-            // Add feedback from challenges
-            if let Some(challenges) = analysis_json["challenges"].as_array() {
-                for challenge in challenges {
-                    if let Some(challenge_str) = challenge.as_str() {
-                        self.feedback.push(challenge_str.to_string());
-                    }
-                }
-            }
-        }
-    }
-
-    fn should_pause(&self) -> bool {
-        // Pause if too many attempts
-        if self.attempts > 10 {
-            return true;
-        }
-
-        // Pause if stuck in a loop (same action repeated)
-        if self.attempts > 3 {
-            let last_actions: Vec<String> = self
-                .feedback
-                .iter()
-                .rev()
-                .take(3)
-                .filter_map(|f| f.split(":").next().map(|s| s.to_string()))
-                .collect();
-
-            if last_actions.len() == 3
-                && last_actions[0] == last_actions[1]
-                && last_actions[1] == last_actions[2]
-            {
-                return true;
-            }
-        }
-
-        false
-    }
-
-    fn is_complete(&self, analysis: &str) -> bool {
-        // Don't complete if we haven't taken any actions yet
-        if self.attempts < 2 {
-            return false;
-        }
-
-        // Check if all success criteria are met
-        for criterion in &self.success_criteria {
-            if !analysis.contains(criterion) {
-                return false;
-            }
-        }
-
-        // Check if there are no challenges
-        if let Ok(analysis_json) = serde_json::from_str::<serde_json::Value>(analysis) {
-            if let Some(challenges) = analysis_json["challenges"].as_array() {
-                if !challenges.is_empty() {
-                    return false;
-                }
-            }
-        }
-
-        // Check if we have performed meaningful actions
-        if self.last_action.is_empty() {
-            return false;
-        }
-
-        true
-    }
-
-    // New method to explicitly set task to done state
-    fn set_task_done(&mut self) {
-        self.status = "task_done".to_string();
-        self.last_update = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs() as i64;
-    }
-}
-
-// Function to load or create task state
-fn load_task_state(iteration_dir: &str) -> TaskState {
-    let state_path = Path::new(iteration_dir).join("task_state.json");
-    if state_path.exists() {
-        if let Ok(state_json) = fs::read_to_string(&state_path) {
-            if let Ok(state) = serde_json::from_str::<TaskState>(&state_json) {
-                return state;
-            }
-        }
-    }
-    TaskState::new()
-}
-
-// Function to save task state
-fn save_task_state(iteration_dir: &str, state: &TaskState) {
-    let state_path = Path::new(iteration_dir).join("task_state.json");
-    if let Ok(state_json) = serde_json::to_string_pretty(state) {
-        let _ = fs::write(&state_path, state_json);
-    }
-}
-
-// Function to get the last N iterations
-fn get_last_n_iterations(n: usize) -> Vec<(String, String, String)> {
-    let iterations_dir = Path::new("target/iterations");
-    if !iterations_dir.exists() {
-        return Vec::new();
-    }
-
-    let mut iterations: Vec<(String, String, String)> = Vec::new();
-
-    // Get all iteration directories and sort them by name (timestamp) in descending order
-    let mut dirs: Vec<_> = fs::read_dir(iterations_dir)
-        .unwrap()
-        .filter_map(|entry| entry.ok())
-        .filter(|entry| entry.path().is_dir())
-        .collect();
-    dirs.sort_by(|a, b| b.file_name().cmp(&a.file_name()));
-
-    // Take the last N iterations
-    for entry in dirs.iter().take(n) {
-        let dir_path = entry.path();
-        let metadata_path = dir_path.join("metadata.json");
-        let analysis_path = dir_path.join("analysis.json");
-        let actions_path = dir_path.join("actions.json");
-
-        if metadata_path.exists() && analysis_path.exists() && actions_path.exists() {
-            if let (Ok(metadata), Ok(analysis), Ok(actions)) = (
-                fs::read_to_string(&metadata_path),
-                fs::read_to_string(&analysis_path),
-                fs::read_to_string(&actions_path),
-            ) {
-                iterations.push((metadata, analysis, actions));
-            }
-        }
-    }
-
-    iterations
-}
 
 // Function to get screenshot from iteration directory
 fn get_screenshot_from_iteration(dir_path: &Path) -> Option<String> {
@@ -289,9 +65,9 @@ fn get_screenshot_from_iteration(dir_path: &Path) -> Option<String> {
 
 // Function to get the last N iterations with screenshots
 fn get_last_n_iterations_with_screenshots(
+    iterations_dir: &Path,
     n: usize,
 ) -> Vec<(String, String, String, Option<String>)> {
-    let iterations_dir = Path::new("target/iterations");
     if !iterations_dir.exists() {
         return Vec::new();
     }
@@ -304,7 +80,7 @@ fn get_last_n_iterations_with_screenshots(
         .filter_map(|entry| entry.ok())
         .filter(|entry| entry.path().is_dir())
         .collect();
-    dirs.sort_by(|a, b| b.file_name().cmp(&a.file_name()));
+    dirs.sort_by_key(|entry| std::cmp::Reverse(entry.file_name()));
 
     // Take the last N iterations
     for entry in dirs.iter().take(n) {
@@ -362,18 +138,18 @@ fn format_iterations_history(iterations: &[(String, String, String, Option<Strin
 
 // Function to generate self-instruction based on history
 async fn generate_self_instruction(
-    client: &Client<OpenAIConfig>,
+    llm: &dyn LlmClient,
     model_name: &str,
+    iteration: usize,
     history: &[(String, String, String, Option<String>)],
     current_instruction: &str,
     task_state: &TaskState,
-) -> String {
+) -> Result<String, LlmError> {
     if history.is_empty() {
-        return current_instruction.to_string();
+        return Ok(current_instruction.to_string());
     }
 
     let history_text = format_iterations_history(history);
-    let last_iteration = &history[0];
 
     // Use task state for feedback instead of is_task_complete
     let feedback = if task_state.status == "completed" {
@@ -426,236 +202,1805 @@ Response should be ONLY the new instruction, no additional text.",
         .build()
         .unwrap();
 
-    let response = client
-        .chat()
-        .create(self_instruction_request)
-        .await
-        .unwrap();
-    let mut new_instruction = String::new();
-    for choice in response.choices {
-        new_instruction = choice.message.content.unwrap_or_default();
+    let new_instruction = llm
+        .complete(
+            Call::new(Stage::SelfInstruction, iteration),
+            self_instruction_request,
+        )
+        .await?;
+
+    Ok(new_instruction.trim().to_string())
+}
+
+// Model settings for the analysis stage
+struct AnalysisSettings {
+    model_name: String,
+    max_tokens: u32,
+    response_mode: ResponseMode,
+    max_repairs: u32,
+}
+
+// Function to tell whether a rejected request failed because of its
+// response_format rather than anything else about it
+fn rejects_response_format(message: &str) -> bool {
+    let message = message.to_lowercase();
+    message.contains("response_format") || message.contains("json_schema")
+}
+
+// Function to run the analysis stage. A reply that does not parse or validate
+// is sent back with the error for a bounded number of repair attempts.
+async fn request_analysis(
+    llm: &dyn LlmClient,
+    settings: &mut AnalysisSettings,
+    iteration: usize,
+    content: Vec<ChatCompletionRequestUserMessageContentPart>,
+    space: &CoordinateSpace,
+) -> error::Result<ScreenAnalysis> {
+    let mut messages: Vec<ChatCompletionRequestMessage> = vec![
+        ChatCompletionRequestUserMessageArgs::default()
+            .content(content)
+            .build()
+            .unwrap()
+            .into(),
+    ];
+    let mut stage = Stage::Analysis;
+    let mut repairs = 0;
+
+    loop {
+        let mut request = CreateChatCompletionRequestArgs::default();
+        request
+            .model(&settings.model_name)
+            .max_tokens(settings.max_tokens)
+            .messages(messages.clone());
+        if let Some(response_format) = settings.response_mode.response_format() {
+            request.response_format(response_format);
+        }
+        let request = request.build().unwrap();
+
+        let response = match llm.complete(Call::new(stage, iteration), request).await {
+            Ok(response) => response,
+            // Not every provider supports structured output; once one rejects
+            // the response format, the prompt alone is used for the rest of the run
+            Err(LlmError::Status {
+                status: 400,
+                message,
+            }) if settings.response_mode != ResponseMode::Text
+                && rejects_response_format(&message) =>
+            {
+                println!(
+                    "Warning: {:?} response format rejected ({}), falling back to text",
+                    settings.response_mode, message
+                );
+                settings.response_mode = ResponseMode::Text;
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        println!("Analysis Response: {}", response);
+
+        let analysis = parse_analysis(&response).and_then(|analysis| {
+            analysis.validate(space.image_width, space.image_height)?;
+            Ok(analysis)
+        });
+        match analysis {
+            Ok(analysis) => return Ok(analysis),
+            Err(e) if repairs < settings.max_repairs => {
+                repairs += 1;
+                println!(
+                    "Warning: {}, asking for a repair ({}/{})",
+                    e, repairs, settings.max_repairs
+                );
+                messages.push(
+                    ChatCompletionRequestAssistantMessageArgs::default()
+                        .content(response)
+                        .build()
+                        .unwrap()
+                        .into(),
+                );
+                messages.push(
+                    ChatCompletionRequestUserMessageArgs::default()
+                        .content(format!(
+                            "Your response could not be used: {}. Reply with ONLY the corrected JSON object.",
+                            e
+                        ))
+                        .build()
+                        .unwrap()
+                        .into(),
+                );
+                stage = Stage::AnalysisRepair;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+// Function to pause the task: release held inputs and persist the state
+fn pause_task(
+    input: &mut dyn InputBackend,
+    task_state: &mut TaskState,
+    session: &Session,
+    reason: &str,
+) {
+    if let Err(e) = input.release_all() {
+        println!("Error: Could not release held inputs: {}", e);
+    }
+
+    if task_state.status != "in_progress" {
+        return;
+    }
+    task_state.pause(reason);
+    session.save_state(task_state);
+    println!("Task paused: {}", reason);
+}
+
+// Where a request for the operator's approval stands. The runner asks and
+// waits; the input thread records the answer. Answers come from stdin only:
+// there is no control API to approve over yet, and one would record its
+// answers here the same way.
+#[derive(Debug, Clone, PartialEq)]
+enum Approval {
+    None,
+    Pending,
+    Approved,
+    Rejected(String),
+}
+
+// Function to read an answer to an approval request: y, yes or approve, or
+// n, no or reject followed by an optional reason
+fn parse_approval(input: &str) -> Option<Approval> {
+    let (word, reason) = input.split_once(' ').unwrap_or((input, ""));
+    match word.to_lowercase().as_str() {
+        "y" | "yes" | "approve" if reason.is_empty() => Some(Approval::Approved),
+        "n" | "no" | "reject" => Some(Approval::Rejected(reason.trim().to_string())),
+        _ => None,
+    }
+}
+
+// Everything the actions of one iteration are executed and verified with
+struct ActionRunner<'a> {
+    llm: &'a dyn LlmClient,
+    screen: &'a mut dyn ScreenSource,
+    input: &'a mut dyn InputBackend,
+    session: &'a Session,
+    should_continue: &'a Mutex<bool>,
+    is_paused: &'a Mutex<bool>,
+    // Set by the hotkey or the corner failsafe, with the reason of the pause
+    emergency: &'a EmergencyStop,
+    approval: &'a Mutex<Approval>,
+    is_idle: &'a Mutex<bool>,
+    model_name: &'a str,
+    max_tokens: u32,
+    iteration: usize,
+    iteration_dir: &'a str,
+    // Directory of the latest capture, whose screenshot.png zooms crop from
+    capture_dir: String,
+    display_size: (i32, i32),
+    grid: Option<u32>,
+    // Updated whenever the screen is captured again during the iteration
+    space: CoordinateSpace,
+    monitors: Vec<MonitorInfo>,
+    // Numbered elements the planner may click with click_element
+    mark_source: Option<MarkSource>,
+    marks: Vec<Mark>,
+    // Region of the last zoom, which refines the next action aimed inside it
+    zoom: Option<[i32; 4]>,
+    // Check mouse and keyboard actions against a diff of the screen
+    verify_pixels: bool,
+    // Window management through the window manager, when there is one
+    windows: Option<&'a dyn WindowManager>,
+    // Applications launch_app may start
+    launch_allowlist: &'a Allowlist,
+    // X display of the screen source when it is not DISPLAY
+    display: Option<&'a str>,
+    // Rules every action must pass before it runs
+    policy: &'a Policy,
+    // Record actions on the screenshots instead of carrying them out
+    dry_run: bool,
+    // Actions recorded in a dry run, with their targets in screenshot pixels
+    rehearsed: Vec<serde_json::Value>,
+    // Approval requests made, which number their screenshot crops
+    approvals: usize,
+    // Set once the operator rejects an action; the rest of the batch assumed
+    // it would run
+    rejected: bool,
+    clipboard: Option<&'a mut dyn Clipboard>,
+    // Element the last click landed on, where typed text should show up
+    focus: Option<[i32; 4]>,
+}
+
+// Whether a pause or the end of the run should cut a wait short
+fn interruption<'a>(
+    is_paused: &'a Mutex<bool>,
+    should_continue: &'a Mutex<bool>,
+) -> impl Fn() -> bool + 'a {
+    move || *is_paused.lock().unwrap() || !*should_continue.lock().unwrap()
+}
+
+impl ActionRunner<'_> {
+    // Check before an action whether the batch may go on. A stop or pause
+    // aborts the rest of it; the previous action has already finished.
+    fn may_continue(
+        &mut self,
+        action: &Action,
+        remaining: usize,
+        task_state: &mut TaskState,
+        was_paused: &mut bool,
+    ) -> bool {
+        if !*self.should_continue.lock().unwrap() {
+            return false;
+        }
+        if self.rejected {
+            println!(
+                "Skipping the remaining {} actions after the operator rejected one",
+                remaining
+            );
+            return false;
+        }
+        if *self.is_paused.lock().unwrap() {
+            println!(
+                "Pause requested, skipping the remaining {} actions",
+                remaining
+            );
+            let reason = self
+                .emergency
+                .take()
+                .unwrap_or_else(|| format!("operator requested pause before {}", action.name()));
+            pause_task(self.input, task_state, self.session, &reason);
+            *was_paused = true;
+            return false;
+        }
+        true
+    }
+
+    // Execute an action and verify it. An error means the input failed
+    // halfway; whatever it left pressed has been released.
+    async fn run_action(
+        &mut self,
+        action: &Action,
+        analysis: &ScreenAnalysis,
+        task_state: &mut TaskState,
+    ) -> error::Result<ActionResult> {
+        // The model answers in screenshot pixels or with a marked element;
+        // the input needs logical pixels
+        let resolved = executor::resolve_element(action, &self.marks)?;
+        let refined = match self.zoom.take() {
+            Some(region) => self.refine_target(&resolved, region).await,
+            None => None,
+        };
+        let mut screen_action = resolved.map_coordinates(|monitor, x, y| match monitor {
+            None => Ok(self.space.image_to_logical(x, y)),
+            Some(index) => self
+                .monitors
+                .iter()
+                .find(|m| m.index == index)
+                .map(|m| self.space.monitor_to_logical(m, x, y))
+                .ok_or(Error::Capture(CaptureError::NoMonitor(index))),
+        })?;
+        if let Some((x, y)) = refined {
+            screen_action = screen_action.retarget(x, y);
+        }
+
+        if let Err(reason) = self.check_policy(action, &screen_action) {
+            let message = format!("{} denied by the safety policy: {}", action.name(), reason);
+            println!("Denied: {}", message);
+            // Feedback carries the denial into the next iteration's prompts
+            task_state.feedback.push(message.clone());
+            let result = ActionResult::new(action.name()).with_error(&message);
+            task_state.action_results.push(result.clone());
+            self.session.save_state(task_state);
+            return Ok(result);
+        }
+
+        let approval = if self.policy.watches_active_window() {
+            self.policy
+                .needs_approval(action, &self.active_window(analysis))
+        } else {
+            self.policy.needs_approval(action, "")
+        };
+
+        // Zoom and task_done only affect the agent itself
+        if self.dry_run && !matches!(action, Action::Zoom { .. } | Action::TaskDone { .. }) {
+            return self.rehearse(action, &screen_action, approval);
+        }
+
+        // The area the action should change: the clicked element, or for
+        // typing the one the last click focused
+        let area = match &resolved {
+            Action::MouseClick { .. } | Action::ClickAt { .. } | Action::DoubleClick { .. } => {
+                let area = match action {
+                    Action::ClickElement { id, .. } => {
+                        marks::find(&self.marks, *id).ok().map(|mark| mark.coords)
+                    }
+                    // Where the action lands after any zoom, in image pixels
+                    _ => screen_action
+                        .target()
+                        .map(|(x, y)| self.space.logical_to_image(x, y))
+                        .and_then(|(x, y)| executor::element_at(analysis, x, y)),
+                };
+                self.focus = area;
+                area
+            }
+            Action::TextInput { .. } | Action::PasteText { .. } => self.focus,
+            Action::WindowFocus { .. }
+            | Action::KeyPress { .. }
+            | Action::KeyCombination { .. } => {
+                // Focus may have moved somewhere else
+                self.focus = None;
+                None
+            }
+            _ => None,
+        };
+
+        if let Some(reason) = approval {
+            if let Some(refused) =
+                self.ask_approval(action, &screen_action, area, &reason, task_state)
+            {
+                return Ok(refused);
+            }
+        }
+
+        // Raise the exact window when the window manager lets us, instead of
+        // cycling through them with the keyboard
+        if let (Action::WindowFocus { .. }, Some(_)) = (action, self.windows) {
+            return Ok(self.manage_window(action, task_state));
+        }
+
+        // Pasting needs the text on the clipboard first
+        if let Action::PasteText { text } = action {
+            if let Err(message) = self.set_clipboard(text) {
+                println!("Error: {}", message);
+                let result = ActionResult::new(action.name()).with_error(&message);
+                task_state.action_results.push(result.clone());
+                return Ok(result);
+            }
+        }
+
+        let before = if self.verify_pixels && executor::expects_change(action) {
+            self.screen
+                .capture()
+                .inspect_err(|e| println!("Warning: No capture to verify against: {}", e))
+                .ok()
+        } else {
+            None
+        };
+
+        if let Err(e) = executor::perform_action(&screen_action, self.input) {
+            if let Err(e) = self.input.release_all() {
+                println!("Error: Could not release held inputs: {}", e);
+            }
+            return Err(e);
+        }
+
+        // Verify the action
+        let action_result = match action {
+            Action::WindowFocus { .. } => self.verify_window_focus(action, task_state).await,
+            Action::Wait { ms } => {
+                if !wait::sleep_for(
+                    Duration::from_millis(*ms),
+                    &interruption(self.is_paused, self.should_continue),
+                ) {
+                    println!("The wait was cut short by a pause");
+                }
+                // Wait actions always succeed
+                ActionResult::new(action.name()).success()
+            }
+            Action::WaitForChange { .. }
+            | Action::WaitForStable { .. }
+            | Action::WaitForWindow { .. } => self.wait_for(action, task_state),
+            Action::WindowMove { .. }
+            | Action::WindowResize { .. }
+            | Action::WindowMaximize { .. }
+            | Action::WindowMinimize { .. }
+            | Action::WindowClose { .. } => self.manage_window(action, task_state),
+            Action::LaunchApp { .. } => self.launch_app(action, task_state),
+            Action::ClipboardSet { .. } | Action::ClipboardGet { .. } => {
+                self.use_clipboard(action, task_state)
+            }
+            Action::Zoom { x1, y1, x2, y2 } => {
+                self.zoom = Some([*x1, *y1, *x2, *y2]);
+                ActionResult::new(action.name()).success()
+            }
+            Action::TaskDone { reason } => {
+                println!("Task done. Reason: {}", reason);
+                task_state.set_task_done();
+                self.session.save_state(task_state);
+                *self.is_idle.lock().unwrap() = true;
+
+                // Task done actions always succeed
+                ActionResult::new(action.name()).success()
+            }
+            _ => {
+                // Compare a capture taken once the screen settled with the
+                // one from before the action
+                let screen = &mut *self.screen;
+                let space = self.space;
+                let mut observe = || {
+                    let before = before.as_ref()?;
+                    sleep(Duration::from_millis(SETTLE_MS));
+                    let after = screen
+                        .capture()
+                        .inspect_err(|e| println!("Warning: No capture to verify with: {}", e))
+                        .ok()?;
+                    let diff = diff::compare(before, &after).to_image(&space);
+                    println!("After {}: {}", action.name(), diff.describe());
+                    Some(Effect { diff, area })
+                };
+                retry_action(
+                    action,
+                    analysis,
+                    task_state,
+                    self.input,
+                    &self.space,
+                    &mut observe,
+                )
+            }
+        };
+        Ok(action_result)
+    }
+
+    // Dry run: send an action to the recording input and mark where it is
+    // aimed on the latest capture, without touching windows, the clipboard
+    // or applications. Recorded actions count as successful.
+    fn rehearse(
+        &mut self,
+        action: &Action,
+        screen_action: &Action,
+        approval: Option<String>,
+    ) -> error::Result<ActionResult> {
+        executor::perform_action(screen_action, self.input)?;
+
+        // Pointer actions end with the cursor on their target
+        let targets: Vec<(i32, i32)> = match screen_action {
+            Action::Drag {
+                from_x,
+                from_y,
+                to_x,
+                to_y,
+                ..
+            } => vec![(*from_x, *from_y), (*to_x, *to_y)],
+            Action::MouseMove { .. }
+            | Action::MouseClick { .. }
+            | Action::ClickAt { .. }
+            | Action::DoubleClick { .. }
+            | Action::MouseDown { .. }
+            | Action::MouseUp { .. }
+            | Action::Scroll { .. } => self.input.location().into_iter().collect(),
+            _ => Vec::new(),
+        };
+        let targets: Vec<(i32, i32)> = targets
+            .into_iter()
+            .map(|(x, y)| self.space.logical_to_image(x, y))
+            .collect();
+
+        self.rehearsed.push(serde_json::json!({
+            "action": action,
+            "targets": targets,
+            "needs_approval": approval,
+        }));
+        save_artifact(
+            &format!("{}/dry_run.json", self.iteration_dir),
+            &serde_json::to_string_pretty(&self.rehearsed).unwrap_or_default(),
+        );
+        if !targets.is_empty() {
+            self.draw_targets(self.rehearsed.len(), &targets);
+        }
+        println!(
+            "Dry run: recorded {} #{}",
+            action.name(),
+            self.rehearsed.len()
+        );
+        Ok(ActionResult::new(action.name()).success())
+    }
+
+    // Mark the targets of a recorded action, numbered in plan order, on a
+    // copy of the latest capture
+    fn draw_targets(&self, number: usize, targets: &[(i32, i32)]) {
+        let marked_path = format!("{}/dry_run.png", self.capture_dir);
+        let source_path = if Path::new(&marked_path).exists() {
+            marked_path.clone()
+        } else {
+            format!("{}/screenshot.png", self.capture_dir)
+        };
+        let mut marked = match image::open(&source_path) {
+            Ok(capture) => capture.to_rgba8(),
+            Err(e) => {
+                println!("Error: Could not open {} to draw on: {}", source_path, e);
+                return;
+            }
+        };
+        for &(x, y) in targets {
+            let (x, y) = self.space.image_to_capture(x, y);
+            draw::crosshair(&mut marked, x, y, 12, DRY_RUN_COLOR);
+            draw::tag(&mut marked, x + 14, y + 14, number, DRY_RUN_COLOR);
+        }
+        save_artifact_image(&DynamicImage::ImageRgba8(marked), &marked_path);
+    }
+
+    // Title of the active window: from the window manager when there is
+    // one, otherwise as the analysis read it off the screenshot
+    fn active_window(&self, analysis: &ScreenAnalysis) -> String {
+        self.windows
+            .and_then(|windows| windows.list().ok())
+            .and_then(|list| list.into_iter().find(|window| window.active))
+            .map(|window| window.title)
+            .unwrap_or_else(|| analysis.state.active_window.clone())
+    }
+
+    // Show the operator an action the policy wants approved and wait for
+    // their answer. None means approved; otherwise the failed result of an
+    // action that must not run, whose rejection is fed back to the planner.
+    fn ask_approval(
+        &mut self,
+        action: &Action,
+        screen_action: &Action,
+        area: Option<[i32; 4]>,
+        reason: &str,
+        task_state: &mut TaskState,
+    ) -> Option<ActionResult> {
+        self.approvals += 1;
+        let crop = self.approval_crop(screen_action, area);
+        println!("\nApproval needed: {}", reason);
+        println!(
+            "{}",
+            serde_json::to_string_pretty(action).unwrap_or_default()
+        );
+        if let Some(path) = crop {
+            println!("Target: {}", path);
+        }
+        println!("Run it? Answer y on stdin to approve, or n followed by a reason to reject");
+        *self.approval.lock().unwrap() = Approval::Pending;
+
+        let answer = loop {
+            if !*self.should_continue.lock().unwrap() || *self.is_paused.lock().unwrap() {
+                break None;
+            }
+            match &*self.approval.lock().unwrap() {
+                Approval::Pending => {}
+                answer => break Some(answer.clone()),
+            }
+            sleep(Duration::from_millis(100));
+        };
+        *self.approval.lock().unwrap() = Approval::None;
+
+        let message = match answer {
+            Some(Approval::Approved) => {
+                println!("Approved, running {}", action.name());
+                return None;
+            }
+            Some(Approval::Rejected(reason)) if !reason.is_empty() => {
+                format!("{} rejected by the operator: {}", action.name(), reason)
+            }
+            Some(_) => format!("{} rejected by the operator", action.name()),
+            None => {
+                // The pause or stop itself ends the batch
+                return Some(
+                    ActionResult::new(action.name())
+                        .with_error("skipped: the automation was paused before approval"),
+                );
+            }
+        };
+        println!("{}", message);
+        self.rejected = true;
+        task_state.feedback.push(message.clone());
+        let result = ActionResult::new(action.name()).with_error(&message);
+        task_state.action_results.push(result.clone());
+        self.session.save_state(task_state);
+        Some(result)
+    }
+
+    // Save a crop of the latest capture around where an action, in logical
+    // pixels and refined by any zoom, is aimed, marked, for the operator to
+    // approve. The whole capture is saved for actions without a place on the
+    // screen.
+    fn approval_crop(&self, screen_action: &Action, area: Option<[i32; 4]>) -> Option<String> {
+        let capture_path = format!("{}/screenshot.png", self.capture_dir);
+        let mut capture = match image::open(&capture_path) {
+            Ok(capture) => capture.to_rgba8(),
+            Err(e) => {
+                println!("Error: Could not open {} to crop: {}", capture_path, e);
+                return None;
+            }
+        };
+
+        // Everything to mark, in capture pixels
+        let to_capture = |(x, y): (i32, i32)| {
+            let (x, y) = self.space.logical_to_image(x, y);
+            self.space.image_to_capture(x, y)
+        };
+        let mut points = Vec::new();
+        if let Action::Drag { to_x, to_y, .. } = screen_action {
+            points.push(to_capture((*to_x, *to_y)));
+        }
+        match screen_action.target() {
+            Some(target) => points.push(to_capture(target)),
+            None if matches!(
+                screen_action,
+                Action::MouseClick { .. }
+                    | Action::DoubleClick { .. }
+                    | Action::MouseDown { .. }
+                    | Action::MouseUp { .. }
+            ) =>
+            {
+                if let Ok(location) = self.input.location() {
+                    points.push(to_capture(location));
+                }
+            }
+            None => {}
+        }
+        let area = area.map(|[x1, y1, x2, y2]| {
+            let (x1, y1) = self.space.image_to_capture(x1, y1);
+            let (x2, y2) = self.space.image_to_capture(x2, y2);
+            [x1, y1, x2, y2]
+        });
+
+        let mut bounds: Option<[i32; 4]> = area;
+        for &(x, y) in &points {
+            draw::crosshair(&mut capture, x, y, 12, APPROVAL_COLOR);
+            bounds = Some(match bounds {
+                Some([x1, y1, x2, y2]) => [x1.min(x), y1.min(y), x2.max(x), y2.max(y)],
+                None => [x, y, x, y],
+            });
+        }
+        if let Some([x1, y1, x2, y2]) = area {
+            draw::outline(&mut capture, [x1, y1, x2 + 1, y2 + 1], APPROVAL_COLOR);
+        }
+
+        let mut marked = DynamicImage::ImageRgba8(capture);
+        if let Some([x1, y1, x2, y2]) = bounds {
+            let (width, height) = (marked.width() as i32, marked.height() as i32);
+            let (x1, y1) = ((x1 - APPROVAL_MARGIN).max(0), (y1 - APPROVAL_MARGIN).max(0));
+            let (x2, y2) = (
+                (x2 + APPROVAL_MARGIN).min(width - 1),
+                (y2 + APPROVAL_MARGIN).min(height - 1),
+            );
+            marked = marked.crop_imm(
+                x1 as u32,
+                y1 as u32,
+                (x2 - x1 + 1).max(1) as u32,
+                (y2 - y1 + 1).max(1) as u32,
+            );
+        }
+        let path = format!("{}/approval_{}.png", self.iteration_dir, self.approvals);
+        save_artifact_image(&marked, &path);
+        Some(path)
+    }
+
+    // Check a planned action, and the same action in input pixels, against
+    // the safety policy, reading the cursor and the open windows for the
+    // click rules
+    fn check_policy(&self, action: &Action, screen_action: &Action) -> Result<(), String> {
+        let cursor = self.input.location().ok();
+        let windows = match self.windows {
+            Some(windows) if self.policy.restricts_clicks_to_windows() => {
+                windows.list().unwrap_or_else(|e| {
+                    println!(
+                        "Warning: Could not list the windows clicks are allowed in: {}",
+                        e
+                    );
+                    Vec::new()
+                })
+            }
+            _ => Vec::new(),
+        };
+        self.policy.check(action, screen_action, cursor, &windows)
+    }
+
+    // Carry out a window action through the window manager and check it by
+    // reading the window back: window_focus makes it the active window, the
+    // others change its geometry or state, or close it
+    fn manage_window(&self, action: &Action, task_state: &mut TaskState) -> ActionResult {
+        let managed = match self.windows {
+            Some(windows) => self.request_window(windows, action),
+            None => Ok(Err(
+                "Window management needs an EWMH window manager, which is unavailable".to_string(),
+            )),
+        };
+        let message = match managed {
+            Ok(Ok(())) => return ActionResult::new(action.name()).success(),
+            Ok(Err(message)) => message,
+            Err(e) => format!("{} failed: {}", action.name(), e),
+        };
+        println!("Error: {}", message);
+        let result = ActionResult::new(action.name()).with_error(&message);
+        task_state.action_results.push(result.clone());
+        result
+    }
+
+    // The request behind manage_window. The inner error explains a window
+    // that does not exist or did not end up as asked.
+    fn request_window(
+        &self,
+        windows: &dyn WindowManager,
+        action: &Action,
+    ) -> Result<Result<(), String>, WindowError> {
+        let (title, class) = match action {
+            Action::WindowFocus { title, class, .. }
+            | Action::WindowMove { title, class, .. }
+            | Action::WindowResize { title, class, .. }
+            | Action::WindowMaximize { title, class }
+            | Action::WindowMinimize { title, class }
+            | Action::WindowClose { title, class } => (title, class),
+            _ => return Ok(Ok(())),
+        };
+        let list = windows.list()?;
+        let Some(target) = window::find(&list, title, class) else {
+            return Ok(Err(format!(
+                "No window matches \"{}\" ({}). Open windows:\n{}",
+                title,
+                class,
+                window::describe(&windows_in_image(list, &self.space))
+            )));
+        };
+        println!(
+            "{} on window \"{}\" ({})",
+            action.name(),
+            target.title,
+            target.class
+        );
+
+        // Sizes and positions are given in screenshot pixels
+        let goal = match action {
+            Action::WindowMove { x, y, .. } => {
+                let (x, y) = self.space.image_to_logical(*x, *y);
+                windows.move_resize(target.id, Some((x, y)), None)?;
+                WindowGoal::Position(x, y)
+            }
+            Action::WindowResize { width, height, .. } => {
+                let (width, height) = self.space.image_to_logical_size(*width, *height);
+                windows.move_resize(target.id, None, Some((width, height)))?;
+                WindowGoal::Size(width, height)
+            }
+            Action::WindowMaximize { .. } => {
+                windows.maximize(target.id)?;
+                WindowGoal::Maximized
+            }
+            Action::WindowMinimize { .. } => {
+                windows.minimize(target.id)?;
+                WindowGoal::Minimized
+            }
+            Action::WindowClose { .. } => {
+                windows.close(target.id)?;
+                WindowGoal::Closed
+            }
+            _ => {
+                windows.activate(target.id)?;
+                WindowGoal::Active
+            }
+        };
+
+        self.await_window(windows, target, goal, action.name())
+    }
+
+    // Read a window back until it reaches the goal of a request about it
+    fn await_window(
+        &self,
+        windows: &dyn WindowManager,
+        target: &WindowInfo,
+        goal: WindowGoal,
+        request: &str,
+    ) -> Result<Result<(), String>, WindowError> {
+        let mut current = None;
+        for _ in 0..WINDOW_POLLS {
+            sleep(Duration::from_millis(100));
+            current = windows
+                .list()?
+                .into_iter()
+                .find(|window| window.id == target.id);
+            if goal.reached(current.as_ref()) {
+                return Ok(Ok(()));
+            }
+        }
+        Ok(Err(match current {
+            Some(window) => format!(
+                "{} did not take effect; the window is now:\n{}",
+                request,
+                window::describe(&windows_in_image(vec![window], &self.space))
+            ),
+            None => format!("\"{}\" closed instead", target.title),
+        }))
+    }
+
+    // Start an application for a launch_app action, wait for its window and
+    // bring it to the front
+    fn launch_app(&mut self, action: &Action, task_state: &mut TaskState) -> ActionResult {
+        let message = match self.start_app(action) {
+            Ok(outcome) => {
+                println!("{}", outcome);
+                return ActionResult::new(action.name()).success();
+            }
+            Err(message) => message,
+        };
+        println!("Error: launch_app failed: {}", message);
+        let result = ActionResult::new(action.name()).with_error(&message);
+        task_state.action_results.push(result.clone());
+        result
+    }
+
+    fn start_app(&mut self, action: &Action) -> Result<String, String> {
+        let Action::LaunchApp {
+            name,
+            desktop_id,
+            command,
+            timeout_ms,
+        } = action
+        else {
+            return Ok("Nothing to launch".to_string());
+        };
+        let given = |field: &Option<String>| field.clone().filter(|value| !value.trim().is_empty());
+        let target = match (given(name), given(desktop_id), given(command)) {
+            (_, _, Some(command)) => {
+                LaunchTarget::Command(launch::split_command(&command).map_err(|e| e.to_string())?)
+            }
+            (Some(name), _, _) | (_, Some(name), _) => {
+                let entries = launch::desktop_entries(&launch::application_dirs());
+                let entry = launch::find_entry(&entries, &name)
+                    .ok_or_else(|| LaunchError::NotFound(name).to_string())?;
+                LaunchTarget::Entry(entry.clone())
+            }
+            (None, None, None) => return Err("nothing to launch".to_string()),
+        };
+        if !self.launch_allowlist.allows(&target) {
+            return Err(LaunchError::NotAllowed(target.describe()).to_string());
+        }
+        let args = match &target {
+            LaunchTarget::Entry(entry) => entry.command().map_err(|e| e.to_string())?,
+            LaunchTarget::Command(args) => args.clone(),
+        };
+
+        let timeout =
+            Duration::from_millis(timeout_ms.unwrap_or(action::DEFAULT_LAUNCH_TIMEOUT_MS));
+        let hints: Vec<String> = target
+            .window_hints()
+            .iter()
+            .map(|hint| hint.to_lowercase())
+            .collect();
+        let interrupted = interruption(self.is_paused, self.should_continue);
+        let Some(windows) = self.windows else {
+            // Without a window manager, wait for the title through the screen source
+            let pid = launch::spawn_detached(&args, self.display).map_err(|e| e.to_string())?;
+            let title = hints.last().cloned().unwrap_or_default();
+            return match wait::wait_for_window(self.screen, &title, timeout, &interrupted) {
+                Ok(Some(window)) => Ok(format!(
+                    "Started {} (pid {}): \"{}\"",
+                    target.describe(),
+                    pid,
+                    window
+                )),
+                Ok(None) => Err(format!(
+                    "Started {} (pid {}), but no window titled \"{}\" appeared",
+                    target.describe(),
+                    pid,
+                    title
+                )),
+                Err(e) => Err(format!(
+                    "Started {} (pid {}), but could not watch for its window: {}",
+                    target.describe(),
+                    pid,
+                    e
+                )),
+            };
+        };
+
+        // The application's window is one that was not there before
+        let before: Vec<u32> = windows
+            .list()
+            .map_err(|e| e.to_string())?
+            .iter()
+            .map(|window| window.id)
+            .collect();
+        let pid = launch::spawn_detached(&args, self.display).map_err(|e| e.to_string())?;
+        println!("Started {} with pid {}", target.describe(), pid);
+
+        let started = Instant::now();
+        let new_window = loop {
+            sleep(Duration::from_millis(100));
+            if interrupted() {
+                return Err(format!(
+                    "Started {} (pid {}), but a pause interrupted the wait for its window",
+                    target.describe(),
+                    pid
+                ));
+            }
+            let new: Vec<WindowInfo> = windows
+                .list()
+                .map_err(|e| e.to_string())?
+                .into_iter()
+                .filter(|window| !before.contains(&window.id))
+                .collect();
+            let matching = new.iter().find(|window| {
+                let names = [&window.title, &window.class, &window.instance]
+                    .map(|name| name.to_lowercase());
+                hints
+                    .iter()
+                    .any(|hint| names.iter().any(|name| name.contains(hint)))
+            });
+            if let Some(window) = matching {
+                break window.clone();
+            }
+            if started.elapsed() >= timeout {
+                // Settle for a window that does not carry the application's name
+                match new.into_iter().next() {
+                    Some(window) => break window,
+                    None => {
+                        return Err(format!(
+                            "Started {} (pid {}), but no window appeared within {}ms",
+                            target.describe(),
+                            pid,
+                            timeout.as_millis()
+                        ));
+                    }
+                }
+            }
+        };
+
+        let focused = windows
+            .activate(new_window.id)
+            .and_then(|_| self.await_window(windows, &new_window, WindowGoal::Active, "launch_app"))
+            .map_err(|e| e.to_string())?;
+        focused.map(|_| {
+            format!(
+                "Launched {}: \"{}\" is active",
+                target.describe(),
+                new_window.title
+            )
+        })
+    }
+
+    // Carry out a clipboard action. The text clipboard_get reads is kept in
+    // the task memory, where later plans see it, and returned to the planner.
+    fn use_clipboard(&mut self, action: &Action, task_state: &mut TaskState) -> ActionResult {
+        let used = match action {
+            Action::ClipboardSet { text } => self.set_clipboard(text).map(|_| None),
+            Action::ClipboardGet {} => match self.clipboard.as_deref_mut() {
+                Some(clipboard) => clipboard
+                    .get_text()
+                    .map(Some)
+                    .map_err(|e| format!("clipboard_get failed: {}", e)),
+                None => Err(CLIPBOARD_UNAVAILABLE.to_string()),
+            },
+            _ => Ok(None),
+        };
+        match used {
+            Ok(Some(text)) => {
+                println!("Clipboard holds {} characters", text.chars().count());
+                task_state
+                    .memory
+                    .insert("clipboard".to_string(), text.clone());
+                self.session.save_state(task_state);
+                ActionResult::new(action.name())
+                    .success()
+                    .with_output(&text)
+            }
+            Ok(None) => ActionResult::new(action.name()).success(),
+            Err(message) => {
+                println!("Error: {}", message);
+                let result = ActionResult::new(action.name()).with_error(&message);
+                task_state.action_results.push(result.clone());
+                result
+            }
+        }
+    }
+
+    fn set_clipboard(&mut self, text: &str) -> Result<(), String> {
+        let Some(clipboard) = self.clipboard.as_deref_mut() else {
+            return Err(CLIPBOARD_UNAVAILABLE.to_string());
+        };
+        clipboard
+            .set_text(text)
+            .map_err(|e| format!("Could not set the clipboard: {}", e))
+    }
+
+    // Poll the screen until the condition of a wait_for_* action holds. A
+    // timeout fails the action so the planner learns the screen did not react.
+    fn wait_for(&mut self, action: &Action, task_state: &mut TaskState) -> ActionResult {
+        let started = Instant::now();
+        let interrupted = interruption(self.is_paused, self.should_continue);
+        let waited = match action {
+            Action::WaitForChange {
+                region,
+                threshold,
+                timeout_ms,
+            } => {
+                let region = region.map(|[x1, y1, x2, y2]| {
+                    let (x1, y1) = self.space.image_to_capture(x1, y1);
+                    let (x2, y2) = self.space.image_to_capture(x2, y2);
+                    [x1, y1, x2, y2]
+                });
+                wait::wait_for_change(
+                    self.screen,
+                    region,
+                    threshold.unwrap_or(action::DEFAULT_CHANGE_THRESHOLD),
+                    Duration::from_millis(*timeout_ms),
+                    &interrupted,
+                )
+                .map(|changed| changed.then(|| "the screen changed".to_string()))
+            }
+            Action::WaitForStable { frames, timeout_ms } => wait::wait_for_stable(
+                self.screen,
+                frames.unwrap_or(action::DEFAULT_STABLE_FRAMES),
+                Duration::from_millis(*timeout_ms),
+                &interrupted,
+            )
+            .map(|stable| stable.then(|| "the screen is stable".to_string())),
+            Action::WaitForWindow { title, timeout_ms } => {
+                let timeout = Duration::from_millis(*timeout_ms);
+                wait::wait_for_window(self.screen, title, timeout, &interrupted)
+                    .map(|found| found.map(|window| format!("\"{}\" is open", window)))
+            }
+            _ => Ok(Some("nothing to wait for".to_string())),
+        };
+
+        let message = match waited {
+            Ok(Some(outcome)) => {
+                println!("After {:?}, {}", started.elapsed(), outcome);
+                return ActionResult::new(action.name()).success();
+            }
+            Ok(None) if interrupted() => "Interrupted by a pause".to_string(),
+            Ok(None) => format!("Timed out after {}ms", started.elapsed().as_millis()),
+            Err(e) => format!("Could not watch the screen: {}", e),
+        };
+        println!("Error: {} failed: {}", action.name(), message);
+        let result = ActionResult::new(action.name()).with_error(&message);
+        task_state.action_results.push(result.clone());
+        result
+    }
+
+    // Second pass after a zoom: show the model the region at full resolution
+    // and let it correct the point the action is aimed at. Returns the new
+    // point in input coordinates, or None to keep the planned one.
+    async fn refine_target(&mut self, action: &Action, region: [i32; 4]) -> Option<(i32, i32)> {
+        let [x1, y1, x2, y2] = region;
+        let (target_x, target_y) = action.target()?;
+        if target_x < x1 || target_x > x2 || target_y < y1 || target_y > y2 {
+            println!(
+                "Zoom region does not contain the {} target, ignoring it",
+                action.name()
+            );
+            return None;
+        }
+
+        // Crop the region from the full-resolution capture and enlarge it
+        let (crop_x, crop_y) = self.space.image_to_capture(x1, y1);
+        let (crop_x2, crop_y2) = self.space.image_to_capture(x2, y2);
+        let capture_path = format!("{}/screenshot.png", self.capture_dir);
+        let capture = match image::open(&capture_path) {
+            Ok(capture) => capture,
+            Err(e) => {
+                println!("Error: Could not open {} to zoom: {}", capture_path, e);
+                return None;
+            }
+        };
+        let crop = capture.crop_imm(
+            crop_x as u32,
+            crop_y as u32,
+            (crop_x2 - crop_x + 1) as u32,
+            (crop_y2 - crop_y + 1) as u32,
+        );
+        let scale = (ZOOM_SIZE / crop.width().max(crop.height()).max(1)).max(1);
+        let zoomed = crop.resize_exact(
+            crop.width() * scale,
+            crop.height() * scale,
+            FilterType::Nearest,
+        );
+        save_artifact_image(&zoomed, &format!("{}/zoom.png", self.capture_dir));
+        let zoomed_base64 = match encode_png(&zoomed) {
+            Ok(zoomed_base64) => zoomed_base64,
+            Err(e) => {
+                println!("Error: Could not encode the zoomed region: {}", e);
+                return None;
+            }
+        };
+
+        // Where the action is aimed now, in the enlarged image
+        let (capture_x, capture_y) = self.space.image_to_capture(target_x, target_y);
+        let planned = (
+            (capture_x - crop_x) * scale as i32,
+            (capture_y - crop_y) * scale as i32,
+        );
+
+        let zoom_request = CreateChatCompletionRequestArgs::default()
+            .model(self.model_name)
+            .max_tokens(self.max_tokens)
+            .messages([ChatCompletionRequestUserMessageArgs::default()
+                .content(vec![
+                    text_part(format!(
+                        "This is the region ({}, {})-({}, {}) of the screenshot at full resolution, \
+                         enlarged to {}x{} pixels. The planned {} is aimed at ({}, {}) in this \
+                         image. Reply with ONLY a JSON object {{\"x\": <integer>, \"y\": <integer>}} \
+                         giving the exact point to aim at, in this image's pixels.",
+                        x1,
+                        y1,
+                        x2,
+                        y2,
+                        zoomed.width(),
+                        zoomed.height(),
+                        action.name(),
+                        planned.0,
+                        planned.1
+                    )),
+                    image_part(&zoomed_base64),
+                ])
+                .build()
+                .unwrap()
+                .into()])
+            .build()
+            .unwrap();
+        let response = match self
+            .llm
+            .complete(Call::new(Stage::Zoom, self.iteration), zoom_request)
+            .await
+        {
+            Ok(response) => response,
+            Err(e) => {
+                println!("Error: Zoom refinement failed: {}", e);
+                return None;
+            }
+        };
+
+        let point = serde_json::from_str::<serde_json::Value>(clean_json_response(&response))
+            .ok()
+            .and_then(|point| Some((point["x"].as_i64()?, point["y"].as_i64()?)))
+            .filter(|(x, y)| {
+                (0..zoomed.width() as i64).contains(x) && (0..zoomed.height() as i64).contains(y)
+            });
+        let Some((x, y)) = point else {
+            println!(
+                "Warning: Zoom refinement gave no point inside the region: {}",
+                response
+            );
+            return None;
+        };
+        let refined = self.space.capture_to_logical(
+            crop_x + x as i32 / scale as i32,
+            crop_y + y as i32 / scale as i32,
+        );
+        println!(
+            "Zoom moved the {} target from ({}, {}) to ({}, {}) in the zoomed region",
+            action.name(),
+            planned.0,
+            planned.1,
+            x,
+            y
+        );
+        Some(refined)
+    }
+
+    // Analyze a new screenshot to check that the window came to the front
+    async fn verify_window_focus(
+        &mut self,
+        action: &Action,
+        task_state: &mut TaskState,
+    ) -> ActionResult {
+        // Wait a bit for the window to focus
+        sleep(Duration::from_millis(500));
+
+        // Capture a new screenshot to verify the action
+        let verify_image_path = format!("{}/verify_screenshot.png", self.iteration_dir);
+        let verify_base64 = self
+            .screen
+            .capture()
+            .map_err(Error::from)
+            .and_then(|image| {
+                image.save(&verify_image_path)?;
+                encode_png(&DynamicImage::ImageRgba8(image))
+            });
+        let verify_base64 = match verify_base64 {
+            Ok(verify_base64) => verify_base64,
+            Err(e) => {
+                let result = ActionResult::new(action.name())
+                    .with_error(&format!("Verification capture failed: {}", e));
+                task_state.action_results.push(result.clone());
+                self.session.save_state(task_state);
+                return result;
+            }
+        };
+
+        // Analyze the new screenshot
+        let verify_analysis_request = CreateChatCompletionRequestArgs::default()
+            .model(self.model_name)
+            .max_tokens(self.max_tokens)
+            .messages([ChatCompletionRequestUserMessageArgs::default()
+                .content(vec![
+                    ChatCompletionRequestMessageContentPartTextArgs::default()
+                        .text("Analyze this screenshot and provide a STRICT JSON response with the same format as before.")
+                        .build()
+                        .unwrap()
+                        .into(),
+                    image_part(&verify_base64),
+                ])
+                .build()
+                .unwrap()
+                .into()])
+            .build()
+            .unwrap();
+
+        let verify_analysis = self
+            .llm
+            .complete(
+                Call::new(Stage::Verify, self.iteration),
+                verify_analysis_request,
+            )
+            .await
+            .map_err(Error::from)
+            .and_then(|response| Ok(parse_analysis(&response)?));
+        match verify_analysis {
+            // Verify the action
+            Ok(verify_analysis) => retry_action(
+                action,
+                &verify_analysis,
+                task_state,
+                self.input,
+                &self.space,
+                &mut || None,
+            ),
+            Err(e) => {
+                println!("Error: Verification failed: {}", e);
+                let result = ActionResult::new(action.name())
+                    .with_error(&format!("Verification failed: {}", e));
+                task_state.action_results.push(result.clone());
+                result
+            }
+        }
+    }
+
+    // Check an action's result, pausing the task once an action has been
+    // retried too often. Returns whether the batch may go on.
+    fn check_result(
+        &mut self,
+        action_result: &ActionResult,
+        task_state: &mut TaskState,
+        was_paused: &mut bool,
+    ) -> bool {
+        if action_result.success {
+            return true;
+        }
+        println!("Action failed: {:?}", action_result.error_message);
+
+        // If we've retried too many times, pause the task
+        if action_result.retry_count >= 3 {
+            println!(
+                "Too many retries for action: {}. Pausing task. Type 'resume' to continue.",
+                action_result.action_type
+            );
+            pause_task(
+                self.input,
+                task_state,
+                self.session,
+                &format!("too many retries for {}", action_result.action_type),
+            );
+            *self.is_paused.lock().unwrap() = true;
+            *was_paused = true;
+            return false;
+        }
+        true
     }
 
-    new_instruction.trim().to_string()
+    // Capture the screen after a turn of tool calls and describe it to the model
+    fn screenshot_message(&mut self, turn: usize) -> ChatCompletionRequestMessage {
+        let turn_dir = format!("{}/turn_{}", self.iteration_dir, turn);
+        let captured = fs::create_dir_all(&turn_dir)
+            .map_err(Error::from)
+            .and_then(|_| capture_screenshot(self.screen, &turn_dir, self.display_size, self.grid));
+
+        let mut content = Vec::new();
+        match captured {
+            Ok(screenshot) => {
+                self.capture_dir = turn_dir.clone();
+                self.space = screenshot.space;
+                self.monitors = screenshot.monitors;
+                content.push(text_part(format!(
+                    "The screen after these actions:\n{}",
+                    describe_screen(&self.space, &self.monitors, self.grid)
+                )));
+                content.push(image_part(&screenshot.base64));
+
+                // Marks from the analysis described the screen before these actions
+                let had_marks = !self.marks.is_empty();
+                self.marks = match self.mark_source {
+                    Some(MarkSource::Detect) => marks::detect(&screenshot.image.to_rgba8()),
+                    Some(MarkSource::Analysis) | None => Vec::new(),
+                };
+                if had_marks && self.marks.is_empty() {
+                    content.push(text_part(
+                        "The element marks no longer apply; use coordinates.".to_string(),
+                    ));
+                }
+                content.extend(marks_content(&screenshot.image, &self.marks, &turn_dir));
+            }
+            Err(e) => {
+                println!("Error: capture failed: {}", e);
+                content.push(text_part(format!(
+                    "The screen could not be captured after these actions: {}",
+                    e
+                )));
+            }
+        }
+        ChatCompletionRequestUserMessageArgs::default()
+            .content(content)
+            .build()
+            .unwrap()
+            .into()
+    }
+}
+
+// Actions the planner is not offered: click_element needs marks to click
+fn unavailable_actions(mark_source: Option<MarkSource>) -> &'static [&'static str] {
+    match mark_source {
+        Some(_) => &[],
+        None => &["click_element"],
+    }
+}
+
+// Function to plan with tool calls. Every call is executed as it arrives and
+// answered with its result, and every turn with a new screenshot, until the
+// model stops calling tools. Returns the actions that were executed.
+async fn plan_with_tools(
+    runner: &mut ActionRunner<'_>,
+    content: Vec<ChatCompletionRequestUserMessageContentPart>,
+    analysis: &ScreenAnalysis,
+    task_state: &mut TaskState,
+    was_paused: &mut bool,
+    max_turns: usize,
+) -> Vec<Action> {
+    let tools = planner::action_tools(unavailable_actions(runner.mark_source));
+    let mut messages: Vec<ChatCompletionRequestMessage> = vec![
+        ChatCompletionRequestUserMessageArgs::default()
+            .content(content)
+            .build()
+            .unwrap()
+            .into(),
+    ];
+    let mut executed = Vec::new();
+    // The analysis describes the screen of the first turn only; later turns
+    // go without element areas and the analysed window
+    let unanalysed = ScreenAnalysis::default();
+
+    for turn in 0..max_turns {
+        let analysis = if turn == 0 { analysis } else { &unanalysed };
+        let request = CreateChatCompletionRequestArgs::default()
+            .model(runner.model_name)
+            .max_tokens(runner.max_tokens)
+            .messages(messages.clone())
+            .tools(tools.clone())
+            .tool_choice(ChatCompletionToolChoiceOption::Auto)
+            .build()
+            .unwrap();
+        let call = Call::new(Stage::Planning, runner.iteration).with_turn(turn);
+        let reply = match runner.llm.reply(call, request).await {
+            Ok(reply) => reply,
+            Err(e) => {
+                let e = e.into();
+                record_failure(task_state, runner.session, "planning", &e);
+                end_of_script(&e, runner.should_continue);
+                return executed;
+            }
+        };
+        if let Some(content) = &reply.content {
+            println!("Planner: {}", content);
+        }
+        if reply.tool_calls.is_empty() {
+            return executed;
+        }
+
+        let mut assistant = ChatCompletionRequestAssistantMessageArgs::default();
+        assistant.tool_calls(reply.tool_calls.clone());
+        if let Some(content) = reply.content {
+            assistant.content(content);
+        }
+        messages.push(assistant.build().unwrap().into());
+
+        // Run the calls in order. Once one fails, the rest of the turn is
+        // skipped, since it assumed that one worked; every call is answered.
+        let total_calls = reply.tool_calls.len();
+        let mut skip_reason: Option<String> = None;
+        let mut halted = false;
+        for (index, tool_call) in reply.tool_calls.iter().enumerate() {
+            let name = &tool_call.function.name;
+            println!("Tool call: {} {}", name, tool_call.function.arguments);
+            if let Some(reason) = &skip_reason {
+                let skipped = ActionResult::new(name).with_error(&format!("skipped: {}", reason));
+                messages.push(planner::tool_result(tool_call, &skipped));
+                continue;
+            }
+
+            let action = match planner::parse_tool_call(tool_call) {
+                Ok(action) => action,
+                Err(e) => {
+                    task_state
+                        .feedback
+                        .push(format!("Invalid tool call: {}", e));
+                    record_failure(task_state, runner.session, "planning", &e.clone().into());
+                    let invalid = ActionResult::new(name).with_error(&e.to_string());
+                    messages.push(planner::tool_result(tool_call, &invalid));
+                    skip_reason = Some(format!("{} was invalid", name));
+                    continue;
+                }
+            };
+
+            let action_result =
+                if runner.may_continue(&action, total_calls - index, task_state, was_paused) {
+                    match runner.run_action(&action, analysis, task_state).await {
+                        Ok(action_result) => {
+                            // Denied, rejected and failed actions are not
+                            // recorded as executed
+                            if action_result.success {
+                                executed.push(action.clone());
+                            }
+                            if !runner.check_result(&action_result, task_state, was_paused) {
+                                halted = true;
+                            }
+                            // The model hears of the rejection and plans again
+                            // next turn
+                            if runner.rejected {
+                                runner.rejected = false;
+                                skip_reason = Some(format!("the operator rejected {}", name));
+                            }
+                            action_result
+                        }
+                        Err(e) => {
+                            record_failure(task_state, runner.session, action.name(), &e);
+                            skip_reason = Some(format!("{} failed", name));
+                            ActionResult::new(name).with_error(&e.to_string())
+                        }
+                    }
+                } else {
+                    halted = true;
+                    ActionResult::new(name).with_error("skipped: the automation was paused")
+                };
+            if halted {
+                skip_reason = Some("the automation was paused".to_string());
+            }
+            messages.push(planner::tool_result(tool_call, &action_result));
+        }
+
+        // An emergency stop during the turn is handled as a pause by the caller
+        if halted || task_state.status == "task_done" || *runner.is_paused.lock().unwrap() {
+            return executed;
+        }
+
+        // Show the model what its actions did
+        messages.push(runner.screenshot_message(turn + 1));
+    }
+
+    println!("Planner stopped after {} turns", max_turns);
+    executed
+}
+
+// Function to build a text part of a user message
+fn text_part(text: String) -> ChatCompletionRequestUserMessageContentPart {
+    ChatCompletionRequestMessageContentPartTextArgs::default()
+        .text(text)
+        .build()
+        .unwrap()
+        .into()
+}
+
+// Function to build an image part of a user message from a base64 PNG
+fn image_part(base64_png: &str) -> ChatCompletionRequestUserMessageContentPart {
+    ChatCompletionRequestMessageContentPartImageArgs::default()
+        .image_url(
+            ImageUrlArgs::default()
+                .url(format!("data:image/png;base64,{}", base64_png))
+                .detail(ImageDetail::High)
+                .build()
+                .unwrap(),
+        )
+        .build()
+        .unwrap()
+        .into()
+}
+
+// Function to record a failed stage in the session state instead of giving up
+fn record_failure(task_state: &mut TaskState, session: &Session, stage: &str, error: &Error) {
+    println!("Error: {} failed: {}", stage, error);
+    task_state
+        .action_results
+        .push(ActionResult::new(stage).with_error(&error.to_string()));
+    session.save_state(task_state);
+}
+
+// Function to end a replayed run once its script has no response left for a
+// stage the loop needs; retrying would only fail the same way
+fn end_of_script(error: &Error, should_continue: &Mutex<bool>) -> bool {
+    if !matches!(error, Error::Llm(LlmError::ScriptExhausted(_))) {
+        return false;
+    }
+    println!("The model script has no more responses, ending the run");
+    *should_continue.lock().unwrap() = false;
+    true
+}
+
+// Function to save an image artifact; a failed write is reported but not fatal
+fn save_artifact_image(image: &DynamicImage, path: &str) {
+    if let Err(e) = image.save(path) {
+        println!("Error: Could not save {}: {}", path, e);
+    }
+}
+
+// Function to list the open windows for the prompts, saving them with the
+// iteration. Empty without a window manager or when listing fails.
+fn list_windows(windows: Option<&dyn WindowManager>, space: &CoordinateSpace, dir: &str) -> String {
+    let Some(windows) = windows else {
+        return String::new();
+    };
+    match windows.list() {
+        Ok(list) => {
+            save_artifact(
+                &format!("{}/windows.json", dir),
+                &serde_json::to_string_pretty(&list).unwrap_or_default(),
+            );
+            format!(
+                "\nOpen windows, with their position and size in screenshot pixels; \
+                 window_focus and the other window actions find them by title:\n{}",
+                window::describe(&windows_in_image(list, space))
+            )
+        }
+        Err(e) => {
+            println!("Warning: Could not list the open windows: {}", e);
+            String::new()
+        }
+    }
+}
+
+// Function to size the recorded display of a dry run like the primary
+// monitor, or like a capture when the screen source knows no monitors
+fn dry_run_display(screen: &mut dyn ScreenSource) -> error::Result<(i32, i32)> {
+    let monitors = screen.monitors()?;
+    let primary = monitors
+        .iter()
+        .find(|monitor| monitor.primary)
+        .or(monitors.first());
+    if let Some(monitor) = primary {
+        return Ok((monitor.width as i32, monitor.height as i32));
+    }
+    let capture = screen.capture()?;
+    Ok((capture.width() as i32, capture.height() as i32))
+}
+
+// Function to show the planner the text clipboard_get last read, if any
+fn describe_clipboard(task_state: &TaskState) -> String {
+    let Some(text) = task_state.memory.get("clipboard") else {
+        return String::new();
+    };
+    let shown: String = text.chars().take(CLIPBOARD_PROMPT_CHARS).collect();
+    let omitted = text.chars().count() - shown.chars().count();
+    let mut described = format!("\n\nClipboard text read with clipboard_get:\n{}", shown);
+    if omitted > 0 {
+        described.push_str(&format!("\n... {} more characters", omitted));
+    }
+    described
+}
+
+// Function to move window geometry from input to screenshot pixels, the
+// space the model reads and answers in
+fn windows_in_image(mut windows: Vec<WindowInfo>, space: &CoordinateSpace) -> Vec<WindowInfo> {
+    for window in &mut windows {
+        (window.x, window.y) = space.logical_to_image(window.x, window.y);
+        (window.width, window.height) = space.logical_to_image_size(window.width, window.height);
+    }
+    windows
+}
+
+// Function to write an iteration artifact; a failed write is reported but not fatal
+fn save_artifact(path: &str, contents: &str) {
+    if let Err(e) = fs::write(path, contents) {
+        println!("Error: Could not save {}: {}", path, e);
+    }
+}
+
+// Function to encode an image as base64 PNG
+fn encode_png(img: &DynamicImage) -> error::Result<String> {
+    let mut buf = Vec::new();
+    let mut cursor = std::io::Cursor::new(&mut buf);
+    img.write_to(&mut cursor, ImageFormat::Png)?;
+    Ok(base64::engine::general_purpose::STANDARD.encode(&buf))
 }
 
-// Function to verify if an action was successful
-fn verify_action(
-    action: &serde_json::Value,
-    analysis_json: &serde_json::Value,
-    task_state: &mut TaskState,
-) -> ActionResult {
-    let mut result = ActionResult::new(action["action"].as_str().unwrap_or("unknown"));
+// A capture of the screen as the model sees it
+struct Screenshot {
+    space: CoordinateSpace,
+    monitors: Vec<MonitorInfo>,
+    // The downscaled copy sent to the model, and its base64 PNG
+    image: DynamicImage,
+    base64: String,
+}
 
-    // Get UI elements from analysis
-    let ui_elements = if let Some(elements) = analysis_json["ui_elements"].as_array() {
-        elements
-    } else {
-        result.error_message = Some("No UI elements found in analysis".to_string());
-        return result;
-    };
+// Function to capture the screen into the iteration directory. With a grid
+// spacing, the copy sent to the model has a labelled grid drawn on it.
+fn capture_screenshot(
+    screen: &mut dyn ScreenSource,
+    iteration_dir: &str,
+    display_size: (i32, i32),
+    grid: Option<u32>,
+) -> error::Result<Screenshot> {
+    let start = Instant::now();
 
-    // Get current state from analysis
-    let state = if let Some(state_obj) = analysis_json["state"].as_object() {
-        state_obj
-    } else {
-        result.error_message = Some("No state information found in analysis".to_string());
-        return result;
+    let monitors = screen.monitors()?;
+    let image = screen.capture()?;
+    image.save(format!("{}/screenshot.png", iteration_dir))?;
+
+    println!("capture time: {:?}", start.elapsed());
+
+    // ---
+
+    let start = Instant::now();
+
+    // The frame covers the captured monitors, or the whole display when the
+    // source does not know about monitors
+    let captured: Vec<&MonitorInfo> = monitors.iter().filter(|m| m.captured).collect();
+    let (origin_x, origin_y, logical_size) = match MonitorInfo::bounds(&captured) {
+        Some((x, y, width, height)) => (x, y, (width as i32, height as i32)),
+        None => (0, 0, display_size),
     };
 
-    // Verify based on action type
-    match action["action"].as_str() {
-        Some("window_focus") => {
-            if let (Some(title), Some(class)) = (action["title"].as_str(), action["class"].as_str())
-            {
-                if let Some(active_window) = state["active_window"].as_str() {
-                    if active_window.to_lowercase().contains(&title.to_lowercase()) {
-                        result = result.success();
-                    } else {
-                        result.error_message = Some(format!(
-                            "Window focus failed. Expected: {}, Got: {}",
-                            title, active_window
-                        ));
-                    }
-                } else {
-                    result.error_message = Some("Could not determine active window".to_string());
-                }
-            } else {
-                result.error_message =
-                    Some("Missing title or class in window_focus action".to_string());
-            }
-        }
-        Some("mouse_move") | Some("mouse_click") => {
-            // For mouse actions, we can't directly verify if they worked
-            // Instead, we'll check if the UI state changed after the action
-            // This is a simplified approach - in a real implementation, you'd want to
-            // compare the UI state before and after the action
-            result = result.success();
-        }
-        Some("key_press") | Some("key_combination") | Some("text_input") => {
-            // For keyboard actions, we can't directly verify if they worked
-            // Instead, we'll check if the UI state changed after the action
-            result = result.success();
-        }
-        Some("wait") => {
-            // Wait actions always succeed
-            result = result.success();
-        }
-        Some("task_done") => {
-            // Task done actions always succeed
-            result = result.success();
-        }
-        _ => {
-            result.error_message = Some(format!(
-                "Unknown action type: {}",
-                action["action"].as_str().unwrap_or("unknown")
-            ));
+    let img = DynamicImage::ImageRgba8(image);
+    let space = CoordinateSpace::new(img.dimensions(), RESIZE_FACTOR, logical_size)
+        .with_origin(origin_x, origin_y);
+    let img = img.resize(
+        space.image_width,
+        space.image_height,
+        FilterType::CatmullRom,
+    );
+    img.save(format!("{}/screenshot_resized.png", iteration_dir))?;
+
+    // Record the mapping so the iteration's coordinates can be interpreted later
+    fs::write(
+        format!("{}/coordinates.json", iteration_dir),
+        serde_json::to_string_pretty(&space)?,
+    )?;
+    fs::write(
+        format!("{}/monitors.json", iteration_dir),
+        serde_json::to_string_pretty(&monitors)?,
+    )?;
+
+    let res_base64 = match grid {
+        Some(spacing) => {
+            let mut gridded = img.to_rgba8();
+            draw::grid(&mut gridded, spacing);
+            let gridded = DynamicImage::ImageRgba8(gridded);
+            gridded.save(format!("{}/screenshot_grid.png", iteration_dir))?;
+            encode_png(&gridded)?
         }
+        None => encode_png(&img)?,
+    };
+
+    println!("encode time: {:?}", start.elapsed());
+
+    Ok(Screenshot {
+        space,
+        monitors,
+        image: img,
+        base64: res_base64,
+    })
+}
+
+// Function to draw the marks onto the screenshot and describe them to the
+// model; nothing is added when there are no marks
+fn marks_content(
+    image: &DynamicImage,
+    marks: &[Mark],
+    dir: &str,
+) -> Vec<ChatCompletionRequestUserMessageContentPart> {
+    if marks.is_empty() {
+        return Vec::new();
+    }
+    save_artifact(
+        &format!("{}/marks.json", dir),
+        &serde_json::to_string_pretty(marks).unwrap_or_default(),
+    );
+
+    let mut content = vec![text_part(format!(
+        "Marked elements, numbered on the screenshot below:\n{}\nTo click a marked element, \
+         prefer click_element with its number over click_at.",
+        marks::describe(marks)
+    ))];
+    let mut marked = image.to_rgba8();
+    marks::draw(&mut marked, marks);
+    let marked = DynamicImage::ImageRgba8(marked);
+    let encoded = marked
+        .save(format!("{}/screenshot_marked.png", dir))
+        .map_err(Error::from)
+        .and_then(|_| encode_png(&marked));
+    match encoded {
+        Ok(marked_base64) => content.push(image_part(&marked_base64)),
+        Err(e) => println!("Error: Could not draw the marks: {}", e),
     }
+    content
+}
 
-    // Add the result to the task state
-    task_state.action_results.push(result.clone());
+// Function to describe the screenshot's coordinates, the grid drawn on it and
+// the monitors for the prompts
+fn describe_screen(space: &CoordinateSpace, monitors: &[MonitorInfo], grid: Option<u32>) -> String {
+    let mut description = space.describe();
+    if let Some(spacing) = grid {
+        description.push_str(&format!(
+            "\nA grid is drawn every {} pixels, each line labelled with its x (top edge) or y \
+             (left edge) coordinate; use it to read off coordinates.",
+            spacing
+        ));
+    }
+    if monitors.is_empty() {
+        return description;
+    }
+    format!(
+        "{}\nMonitors:\n{}\nTo target a monitor, add \"monitor\": <index> to a mouse or scroll \
+         action; its coordinates are then relative to that monitor's top-left corner, at the \
+         screenshot's scale.",
+        description,
+        space.describe_monitors(monitors)
+    )
+}
 
-    result
+// Function to strip markdown code fences from a model response
+fn clean_json_response(response: &str) -> &str {
+    response
+        .trim()
+        .trim_start_matches("```json")
+        .trim_start_matches("```")
+        .trim_end_matches("```")
+        .trim()
 }
 
-// Function to retry a failed action with adjusted parameters
-fn retry_action(
-    action: &serde_json::Value,
-    analysis_json: &serde_json::Value,
-    task_state: &mut TaskState,
-    enigo: &mut Enigo,
-) -> ActionResult {
-    let mut result = verify_action(action, analysis_json, task_state);
+// Factor by which screenshots are downscaled before they are sent to the model
+const RESIZE_FACTOR: u32 = 3;
 
-    // If the action failed and we haven't retried too many times, try again with adjustments
-    if !result.success && result.retry_count < 3 {
-        result = result.increment_retry();
+// Checks, 100ms apart, that a window action took effect
+const WINDOW_POLLS: u32 = 10;
 
-        // Get the last result for this action type
-        let last_result = task_state
-            .action_results
-            .iter()
-            .filter(|r| r.action_type == result.action_type)
-            .last();
-
-        // Adjust the action based on the previous failure
-        match action["action"].as_str() {
-            Some("window_focus") => {
-                // Try a different method for window focus
-                if let Some(method) = action["method"].as_str() {
-                    let new_method = if method == "alt_tab" {
-                        "super_tab"
-                    } else {
-                        "alt_tab"
-                    };
-                    println!("Retrying window focus with method: {}", new_method);
-
-                    if let (Some(title), Some(class)) =
-                        (action["title"].as_str(), action["class"].as_str())
-                    {
-                        match new_method {
-                            "alt_tab" => {
-                                enigo.key(Key::Alt, Direction::Press).unwrap();
-                                sleep(Duration::from_millis(100));
-                                enigo.key(Key::Tab, Direction::Click).unwrap();
-                                sleep(Duration::from_millis(100));
-                                enigo.key(Key::Alt, Direction::Release).unwrap();
-                            }
-                            "super_tab" => {
-                                enigo.key(Key::Meta, Direction::Press).unwrap();
-                                sleep(Duration::from_millis(100));
-                                enigo.key(Key::Tab, Direction::Click).unwrap();
-                                sleep(Duration::from_millis(100));
-                                enigo.key(Key::Meta, Direction::Release).unwrap();
-                            }
-                            _ => {}
-                        }
-                    }
-                }
-            }
-            Some("mouse_move") | Some("mouse_click") => {
-                // Adjust mouse coordinates based on UI elements
-                if let (Some(x), Some(y)) = (action["x"].as_i64(), action["y"].as_i64()) {
-                    // Get UI elements from analysis
-                    if let Some(elements) = analysis_json["ui_elements"].as_array() {
-                        // Find the closest UI element to the target coordinates
-                        let mut closest_element = None;
-                        let mut min_distance = f64::MAX;
-
-                        for element in elements {
-                            if let Some(coords) = element["coords"].as_array() {
-                                if coords.len() >= 4 {
-                                    if let (Some(x1), Some(y1), Some(x2), Some(y2)) = (
-                                        coords[0].as_i64(),
-                                        coords[1].as_i64(),
-                                        coords[2].as_i64(),
-                                        coords[3].as_i64(),
-                                    ) {
-                                        // Calculate center of the element
-                                        let center_x = (x1 + x2) / 2;
-                                        let center_y = (y1 + y2) / 2;
-
-                                        // Calculate distance to target
-                                        let distance =
-                                            ((center_x - x).pow(2) + (center_y - y).pow(2)) as f64;
-
-                                        if distance < min_distance {
-                                            min_distance = distance;
-                                            closest_element = Some((center_x, center_y));
-                                        }
-                                    }
-                                }
-                            }
-                        }
+// Time the screen gets to react to an action before it is captured again
+const SETTLE_MS: u64 = 300;
 
-                        // If we found a close element, use its coordinates
-                        if let Some((new_x, new_y)) = closest_element {
-                            println!("Adjusting mouse coordinates to ({}, {})", new_x, new_y);
-
-                            // Move to the new coordinates
-                            enigo
-                                .move_mouse(new_x as i32, new_y as i32, Coordinate::Abs)
-                                .unwrap();
-
-                            // If this is a click action, click at the new coordinates
-                            if action["action"].as_str() == Some("mouse_click") {
-                                if let Some(button) = action["button"].as_str() {
-                                    match button {
-                                        "left" => {
-                                            enigo.button(Button::Left, Direction::Click).unwrap()
-                                        }
-                                        "right" => {
-                                            enigo.button(Button::Right, Direction::Click).unwrap()
-                                        }
-                                        "middle" => {
-                                            enigo.button(Button::Middle, Direction::Click).unwrap()
-                                        }
-                                        _ => {}
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
-            }
-            _ => {
-                // For other actions, just wait a bit longer and try again
-                sleep(Duration::from_millis(500));
-            }
-        }
+// Most characters of the clipboard text shown in the planning prompts
+const CLIPBOARD_PROMPT_CHARS: usize = 2000;
 
-        // Verify the action again after retry
-        result = verify_action(action, analysis_json, task_state);
-    }
+const CLIPBOARD_UNAVAILABLE: &str = "The clipboard is unavailable on this display";
 
-    result
-}
+// Longest side a zoomed region is enlarged to, in whole multiples
+const ZOOM_SIZE: u32 = 768;
+
+// Consecutive failed captures after which the task is paused
+const MAX_CAPTURE_FAILURES: u32 = 5;
+
+// Keys that stop the automation unless STOP_HOTKEY names others, or "none"
+const DEFAULT_STOP_HOTKEY: &str = "control+shift+escape";
+
+const USAGE: &str = "Usage: automation [--resume <session-id>] [--dry-run]";
+
+// Colour of the action targets drawn on the screenshots of a dry run
+const DRY_RUN_COLOR: Rgba<u8> = Rgba([0, 160, 255, 255]);
+
+// Colour of the targets marked on the crops shown for approval, and the
+// capture pixels shown around them
+const APPROVAL_COLOR: Rgba<u8> = Rgba([255, 60, 0, 255]);
+const APPROVAL_MARGIN: i32 = 200;
 
 #[tokio::main]
-async fn main() {
+async fn main() -> error::Result<()> {
     dotenvy::dotenv().ok();
 
+    // --resume <session-id> picks up a session after a crash or restart;
+    // --dry-run plans and records actions without sending any input
+    let mut resume_id = None;
+    let mut dry_run = false;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--resume" => match args.next() {
+                Some(id) => resume_id = Some(id),
+                None => {
+                    eprintln!("{}", USAGE);
+                    std::process::exit(2);
+                }
+            },
+            "--dry-run" => dry_run = true,
+            other => {
+                eprintln!("Unknown argument: {}", other);
+                eprintln!("{}", USAGE);
+                std::process::exit(2);
+            }
+        }
+    }
+
     let api_base =
         std::env::var("API_BASE").unwrap_or_else(|_| "https://openrouter.ai/api/v1".to_string());
     let model_name =
@@ -665,24 +2010,215 @@ async fn main() {
         .parse::<u32>()
         .unwrap_or(512);
 
-    let api_key = std::env::var("API_KEY").unwrap();
+    // RESPONSE_FORMAT picks the structured output mode for the analysis stage
+    let response_mode = std::env::var("RESPONSE_FORMAT")
+        .ok()
+        .and_then(|name| ResponseMode::from_name(&name))
+        .unwrap_or(ResponseMode::JsonSchema);
+    let mut analysis_settings = AnalysisSettings {
+        model_name: model_name.clone(),
+        max_tokens,
+        response_mode,
+        max_repairs: std::env::var("ANALYSIS_REPAIRS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(2),
+    };
 
-    let client = Client::with_config(
-        OpenAIConfig::new()
-            .with_api_base(api_base)
-            .with_api_key(api_key),
-    );
+    // PLANNER picks how actions are planned: a JSON array in the reply
+    // (prompt) or tool calls executed as they arrive (tools)
+    let planner_mode = std::env::var("PLANNER")
+        .ok()
+        .and_then(|name| PlannerMode::from_name(&name))
+        .unwrap_or(PlannerMode::Prompt);
+    let planner_turns = std::env::var("PLANNER_TURNS")
+        .ok()
+        .and_then(|value| value.parse::<usize>().ok())
+        .unwrap_or(8);
+
+    // MARKS numbers elements on the screenshot for click_element: taken from
+    // the analysis (analysis) or found by an edge detector (detect)
+    let mark_source = std::env::var("MARKS")
+        .ok()
+        .and_then(|name| MarkSource::from_name(&name));
+
+    // GRID draws a labelled grid with this spacing, in screenshot pixels,
+    // on the screenshots sent to the model
+    let grid = std::env::var("GRID")
+        .ok()
+        .and_then(|value| value.parse::<u32>().ok())
+        .filter(|spacing| *spacing > 0);
+
+    // VERIFY=none trusts mouse and keyboard actions instead of checking them
+    // against a diff of the screen before and after each one
+    let verify_pixels = std::env::var("VERIFY").as_deref() != Ok("none");
+
+    // POLICY names a JSON file of rules every action is checked against
+    // before it runs, denying it or holding it for the operator's approval;
+    // without one every action is allowed
+    let policy = match std::env::var("POLICY") {
+        Ok(path) if !path.is_empty() => {
+            println!("Loading the safety policy from {}", path);
+            Policy::load(Path::new(&path))?
+        }
+        _ => Policy::default(),
+    };
+
+    // LAUNCH_ALLOW lists the applications launch_app may start, by desktop
+    // ID, name or program, separated by commas; "*" allows any
+    let launch_allowlist = Allowlist::parse(&std::env::var("LAUNCH_ALLOW").unwrap_or_default());
+
+    // LLM_SCRIPT replays canned responses instead of calling the API
+    let client: Box<dyn LlmClient> = match std::env::var("LLM_SCRIPT") {
+        Ok(script_path) => Box::new(ScriptedClient::from_file(&script_path)?),
+        Err(_) => {
+            let api_key = std::env::var("API_KEY").map_err(|_| {
+                Error::Config("API_KEY must be set unless LLM_SCRIPT is".to_string())
+            })?;
+            Box::new(OpenAiClient::new(&api_base, &api_key))
+        }
+    };
+
+    // Transient API errors are retried with backoff before a stage gives up
+    let mut retry_policy = RetryPolicy::default();
+    if let Some(attempts) = std::env::var("LLM_RETRIES")
+        .ok()
+        .and_then(|value| value.parse::<u32>().ok())
+    {
+        retry_policy.max_attempts = attempts.max(1);
+    }
+    let llm: Box<dyn LlmClient> = Box::new(RetryingClient::new(client, retry_policy));
+
+    let screen_spec = std::env::var("SCREEN_SOURCE").unwrap_or_else(|_| "xcap".to_string());
+    let mut screen = screen::from_spec(&screen_spec)?;
+    // An Xvfb screen source brings its own display, which everything that
+    // talks to X has to use instead of DISPLAY
+    let display = screen.display();
+
+    // A dry run records the input instead, on a display the size of the
+    // primary monitor
+    let mut input: Box<dyn InputBackend> = if dry_run {
+        let (width, height) = dry_run_display(screen.as_mut())?;
+        println!(
+            "Dry run: actions are planned and recorded, but nothing is sent to the mouse, \
+             keyboard, windows or clipboard"
+        );
+        Box::new(RecordingBackend::new(width, height))
+    } else {
+        Box::new(EnigoBackend::new(display.as_deref())?)
+    };
 
-    let mut enigo = Enigo::new(&Settings::default()).unwrap();
+    // Without an EWMH window manager, window_focus cycles with the keyboard
+    let windows: Option<Box<dyn WindowManager>> =
+        match X11WindowManager::connect(display.as_deref()) {
+            Ok(manager) => Some(Box::new(manager)),
+            Err(e) => {
+                println!(
+                    "Window management unavailable, falling back to alt_tab: {}",
+                    e
+                );
+                None
+            }
+        };
+    // The clipboard can only be reached on DISPLAY
+    let mut clipboard: Option<Box<dyn Clipboard>> = match &display {
+        Some(display) => {
+            println!(
+                "Clipboard unavailable on display {}, clipboard actions will fail",
+                display
+            );
+            None
+        }
+        None => match SystemClipboard::new() {
+            Ok(clipboard) => Some(Box::new(clipboard)),
+            Err(e) => {
+                println!("Clipboard unavailable, clipboard actions will fail: {}", e);
+                None
+            }
+        },
+    };
     let should_continue = Arc::new(Mutex::new(true));
     let should_continue_clone = should_continue.clone();
     let current_instruction = Arc::new(Mutex::new(String::from("")));
     let current_instruction_clone = current_instruction.clone();
     let is_idle = Arc::new(Mutex::new(true));
     let is_idle_clone = is_idle.clone();
+    let is_paused = Arc::new(Mutex::new(false));
+    let is_paused_clone = is_paused.clone();
+    let approval = Arc::new(Mutex::new(Approval::None));
+    let approval_clone = approval.clone();
+
+    // The hotkey and a flick of the mouse into a screen corner take the
+    // input back from the agent and pause the task. A dry run sends nothing
+    // it would need to be stopped from.
+    let emergency = EmergencyStop::new(is_paused.clone());
+    if !dry_run {
+        let hotkey_spec =
+            std::env::var("STOP_HOTKEY").unwrap_or_else(|_| DEFAULT_STOP_HOTKEY.to_string());
+        if hotkey_spec != "none" {
+            match Hotkey::parse(&hotkey_spec).and_then(|hotkey| {
+                emergency::watch_hotkey(&hotkey, emergency.clone(), display.as_deref())
+            }) {
+                Ok(()) => println!("Press {} to stop the automation at once", hotkey_spec),
+                Err(e) => println!(
+                    "Warning: Emergency stop hotkey {} unavailable: {}",
+                    hotkey_spec, e
+                ),
+            }
+        }
+        if std::env::var("FAILSAFE").as_deref() != Ok("none") {
+            match emergency::watch_corners(emergency.clone(), display.as_deref()) {
+                Ok(()) => {
+                    println!("Move the mouse into a screen corner to stop the automation at once")
+                }
+                Err(e) => println!("Warning: Corner failsafe unavailable: {}", e),
+            }
+        }
+        input = Box::new(FailsafeBackend::new(input, emergency.clone()));
+    }
+
+    // The session owns the task state, so attempts, memory and feedback
+    // carry across iterations and survive a restart
+    let session = match &resume_id {
+        Some(id) => Session::open(id)?,
+        None => Session::create()?,
+    };
+    let mut task_state = if resume_id.is_some() {
+        session.load_state()
+    } else {
+        TaskState::new()
+    };
+    if resume_id.is_some() {
+        let instruction = session
+            .info()
+            .map(|info| info.instruction)
+            .unwrap_or_default();
+        println!(
+            "Resuming session {} ({}, {} attempts)",
+            session.id(),
+            task_state.status,
+            task_state.attempts
+        );
+        if !instruction.is_empty() && task_state.status != "task_done" {
+            println!("Continuing instruction: {}", instruction);
+            *current_instruction.lock().unwrap() = instruction;
+            *is_idle.lock().unwrap() = false;
+        }
+        // A session saved while paused stays paused until the operator resumes it
+        if task_state.status == "paused" {
+            println!("Session was paused. Type 'resume' to continue.");
+            *is_paused.lock().unwrap() = true;
+        }
+    } else {
+        println!(
+            "Session {} started (restart with --resume {})",
+            session.id(),
+            session.id()
+        );
+    }
 
     // Get screen dimensions
-    let (screen_width, screen_height) = enigo.main_display().unwrap();
+    let (screen_width, screen_height) = input.main_display()?;
     println!("Screen dimensions: {}x{}", screen_width, screen_height);
 
     // Spawn a thread to handle user input
@@ -695,6 +2231,7 @@ async fn main() {
         println!("  stop - Stop the automation");
         println!("  pause - Pause the automation");
         println!("  resume - Resume the automation");
+        println!("  y / n [reason] - Approve or reject an action waiting for approval");
         println!("  help - Show this help message");
         println!("  Any other input will be treated as an instruction for the AI");
         println!(
@@ -706,26 +2243,62 @@ async fn main() {
             print!("> ");
             io::stdout().flush().unwrap();
             input.clear();
-            if reader.read_line(&mut input).is_ok() {
+            let read = reader.read_line(&mut input);
+            // Stdin was closed, e.g. after a piped instruction; the commands
+            // are gone but the run goes on
+            if matches!(read, Ok(0)) {
+                break;
+            }
+            if read.is_ok() {
                 let input = input.trim();
+
+                // While an action waits for approval, y and n answer it and
+                // only the commands are taken besides them
+                let pending = *approval_clone.lock().unwrap() == Approval::Pending;
+                let command = matches!(input, "stop" | "pause" | "resume" | "help");
+                if pending && !command {
+                    match parse_approval(input) {
+                        Some(answer) => *approval_clone.lock().unwrap() = answer,
+                        None => println!("Answer y to approve, or n followed by a reason"),
+                    }
+                    continue;
+                }
+                if !pending && matches!(input, "y" | "yes" | "n" | "no" | "approve" | "reject") {
+                    println!("No action is waiting for approval");
+                    continue;
+                }
+
                 match input {
                     "stop" => {
                         *should_continue_clone.lock().unwrap() = false;
                         println!("Stopping automation...");
                     }
                     "pause" => {
-                        println!("Pausing automation...");
-                        // TODO: Implement pause functionality
+                        let mut paused = is_paused_clone.lock().unwrap();
+                        if *paused {
+                            println!("Automation is already paused");
+                        } else {
+                            *paused = true;
+                            println!("Pausing automation...");
+                        }
                     }
                     "resume" => {
-                        println!("Resuming automation...");
-                        // TODO: Implement resume functionality
+                        let mut paused = is_paused_clone.lock().unwrap();
+                        if *paused {
+                            *paused = false;
+                            println!("Resuming automation...");
+                        } else {
+                            println!("Automation is not paused");
+                        }
                     }
                     "help" => {
                         println!("Available commands:");
                         println!("  stop - Stop the automation");
                         println!("  pause - Pause the automation");
                         println!("  resume - Resume the automation");
+                        println!(
+                            "  y / n [reason] - Approve or reject an action waiting for approval"
+                        );
                         println!("  help - Show this help message");
                         println!("  Any other input will be treated as an instruction for the AI");
                         println!(
@@ -742,68 +2315,118 @@ async fn main() {
         }
     });
 
+    // A session restored in the paused state must not be paused a second time
+    let mut was_paused = *is_paused.lock().unwrap();
+
+    let mut next_iteration = session.iteration_count();
+    let mut capture_failures = 0;
     while *should_continue.lock().unwrap() {
+        // Hold while paused. A pause noticed here happened between batches,
+        // so there is nothing to abort, only inputs to release and state to save.
+        if *is_paused.lock().unwrap() {
+            // An emergency stop while already paused still releases the inputs
+            let emergency_reason = emergency.take();
+            if !was_paused || emergency_reason.is_some() {
+                pause_task(
+                    input.as_mut(),
+                    &mut task_state,
+                    &session,
+                    emergency_reason
+                        .as_deref()
+                        .unwrap_or("operator requested pause"),
+                );
+                was_paused = true;
+            }
+            sleep(Duration::from_millis(100));
+            continue;
+        }
+
+        if was_paused {
+            was_paused = false;
+            if task_state.status == "paused" {
+                task_state.resume();
+                println!(
+                    "Resuming task after {} attempts with {} feedback entries",
+                    task_state.attempts,
+                    task_state.feedback.len()
+                );
+            }
+        }
+
         // Check if we're in idle state
         if *is_idle.lock().unwrap() {
             sleep(Duration::from_millis(100));
             continue;
         }
 
-        let start = Instant::now();
-        let monitors = Monitor::all().unwrap();
+        let iteration = next_iteration;
+        next_iteration += 1;
 
-        // Create timestamp for this iteration
+        // Create the directory for this iteration inside the session
         let timestamp = Local::now().format("%Y%m%d_%H%M%S").to_string();
-        let iteration_dir = format!("target/iterations/{}", timestamp);
-        fs::create_dir_all(&iteration_dir).unwrap();
-
-        dir::create_all("target/monitors", true).unwrap();
 
-        let monitor = monitors.first().unwrap();
-        let image = monitor.capture_image().unwrap();
-
-        let image_file_name = format!("{}/screenshot.png", iteration_dir);
-        image.save(&image_file_name).unwrap();
-
-        println!("capture time: {:?}", start.elapsed());
-
-        // ---
-
-        let start = Instant::now();
-
-        let img = ImageReader::open(&image_file_name).unwrap();
-
-        let img = img.decode().unwrap();
-
-        let (w, h) = img.dimensions();
-        let img = img.resize(w / 3, h / 3, FilterType::CatmullRom);
-
-        let resized_image_file_name = format!("{}/screenshot_resized.png", iteration_dir);
-        img.save(&resized_image_file_name).unwrap();
-
-        // Create a buffer to store the image data
-        let mut buf = Vec::new();
-        let mut cursor = std::io::Cursor::new(&mut buf);
-        img.write_to(&mut cursor, ImageFormat::Png).unwrap();
-
-        // Encode the image data to base64
-        let res_base64 = base64::engine::general_purpose::STANDARD.encode(&buf);
+        // A failed capture skips the iteration; the screen may be back next time
+        let captured = session
+            .new_iteration_dir(iteration)
+            .map_err(Error::from)
+            .and_then(|iteration_dir| {
+                let screenshot = capture_screenshot(
+                    screen.as_mut(),
+                    &iteration_dir,
+                    (screen_width, screen_height),
+                    grid,
+                )?;
+                Ok((iteration_dir, screenshot))
+            });
+        let (iteration_dir, screenshot) = match captured {
+            Ok(captured) => {
+                capture_failures = 0;
+                captured
+            }
+            Err(e) => {
+                record_failure(&mut task_state, &session, "capture", &e);
+                capture_failures += 1;
+                if capture_failures >= MAX_CAPTURE_FAILURES {
+                    println!(
+                        "Screen capture failed {} times in a row. Type 'resume' to try again.",
+                        capture_failures
+                    );
+                    pause_task(
+                        input.as_mut(),
+                        &mut task_state,
+                        &session,
+                        "screen capture keeps failing",
+                    );
+                    *is_paused.lock().unwrap() = true;
+                    was_paused = true;
+                    capture_failures = 0;
+                }
+                sleep(Duration::from_secs(1));
+                continue;
+            }
+        };
 
-        println!("encode time: {:?}", start.elapsed());
+        let Screenshot {
+            space,
+            monitors,
+            image: screenshot_image,
+            base64: res_base64,
+        } = screenshot;
 
         // ---
 
         let start = Instant::now();
 
         let instruction = current_instruction.lock().unwrap().clone();
+        if let Err(e) = session.set_instruction(&instruction) {
+            println!("Error: Could not save session instruction: {}", e);
+        }
 
         // Get the last 3 iterations with screenshots for context
-        let iterations_history = get_last_n_iterations_with_screenshots(3);
+        let iterations_history =
+            get_last_n_iterations_with_screenshots(&session.iterations_dir(), 3);
         let history_text = format_iterations_history(&iterations_history);
 
-        // Load or create task state
-        let mut task_state = load_task_state(&iteration_dir);
-
         // If we're coming from a task_done state and have a new instruction, reset the task state
         if task_state.status == "task_done" && !instruction.is_empty() {
             println!("Starting new task with instruction: {}", instruction);
@@ -815,10 +2438,17 @@ async fn main() {
 
         // Check if we should pause
         if task_state.should_pause() {
-            println!("Task paused due to too many attempts or detected loop");
-            task_state.status = "paused".to_string();
-            save_task_state(&iteration_dir, &task_state);
-            sleep(Duration::from_secs(5));
+            println!(
+                "Task paused due to too many attempts or detected loop. Type 'resume' to continue."
+            );
+            pause_task(
+                input.as_mut(),
+                &mut task_state,
+                &session,
+                "too many attempts or detected loop",
+            );
+            *is_paused.lock().unwrap() = true;
+            was_paused = true;
             continue;
         }
 
@@ -829,7 +2459,7 @@ async fn main() {
                 task_state.attempts
             );
             task_state.status = "completed".to_string();
-            save_task_state(&iteration_dir, &task_state);
+            session.save_state(&task_state);
             break;
         }
 
@@ -837,12 +2467,12 @@ async fn main() {
         if task_state.status == "task_done" {
             println!("Task is in 'task_done' state. Waiting for new instructions...");
             *is_idle.lock().unwrap() = true;
-            save_task_state(&iteration_dir, &task_state);
+            session.save_state(&task_state);
             continue;
         }
 
         // Save updated task state
-        save_task_state(&iteration_dir, &task_state);
+        session.save_state(&task_state);
 
         // Add task state to the prompt
         let state_context = format!(
@@ -871,9 +2501,7 @@ HISTORY OF PREVIOUS ATTEMPTS:
 {history}
 
 CURRENT SCREEN INFORMATION:
-- Screen dimensions: {width}x{height} pixels
-- Coordinate system: (0,0) is at the top-left corner
-- High DPI display: Consider scaling factors when calculating coordinates
+{coordinates}{open_windows}
 
 Analyze the CURRENT screenshot and provide a STRICT JSON response. Your response must be a valid JSON object with EXACTLY these fields:
 
@@ -905,7 +2533,7 @@ Analyze the CURRENT screenshot and provide a STRICT JSON response. Your response
 
 IMPORTANT:
 1. Response must be ONLY the JSON object, no additional text
-2. All coordinates must be within screen bounds
+2. All coordinates must be in screenshot pixels, within the screenshot bounds
 3. All fields are required
 4. Use null for empty values
 5. Do not include any explanations or comments in the JSON
@@ -915,8 +2543,8 @@ IMPORTANT:
                     history_text,
                     state_context,
                     history = history_text,
-                    width = screen_width,
-                    height = screen_height))
+                    coordinates = describe_screen(&space, &monitors, grid),
+                    open_windows = list_windows(windows.as_deref(), &space, &iteration_dir)))
                 .build()
                 .unwrap()
                 .into()
@@ -957,452 +2585,257 @@ IMPORTANT:
         }
 
         // Stage 1: Analysis
-        let analysis_request = CreateChatCompletionRequestArgs::default()
-            .model(&model_name)
-            .max_tokens(max_tokens)
-            .messages([ChatCompletionRequestUserMessageArgs::default()
-                .content(new_content_parts)
-                .build()
-                .unwrap()
-                .into()])
-            .build()
-            .unwrap();
-
-        let analysis_response = client.chat().create(analysis_request).await.unwrap();
-        let mut analysis_json = String::new();
-        for choice in analysis_response.choices {
-            analysis_json = choice.message.content.unwrap_or_default();
-            println!("Analysis Response: {}", analysis_json);
-        }
-
-        // Clean up and validate the analysis JSON
-        let clean_analysis = analysis_json
-            .trim()
-            .trim_start_matches("```json")
-            .trim_start_matches("```")
-            .trim_end_matches("```")
-            .trim();
+        let analysis = match request_analysis(
+            llm.as_ref(),
+            &mut analysis_settings,
+            iteration,
+            new_content_parts,
+            &space,
+        )
+        .await
+        {
+            Ok(analysis) => analysis,
+            Err(e) => {
+                record_failure(&mut task_state, &session, "analysis", &e);
+                end_of_script(&e, &should_continue);
+                continue;
+            }
+        };
 
-        // Save analysis JSON
+        // Save the validated analysis
+        let clean_analysis = serde_json::to_string_pretty(&analysis).unwrap_or_default();
         let analysis_file_name = format!("{}/analysis.json", iteration_dir);
-        fs::write(&analysis_file_name, clean_analysis).unwrap();
-
-        // Validate analysis JSON structure
-        if let Err(e) = serde_json::from_str::<serde_json::Value>(clean_analysis) {
-            println!("Error: Invalid analysis JSON format: {}", e);
-            continue;
-        }
+        save_artifact(&analysis_file_name, &clean_analysis);
+
+        // Number elements on the screenshot so the planner can click them by number
+        let marks = match mark_source {
+            Some(MarkSource::Analysis) => marks::from_analysis(&analysis),
+            Some(MarkSource::Detect) => marks::detect(&screenshot_image.to_rgba8()),
+            None => Vec::new(),
+        };
+        let marks_content = marks_content(&screenshot_image, &marks, &iteration_dir);
+
+        // Everything the actions of this iteration are executed with
+        let mut runner = ActionRunner {
+            llm: llm.as_ref(),
+            screen: screen.as_mut(),
+            input: input.as_mut(),
+            session: &session,
+            should_continue: &should_continue,
+            is_paused: &is_paused,
+            emergency: &emergency,
+            approval: &approval,
+            is_idle: &is_idle,
+            model_name: &model_name,
+            max_tokens,
+            iteration,
+            iteration_dir: &iteration_dir,
+            capture_dir: iteration_dir.clone(),
+            display_size: (screen_width, screen_height),
+            grid,
+            space,
+            monitors: monitors.clone(),
+            mark_source,
+            marks,
+            zoom: None,
+            verify_pixels,
+            focus: None,
+            windows: windows.as_deref(),
+            launch_allowlist: &launch_allowlist,
+            display: display.as_deref(),
+            policy: &policy,
+            dry_run,
+            rehearsed: Vec::new(),
+            approvals: 0,
+            rejected: false,
+            clipboard: clipboard
+                .as_mut()
+                .map(|clipboard| -> &mut dyn Clipboard { clipboard.as_mut() }),
+        };
 
         // Stage 2: Action Planning
-        let action_request = CreateChatCompletionRequestArgs::default()
-            .model(&model_name)
-            .max_tokens(max_tokens)
-            .messages([ChatCompletionRequestUserMessageArgs::default()
-                .content(vec![
-                    ChatCompletionRequestMessageContentPartTextArgs::default()
-                        .text(format!("{}
+        let plan = match planner_mode {
+            PlannerMode::Prompt => {
+                let action_schema = action::action_list_schema(unavailable_actions(mark_source));
+                let example_plan =
+                    serde_json::to_string_pretty(&action::example_plan()).unwrap_or_default();
+                let action_request = CreateChatCompletionRequestArgs::default()
+                    .model(&model_name)
+                    .max_tokens(max_tokens)
+                    .messages([ChatCompletionRequestUserMessageArgs::default()
+                        .content([vec![
+                            ChatCompletionRequestMessageContentPartTextArgs::default()
+                                .text(format!("{}
 
 Based on this context analysis and the instruction '{}', plan a sequence of actions. Your response must be a STRICT JSON array of actions.
 
 Context Analysis:
-{}
-
-Available Actions (use ONLY these exact formats):
-1. Window Focus:
-   {{ \"action\": \"window_focus\", \"title\": string, \"class\": string, \"method\": \"alt_tab\" | \"super_tab\" }}
-
-2. Mouse Movement:
-   {{ \"action\": \"mouse_move\", \"x\": number, \"y\": number }}
+{}{}
 
-3. Mouse Click:
-   {{ \"action\": \"mouse_click\", \"button\": \"left\" | \"right\" | \"middle\" }}
-
-4. Key Press:
-   {{ \"action\": \"key_press\", \"key\": \"return\" | \"tab\" | \"escape\" }}
-
-5. Key Combination:
-   {{ \"action\": \"key_combination\", \"keys\": [\"control\" | \"alt\" | \"shift\" | \"meta\", string] }}
-
-6. Text Input:
-   {{ \"action\": \"text_input\", \"text\": string }}
+Coordinates:
+{}
 
-7. Wait:
-   {{ \"action\": \"wait\", \"ms\": number }}
-   
-8. Task Done:
-   {{ \"action\": \"task_done\", \"reason\": string }}
+Available Actions:
+Every action is a JSON object whose \"action\" field selects its type. The response must validate against this JSON Schema:
+{}
 
 Guidelines:
 1. Response must be ONLY the JSON array, no additional text
-2. Each action must follow the exact format shown above
-3. Wait times should be between 100-1000ms
-4. Mouse coordinates must be within screen bounds
+2. Each action must follow the schema exactly, without extra fields
+3. Wait times should be between 100-1000ms; when a page or window has to load, use wait_for_stable, wait_for_change or wait_for_window instead of guessing a longer delay
+4. Mouse coordinates must be in screenshot pixels, within the screenshot bounds
 5. Key combinations must include at least one modifier key
 6. Do not include any explanations or comments in the JSON
 7. ALWAYS start with window_focus action if the target window is not already active
 8. Add a wait after window_focus to ensure the window is ready
 9. Use super_tab for window switching if alt_tab doesn't work
 10. Verify window focus before proceeding with actions
+11. Prefer click_at over a mouse_move followed by mouse_click
+12. Use scroll to reach content outside the visible area, and drag for sliders or selecting text
+13. Every mouse_down must be followed by a mouse_up in the same plan
+14. Put a zoom around a small target, such as a checkbox or close button, right before the action aimed at it; you will then be shown the region at full resolution to place it precisely
+15. Use paste_text instead of text_input for long text, and clipboard_get after copying text that later steps need
 
 Example valid response:
-[
-    {{ \"action\": \"window_focus\", \"title\": \"Google Chrome\", \"class\": \"chrome\", \"method\": \"super_tab\" }},
-    {{ \"action\": \"wait\", \"ms\": 500 }},
-    {{ \"action\": \"key_combination\", \"keys\": [\"control\", \"t\"] }},
-    {{ \"action\": \"wait\", \"ms\": 500 }},
-    {{ \"action\": \"text_input\", \"text\": \"google.com\" }},
-    {{ \"action\": \"wait\", \"ms\": 200 }},
-    {{ \"action\": \"key_press\", \"key\": \"return\" }}
-]", history_text, instruction, clean_analysis))
+{}", history_text, instruction, clean_analysis, describe_clipboard(&task_state), describe_screen(&space, &monitors, grid), action_schema, example_plan))
+                                .build()
+                                .unwrap()
+                                .into()], marks_content].concat())
                         .build()
                         .unwrap()
                         .into()])
-                .build()
-                .unwrap()
-                .into()])
-            .build()
-            .unwrap();
+                    .build()
+                    .unwrap();
+
+                let action_json = match llm
+                    .complete(Call::new(Stage::Planning, iteration), action_request)
+                    .await
+                {
+                    Ok(response) => response,
+                    Err(e) => {
+                        let e = e.into();
+                        record_failure(&mut task_state, &session, "planning", &e);
+                        end_of_script(&e, &should_continue);
+                        continue;
+                    }
+                };
+                println!("Action Plan: {}", action_json);
 
-        let action_response = client.chat().create(action_request).await.unwrap();
-        let mut action_json = String::new();
-        for choice in action_response.choices {
-            action_json = choice.message.content.unwrap_or_default();
-            println!("Action Plan: {}", action_json);
-        }
+                // Clean up the action JSON, validated below
+                clean_json_response(&action_json).to_string()
+            }
+            PlannerMode::Tools => {
+                // The tool calls are validated and executed as they arrive
+                let prompt = format!("{}
+
+Based on this context analysis and the instruction '{}', carry out the next steps of the task by calling the action tools.
+
+Context Analysis:
+{}{}
 
-        // Clean up and validate the action JSON
-        let clean_action = action_json
-            .trim()
-            .trim_start_matches("```json")
-            .trim_start_matches("```")
-            .trim_end_matches("```")
-            .trim();
+Coordinates:
+{}
+
+Guidelines:
+1. Call one or more tools per turn; every call is answered with its result, and every turn with a new screenshot
+2. Wait times should be between 100-1000ms; when a page or window has to load, use wait_for_stable, wait_for_change or wait_for_window instead of guessing a longer delay
+3. Mouse coordinates must be in screenshot pixels, within the screenshot bounds
+4. Key combinations must include at least one modifier key
+5. ALWAYS start with window_focus if the target window is not already active, followed by a wait
+6. Prefer click_at over a mouse_move followed by mouse_click
+7. Use scroll to reach content outside the visible area, and drag for sliders or selecting text
+8. Every mouse_down must be followed by a mouse_up
+9. Call zoom around a small target, such as a checkbox or close button, right before the action aimed at it; you will then be shown the region at full resolution to place it precisely
+10. Use paste_text instead of text_input for long text, and clipboard_get after copying text that later steps need
+11. Call task_done once the task is complete
+12. Reply without calling a tool to end this iteration", history_text, instruction, clean_analysis, describe_clipboard(&task_state), describe_screen(&space, &monitors, grid));
+                let mut content = vec![text_part(prompt), image_part(&res_base64)];
+                content.extend(marks_content);
+                let executed = plan_with_tools(
+                    &mut runner,
+                    content,
+                    &analysis,
+                    &mut task_state,
+                    &mut was_paused,
+                    planner_turns,
+                )
+                .await;
+                serde_json::to_string_pretty(&executed).unwrap_or_default()
+            }
+        };
 
         // Save action JSON
         let action_file_name = format!("{}/actions.json", iteration_dir);
-        fs::write(&action_file_name, clean_action).unwrap();
+        save_artifact(&action_file_name, &plan);
+
+        // Save metadata so later iterations can include this one in their history
+        let metadata = serde_json::json!({
+            "timestamp": timestamp,
+            "instruction": instruction,
+            "status": task_state.status,
+            "feedback": task_state.feedback.last(),
+        });
+        let metadata_file_name = format!("{}/metadata.json", iteration_dir);
+        save_artifact(&metadata_file_name, &metadata.to_string());
 
         // Generate self-instruction for next iteration if task is not complete
         if task_state.status != "completed" {
-            let new_instruction = generate_self_instruction(
-                &client,
+            match generate_self_instruction(
+                llm.as_ref(),
                 &model_name,
+                iteration,
                 &iterations_history,
                 &instruction,
                 &task_state,
             )
-            .await;
-            println!("Generated new instruction: {}", new_instruction);
-            *current_instruction.lock().unwrap() = new_instruction;
-        }
-
-        // Validate action JSON structure
-        if let Err(e) = serde_json::from_str::<Vec<serde_json::Value>>(clean_action) {
-            println!("Error: Invalid action JSON format: {}", e);
-            continue;
+            .await
+            {
+                Ok(new_instruction) => {
+                    println!("Generated new instruction: {}", new_instruction);
+                    *current_instruction.lock().unwrap() = new_instruction;
+                }
+                Err(e) => println!("Error: Could not generate new instruction: {}", e),
+            }
         }
 
-        // Stage 3: Execution
-        if let Ok(actions) = serde_json::from_str::<Vec<serde_json::Value>>(clean_action) {
-            for action in actions {
-                if !*should_continue.lock().unwrap() {
-                    break;
+        // Validate action JSON structure. Tool calls have already run.
+        let actions = match planner_mode {
+            PlannerMode::Prompt => match action::parse_actions(&plan) {
+                Ok(actions) => actions,
+                Err(e) => {
+                    task_state
+                        .feedback
+                        .push(format!("Invalid action plan: {}", e));
+                    record_failure(&mut task_state, &session, "planning", &e.into());
+                    continue;
                 }
+            },
+            PlannerMode::Tools => Vec::new(),
+        };
 
-                // Parse the analysis JSON for verification
-                let analysis_json =
-                    if let Ok(json) = serde_json::from_str::<serde_json::Value>(&clean_analysis) {
-                        json
-                    } else {
-                        println!("Error: Could not parse analysis JSON for verification");
-                        continue;
-                    };
-
-                // Execute the action and verify it
-                let action_result = match action["action"].as_str() {
-                    Some("window_focus") => {
-                        if let (Some(title), Some(class), Some(method)) = (
-                            action["title"].as_str(),
-                            action["class"].as_str(),
-                            action["method"].as_str(),
-                        ) {
-                            println!("Focusing window: {} ({}) using {}", title, class, method);
-                            match method {
-                                "alt_tab" => {
-                                    enigo.key(Key::Alt, Direction::Press).unwrap();
-                                    sleep(Duration::from_millis(100));
-                                    enigo.key(Key::Tab, Direction::Click).unwrap();
-                                    sleep(Duration::from_millis(100));
-                                    enigo.key(Key::Alt, Direction::Release).unwrap();
-                                }
-                                "super_tab" => {
-                                    enigo.key(Key::Meta, Direction::Press).unwrap();
-                                    sleep(Duration::from_millis(100));
-                                    enigo.key(Key::Tab, Direction::Click).unwrap();
-                                    sleep(Duration::from_millis(100));
-                                    enigo.key(Key::Meta, Direction::Release).unwrap();
-                                }
-                                _ => println!("Unknown window focus method: {}", method),
-                            }
-
-                            // Wait a bit for the window to focus
-                            sleep(Duration::from_millis(500));
-
-                            // Capture a new screenshot to verify the action
-                            let monitors = Monitor::all().unwrap();
-                            let monitor = monitors.first().unwrap();
-                            let image = monitor.capture_image().unwrap();
-                            let verify_image_path =
-                                format!("{}/verify_screenshot.png", iteration_dir);
-                            image.save(&verify_image_path).unwrap();
-
-                            // Analyze the new screenshot
-                            let verify_analysis_request = CreateChatCompletionRequestArgs::default()
-                                .model(&model_name)
-                                .max_tokens(max_tokens)
-                                .messages([ChatCompletionRequestUserMessageArgs::default()
-                                    .content(vec![
-                                        ChatCompletionRequestMessageContentPartTextArgs::default()
-                                            .text("Analyze this screenshot and provide a STRICT JSON response with the same format as before.")
-                                            .build()
-                                            .unwrap()
-                                            .into(),
-                                        ChatCompletionRequestMessageContentPartImageArgs::default()
-                                            .image_url(
-                                                ImageUrlArgs::default()
-                                                    .url(format!("data:image/png;base64,{}", 
-                                                        base64::engine::general_purpose::STANDARD.encode(
-                                                            fs::read(&verify_image_path).unwrap()
-                                                        )))
-                                                    .detail(ImageDetail::High)
-                                                    .build()
-                                                    .unwrap(),
-                                            )
-                                            .build()
-                                            .unwrap()
-                                            .into(),
-                                    ])
-                                    .build()
-                                    .unwrap()
-                                    .into()])
-                                .build()
-                                .unwrap();
-
-                            let verify_analysis_response =
-                                client.chat().create(verify_analysis_request).await.unwrap();
-                            let mut verify_analysis_json = String::new();
-                            for choice in verify_analysis_response.choices {
-                                verify_analysis_json = choice.message.content.unwrap_or_default();
-                            }
-
-                            // Clean up and validate the verification analysis JSON
-                            let clean_verify_analysis = verify_analysis_json
-                                .trim()
-                                .trim_start_matches("```json")
-                                .trim_start_matches("```")
-                                .trim_end_matches("```")
-                                .trim();
-
-                            // Parse the verification analysis JSON
-                            if let Ok(verify_json) =
-                                serde_json::from_str::<serde_json::Value>(&clean_verify_analysis)
-                            {
-                                // Verify the action
-                                retry_action(&action, &verify_json, &mut task_state, &mut enigo)
-                            } else {
-                                println!("Error: Could not parse verification analysis JSON");
-                                ActionResult::new("window_focus").with_error("Verification failed")
-                            }
-                        } else {
-                            println!("Error: Missing parameters for window_focus action");
-                            ActionResult::new("window_focus").with_error("Missing parameters")
-                        }
-                    }
-                    Some("mouse_move") => {
-                        if let (Some(x), Some(y)) = (action["x"].as_i64(), action["y"].as_i64()) {
-                            println!("Moving mouse to ({}, {})", x, y);
-                            enigo
-                                .move_mouse(x as i32, y as i32, Coordinate::Abs)
-                                .unwrap();
-
-                            // Verify the action
-                            retry_action(&action, &analysis_json, &mut task_state, &mut enigo)
-                        } else {
-                            println!("Error: Missing coordinates for mouse_move action");
-                            ActionResult::new("mouse_move").with_error("Missing coordinates")
-                        }
-                    }
-                    Some("mouse_click") => {
-                        if let Some(button) = action["button"].as_str() {
-                            println!("Clicking {} mouse button", button);
-                            match button {
-                                "left" => enigo.button(Button::Left, Direction::Click).unwrap(),
-                                "right" => enigo.button(Button::Right, Direction::Click).unwrap(),
-                                "middle" => enigo.button(Button::Middle, Direction::Click).unwrap(),
-                                _ => println!("Unknown button: {}", button),
-                            }
-
-                            // Verify the action
-                            retry_action(&action, &analysis_json, &mut task_state, &mut enigo)
-                        } else {
-                            println!("Error: Missing button for mouse_click action");
-                            ActionResult::new("mouse_click").with_error("Missing button")
-                        }
-                    }
-                    Some("key_press") => {
-                        if let Some(key) = action["key"].as_str() {
-                            println!("Pressing key: {}", key);
-                            match key.to_lowercase().as_str() {
-                                "return" | "enter" => {
-                                    enigo.key(Key::Return, Direction::Click).unwrap()
-                                }
-                                "tab" => enigo.key(Key::Tab, Direction::Click).unwrap(),
-                                "escape" => enigo.key(Key::Escape, Direction::Click).unwrap(),
-                                _ => println!("Unknown key: {}", key),
-                            }
-
-                            // Verify the action
-                            retry_action(&action, &analysis_json, &mut task_state, &mut enigo)
-                        } else {
-                            println!("Error: Missing key for key_press action");
-                            ActionResult::new("key_press").with_error("Missing key")
-                        }
-                    }
-                    Some("key_combination") => {
-                        if let Some(keys) = action["keys"].as_array() {
-                            let key_names: Vec<String> = keys
-                                .iter()
-                                .filter_map(|k| k.as_str())
-                                .map(|s| s.to_lowercase())
-                                .collect();
-                            println!("Pressing key combination: {:?}", key_names);
-
-                            // Press all modifier keys first
-                            for key in &key_names {
-                                match key.as_str() {
-                                    "control" | "ctrl" => {
-                                        enigo.key(Key::Control, Direction::Press).unwrap();
-                                    }
-                                    "alt" => {
-                                        enigo.key(Key::Alt, Direction::Press).unwrap();
-                                    }
-                                    "shift" => {
-                                        enigo.key(Key::Shift, Direction::Press).unwrap();
-                                    }
-                                    "meta" | "super" | "windows" => {
-                                        enigo.key(Key::Meta, Direction::Press).unwrap();
-                                    }
-                                    _ => {}
-                                }
-                            }
-
-                            // Small delay to ensure modifier keys are registered
-                            sleep(Duration::from_millis(50));
-
-                            // Press the last key (non-modifier)
-                            if let Some(last_key) = key_names.last() {
-                                match last_key.as_str() {
-                                    "t" => enigo.text("t").unwrap(),
-                                    "w" => enigo.text("w").unwrap(),
-                                    "r" => enigo.text("r").unwrap(),
-                                    "l" => enigo.text("l").unwrap(),
-                                    "a" => enigo.text("a").unwrap(),
-                                    "c" => enigo.text("c").unwrap(),
-                                    "v" => enigo.text("v").unwrap(),
-                                    "x" => enigo.text("x").unwrap(),
-                                    "z" => enigo.text("z").unwrap(),
-                                    _ => println!("Unknown key in combination: {}", last_key),
-                                }
-                            }
-
-                            // Small delay to ensure the key combination is registered
-                            sleep(Duration::from_millis(50));
-
-                            for key in &key_names {
-                                match key.as_str() {
-                                    "control" | "ctrl" => {
-                                        enigo.key(Key::Control, Direction::Release).unwrap();
-                                    }
-                                    "alt" => {
-                                        enigo.key(Key::Alt, Direction::Release).unwrap();
-                                    }
-                                    "shift" => {
-                                        enigo.key(Key::Shift, Direction::Release).unwrap();
-                                    }
-                                    "meta" | "super" | "windows" => {
-                                        enigo.key(Key::Meta, Direction::Release).unwrap();
-                                    }
-                                    _ => {}
-                                }
-                            }
-
-                            // Verify the action
-                            retry_action(&action, &analysis_json, &mut task_state, &mut enigo)
-                        } else {
-                            println!("Error: Missing keys for key_combination action");
-                            ActionResult::new("key_combination").with_error("Missing keys")
-                        }
-                    }
-                    Some("text_input") => {
-                        if let Some(text) = action["text"].as_str() {
-                            println!("Typing text: {}", text);
-                            enigo.text(text).unwrap();
-
-                            // Verify the action
-                            retry_action(&action, &analysis_json, &mut task_state, &mut enigo)
-                        } else {
-                            println!("Error: Missing text for text_input action");
-                            ActionResult::new("text_input").with_error("Missing text")
-                        }
-                    }
-                    Some("wait") => {
-                        if let Some(ms) = action["ms"].as_i64() {
-                            println!("Waiting for {}ms", ms);
-                            sleep(Duration::from_millis(ms as u64));
-
-                            // Wait actions always succeed
-                            ActionResult::new("wait").success()
-                        } else {
-                            println!("Error: Missing ms for wait action");
-                            ActionResult::new("wait").with_error("Missing ms")
-                        }
-                    }
-                    Some("task_done") => {
-                        if let Some(reason) = action["reason"].as_str() {
-                            println!("Task done. Reason: {}", reason);
-                            task_state.set_task_done();
-                            save_task_state(&iteration_dir, &task_state);
-                            *is_idle.lock().unwrap() = true;
-
-                            // Task done actions always succeed
-                            ActionResult::new("task_done").success()
-                        } else {
-                            println!("Error: Missing reason for task_done action");
-                            ActionResult::new("task_done").with_error("Missing reason")
-                        }
-                    }
-                    _ => {
-                        println!("Unknown action: {:?}", action["action"]);
-                        ActionResult::new("unknown").with_error("Unknown action type")
-                    }
-                };
-
-                // Check if the action was successful
-                if !action_result.success {
-                    println!("Action failed: {:?}", action_result.error_message);
+        // Stage 3: Execution
+        let total_actions = actions.len();
+        for (index, action) in actions.iter().enumerate() {
+            if !runner.may_continue(
+                action,
+                total_actions - index,
+                &mut task_state,
+                &mut was_paused,
+            ) {
+                break;
+            }
 
-                    // If we've retried too many times, pause the task
-                    if action_result.retry_count >= 3 {
-                        println!(
-                            "Too many retries for action: {}. Pausing task.",
-                            action_result.action_type
-                        );
-                        task_state.status = "paused".to_string();
-                        save_task_state(&iteration_dir, &task_state);
-                        sleep(Duration::from_secs(5));
-                        break;
-                    }
+            // If the input fails halfway, drop the rest of the batch, which
+            // assumed it worked
+            let action_result = match runner.run_action(action, &analysis, &mut task_state).await {
+                Ok(action_result) => action_result,
+                Err(e) => {
+                    record_failure(&mut task_state, &session, action.name(), &e);
+                    break;
                 }
+            };
+            if !runner.check_result(&action_result, &mut task_state, &mut was_paused) {
+                break;
             }
         }
 
@@ -1414,4 +2847,5 @@ Example valid response:
 
     // Wait for the input thread to finish
     input_handle.join().unwrap();
+    Ok(())
 }
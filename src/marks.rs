@@ -0,0 +1,287 @@
+use crate::analysis::ScreenAnalysis;
+use crate::draw;
+use image::{Rgba, RgbaImage};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fmt;
+
+// A numbered box drawn on the screenshot so the planner can point at an
+// element by its number instead of guessing pixels. Coordinates are in
+// screenshot pixels, [x1, y1, x2, y2] like `UiElement::coords`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Mark {
+    pub id: usize,
+    pub kind: String,
+    pub coords: [i32; 4],
+}
+
+impl Mark {
+    pub fn center(&self) -> (i32, i32) {
+        let [x1, y1, x2, y2] = self.coords;
+        ((x1 + x2) / 2, (y1 + y2) / 2)
+    }
+}
+
+// Where the marks come from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MarkSource {
+    // The ui_elements of the analysis stage
+    Analysis,
+    // Boxes around regions of strong edges found in the screenshot itself
+    Detect,
+}
+
+impl MarkSource {
+    // Parse a MARKS value: analysis or detect
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "analysis" => Some(MarkSource::Analysis),
+            "detect" => Some(MarkSource::Detect),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnknownMark(pub usize);
+
+impl fmt::Display for UnknownMark {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "no element is marked with {}", self.0)
+    }
+}
+
+impl std::error::Error for UnknownMark {}
+
+// Most marks drawn on one screenshot; more only clutter it
+pub const MAX_MARKS: usize = 60;
+
+// Number the analysis's ui_elements from 1, in reading order
+pub fn from_analysis(analysis: &ScreenAnalysis) -> Vec<Mark> {
+    let boxes = analysis
+        .ui_elements
+        .iter()
+        .map(|element| (element.kind.clone(), element.coords))
+        .collect();
+    number(boxes)
+}
+
+// Side of the square cells the edge map is grouped into, in pixels
+const CELL: u32 = 4;
+
+// Luminance difference between neighbouring pixels that counts as an edge
+const EDGE_THRESHOLD: i32 = 40;
+
+// Find boxes around clusters of edges, which on a screen are mostly
+// buttons, icons, fields and lines of text. Edges are counted per cell, busy
+// cells are joined with their neighbours, and each group becomes a box.
+pub fn detect(image: &RgbaImage) -> Vec<Mark> {
+    let (width, height) = image.dimensions();
+    if width < 2 || height < 2 {
+        return Vec::new();
+    }
+    let luma = |x: u32, y: u32| {
+        let [r, g, b, _] = image.get_pixel(x, y).0;
+        (299 * r as i32 + 587 * g as i32 + 114 * b as i32) / 1000
+    };
+
+    let (cols, rows) = (width.div_ceil(CELL), height.div_ceil(CELL));
+    let mut edges = vec![0u32; (cols * rows) as usize];
+    for y in 0..height - 1 {
+        for x in 0..width - 1 {
+            let here = luma(x, y);
+            if (here - luma(x + 1, y)).abs() + (here - luma(x, y + 1)).abs() > EDGE_THRESHOLD {
+                edges[((y / CELL) * cols + x / CELL) as usize] += 1;
+            }
+        }
+    }
+    let busy: Vec<bool> = edges.iter().map(|&count| count >= CELL / 2).collect();
+
+    let mut boxes = Vec::new();
+    for [min_col, min_row, max_col, max_row] in group_cells(&busy, cols, rows) {
+        let coords = [
+            (min_col * CELL) as i32,
+            (min_row * CELL) as i32,
+            ((max_col + 1) * CELL).min(width) as i32,
+            ((max_row + 1) * CELL).min(height) as i32,
+        ];
+        // Skip specks and regions too large to be a single control
+        let (box_width, box_height) = (coords[2] - coords[0], coords[3] - coords[1]);
+        if box_width < 2 * CELL as i32 || box_height < 2 * CELL as i32 {
+            continue;
+        }
+        if box_width as u64 * box_height as u64 > width as u64 * height as u64 / 8 {
+            continue;
+        }
+        boxes.push(("region".to_string(), coords));
+    }
+    number(boxes)
+}
+
+// Group the set cells of a cols x rows grid that touch, including
+// diagonally, into [min_col, min_row, max_col, max_row] bounds
+pub(crate) fn group_cells(cells: &[bool], cols: u32, rows: u32) -> Vec<[u32; 4]> {
+    let mut seen = vec![false; cells.len()];
+    let mut groups = Vec::new();
+    for start in 0..cells.len() {
+        if !cells[start] || seen[start] {
+            continue;
+        }
+        seen[start] = true;
+        let mut queue = VecDeque::from([start]);
+        let (mut min_col, mut min_row, mut max_col, mut max_row) = (cols, rows, 0, 0);
+        while let Some(cell) = queue.pop_front() {
+            let (col, row) = (cell as u32 % cols, cell as u32 / cols);
+            (min_col, min_row) = (min_col.min(col), min_row.min(row));
+            (max_col, max_row) = (max_col.max(col), max_row.max(row));
+            for (dx, dy) in [
+                (-1, -1),
+                (0, -1),
+                (1, -1),
+                (-1, 0),
+                (1, 0),
+                (-1, 1),
+                (0, 1),
+                (1, 1),
+            ] {
+                let (next_col, next_row) = (col as i32 + dx, row as i32 + dy);
+                if next_col < 0
+                    || next_row < 0
+                    || next_col >= cols as i32
+                    || next_row >= rows as i32
+                {
+                    continue;
+                }
+                let next = (next_row as u32 * cols + next_col as u32) as usize;
+                if cells[next] && !seen[next] {
+                    seen[next] = true;
+                    queue.push_back(next);
+                }
+            }
+        }
+        groups.push([min_col, min_row, max_col, max_row]);
+    }
+    groups
+}
+
+// Sort boxes into reading order, keep the first MAX_MARKS and number them from 1
+fn number(mut boxes: Vec<(String, [i32; 4])>) -> Vec<Mark> {
+    boxes.sort_by_key(|(_, [x1, y1, _, _])| (*y1, *x1));
+    boxes
+        .into_iter()
+        .take(MAX_MARKS)
+        .enumerate()
+        .map(|(index, (kind, coords))| Mark {
+            id: index + 1,
+            kind,
+            coords,
+        })
+        .collect()
+}
+
+pub fn find(marks: &[Mark], id: usize) -> Result<&Mark, UnknownMark> {
+    marks
+        .iter()
+        .find(|mark| mark.id == id)
+        .ok_or(UnknownMark(id))
+}
+
+// Box colours, cycled so neighbouring marks are told apart
+const COLORS: [Rgba<u8>; 6] = [
+    Rgba([230, 25, 75, 255]),
+    Rgba([0, 130, 200, 255]),
+    Rgba([60, 180, 75, 255]),
+    Rgba([245, 130, 48, 255]),
+    Rgba([145, 30, 180, 255]),
+    Rgba([0, 128, 128, 255]),
+];
+
+// Draw every mark as an outlined box with its number in a tag at the top-left corner
+pub fn draw(image: &mut RgbaImage, marks: &[Mark]) {
+    for mark in marks {
+        let color = COLORS[mark.id % COLORS.len()];
+        let [x1, y1, x2, y2] = mark.coords;
+        for inset in 0..2 {
+            draw::outline(
+                image,
+                [x1 + inset, y1 + inset, x2 - inset, y2 - inset],
+                color,
+            );
+        }
+
+        // Above the box when there is room, otherwise inside it
+        let (_, tag_height) = draw::tag_size(mark.id);
+        let tag_y = if y1 >= tag_height {
+            y1 - tag_height
+        } else {
+            y1
+        };
+        draw::tag(image, x1, tag_y, mark.id, color);
+    }
+}
+
+// List the marks for the prompt
+pub fn describe(marks: &[Mark]) -> String {
+    marks
+        .iter()
+        .map(|mark| {
+            let [x1, y1, x2, y2] = mark.coords;
+            format!(
+                "[{}] {} at ({}, {})-({}, {})",
+                mark.id, mark.kind, x1, y1, x2, y2
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_a_button_on_a_plain_background() {
+        let mut image = RgbaImage::from_pixel(200, 100, Rgba([255, 255, 255, 255]));
+        draw::outline(&mut image, [40, 20, 100, 44], Rgba([0, 0, 0, 255]));
+
+        let marks = detect(&image);
+        assert_eq!(marks.len(), 1);
+        let [x1, y1, x2, y2] = marks[0].coords;
+        assert!(x1 <= 40 && y1 <= 20 && x2 >= 100 && y2 >= 44);
+        assert!(x2 - x1 <= 60 + 2 * CELL as i32 && y2 - y1 <= 24 + 2 * CELL as i32);
+    }
+
+    #[test]
+    fn blank_screen_has_no_marks() {
+        let image = RgbaImage::from_pixel(64, 64, Rgba([30, 30, 30, 255]));
+        assert!(detect(&image).is_empty());
+    }
+
+    #[test]
+    fn marks_are_numbered_in_reading_order() {
+        let marks = number(vec![
+            ("b".to_string(), [50, 10, 60, 20]),
+            ("c".to_string(), [0, 40, 10, 50]),
+            ("a".to_string(), [0, 10, 10, 20]),
+        ]);
+        let kinds: Vec<(usize, &str)> = marks.iter().map(|m| (m.id, m.kind.as_str())).collect();
+        assert_eq!(kinds, [(1, "a"), (2, "b"), (3, "c")]);
+        assert_eq!(find(&marks, 2).unwrap().center(), (55, 15));
+        assert_eq!(find(&marks, 4), Err(UnknownMark(4)));
+    }
+
+    #[test]
+    fn drawing_near_the_edges_stays_inside_the_image() {
+        let mut image = RgbaImage::new(20, 20);
+        let marks = number(vec![
+            ("x".to_string(), [2, 12, 18, 19]),
+            ("y".to_string(), [-5, -5, 30, 30]),
+        ]);
+        draw(&mut image, &marks);
+
+        // Marks are numbered from the top, so "x" is mark 2. Its tag does not
+        // fit above it and goes inside, over mark 1 that covers the image.
+        assert_eq!(*image.get_pixel(17, 18), COLORS[2]);
+        assert_eq!(*image.get_pixel(3, 13), COLORS[2]);
+    }
+}
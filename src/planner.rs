@@ -0,0 +1,177 @@
+use crate::action::{self, Action, ActionError};
+use crate::state::ActionResult;
+use async_openai::types::{
+    ChatCompletionMessageToolCall, ChatCompletionRequestMessage,
+    ChatCompletionRequestToolMessageArgs, ChatCompletionTool, ChatCompletionToolType,
+    FunctionObject,
+};
+use serde_json::Value;
+
+// How the planning stage gets actions out of the model
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlannerMode {
+    // Ask for a JSON array of actions in the reply text
+    Prompt,
+    // Offer every action as a tool and hold a conversation, answering each
+    // tool call with its result and each batch with a new screenshot
+    Tools,
+}
+
+impl PlannerMode {
+    // Parse a PLANNER value: prompt or tools
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "prompt" => Some(PlannerMode::Prompt),
+            "tools" => Some(PlannerMode::Tools),
+            _ => None,
+        }
+    }
+}
+
+// One tool per action type but those named in `left_out`, with the action's
+// fields as its parameters and the variant's description as its own
+pub fn action_tools(left_out: &[&str]) -> Vec<ChatCompletionTool> {
+    action::action_parameter_schemas()
+        .into_iter()
+        .filter(|(name, _)| !left_out.contains(&name.as_str()))
+        .map(|(name, mut parameters)| {
+            let description = parameters
+                .as_object_mut()
+                .and_then(|parameters| parameters.remove("description"))
+                .and_then(|description| description.as_str().map(str::to_string));
+            ChatCompletionTool {
+                r#type: ChatCompletionToolType::Function,
+                function: FunctionObject {
+                    description,
+                    name,
+                    parameters: Some(parameters),
+                    strict: None,
+                },
+            }
+        })
+        .collect()
+}
+
+// Turn a tool call into an action, validated like a planned one
+pub fn parse_tool_call(tool_call: &ChatCompletionMessageToolCall) -> Result<Action, ActionError> {
+    let name = &tool_call.function.name;
+    let invalid = |message: String| ActionError::Invalid {
+        index: 0,
+        action: Some(name.clone()),
+        field: None,
+        message,
+    };
+
+    let arguments = match tool_call.function.arguments.trim() {
+        "" => "{}",
+        arguments => arguments,
+    };
+    let mut value = serde_json::from_str::<Value>(arguments)
+        .map_err(|e| invalid(format!("arguments are not valid JSON: {}", e)))?;
+    let Some(object) = value.as_object_mut() else {
+        return Err(invalid("arguments must be a JSON object".to_string()));
+    };
+    object.insert("action".to_string(), Value::String(name.clone()));
+    action::parse_single_action(&value)
+}
+
+// The tool message answering a call with the outcome of its action
+pub fn tool_result(
+    tool_call: &ChatCompletionMessageToolCall,
+    result: &ActionResult,
+) -> ChatCompletionRequestMessage {
+    let mut content = match &result.error_message {
+        Some(error) => serde_json::json!({ "success": result.success, "error": error }),
+        None => serde_json::json!({ "success": result.success }),
+    };
+    if let Some(output) = &result.output {
+        content["output"] = Value::String(output.clone());
+    }
+    ChatCompletionRequestToolMessageArgs::default()
+        .tool_call_id(tool_call.id.clone())
+        .content(content.to_string())
+        .build()
+        .unwrap()
+        .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_openai::types::FunctionCall;
+
+    fn call(name: &str, arguments: &str) -> ChatCompletionMessageToolCall {
+        ChatCompletionMessageToolCall {
+            id: "call_1".to_string(),
+            r#type: ChatCompletionToolType::Function,
+            function: FunctionCall {
+                name: name.to_string(),
+                arguments: arguments.to_string(),
+            },
+        }
+    }
+
+    // The field and message a tool call is rejected with
+    fn rejected(name: &str, arguments: &str) -> (Option<String>, String) {
+        match parse_tool_call(&call(name, arguments)) {
+            Err(ActionError::Invalid { field, message, .. }) => (field, message),
+            other => panic!("expected an invalid call, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parses_tool_calls_like_planned_actions() {
+        assert_eq!(
+            parse_tool_call(&call("click_at", r#"{"x": 3, "y": 4}"#)).unwrap(),
+            Action::ClickAt {
+                x: 3,
+                y: 4,
+                button: Default::default(),
+                monitor: None,
+            }
+        );
+        // Actions without fields may come without arguments
+        assert!(parse_tool_call(&call("clipboard_get", "")).is_ok());
+
+        let (field, message) = rejected("fly", "{}");
+        assert_eq!(field.as_deref(), Some("action"));
+        assert!(message.contains("unknown action type"), "{}", message);
+
+        let (field, message) = rejected("click_at", r#"{"x": 3,"#);
+        assert_eq!(field, None);
+        assert!(message.contains("not valid JSON"), "{}", message);
+        let (_, message) = rejected("click_at", "[3, 4]");
+        assert!(message.contains("must be a JSON object"), "{}", message);
+
+        assert_eq!(
+            rejected("click_at", r#"{"x": -3, "y": 4}"#).0.as_deref(),
+            Some("x")
+        );
+        assert_eq!(
+            rejected("key_press", r#"{"key": "hyper"}"#).0.as_deref(),
+            Some("key")
+        );
+        // The tool name decides the action, whatever the arguments say
+        assert_eq!(
+            rejected("wait", r#"{"action": "click_at", "x": 1, "y": 1}"#)
+                .0
+                .as_deref(),
+            Some("ms")
+        );
+    }
+
+    #[test]
+    fn tools_leave_out_unavailable_actions() {
+        let names = |tools: Vec<ChatCompletionTool>| -> Vec<String> {
+            tools.into_iter().map(|tool| tool.function.name).collect()
+        };
+        let all = names(action_tools(&[]));
+        assert!(all.contains(&"click_element".to_string()));
+        let without_marks = names(action_tools(&["click_element"]));
+        assert!(!without_marks.contains(&"click_element".to_string()));
+        assert_eq!(without_marks.len(), all.len() - 1);
+
+        assert!(action::action_list_schema(&[]).contains("click_element"));
+        assert!(!action::action_list_schema(&["click_element"]).contains("click_element"));
+    }
+}
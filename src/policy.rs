@@ -0,0 +1,489 @@
+use crate::action::{self, Action};
+use crate::keys::{self, UnknownKey};
+use crate::window::WindowInfo;
+use enigo::Key;
+use regex::Regex;
+use serde::Deserialize;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// The policy file as written, e.g.
+//   {
+//     "deny_actions": ["launch_app"],
+//     "deny_keys": [["control", "alt", "delete"], ["super", "l"]],
+//     "click_regions": [[0, 0, 1919, 1079]],
+//     "click_windows": ["Firefox"],
+//     "deny_text": ["rm\\s+-rf", "(?i)password"],
+//     "approve_actions": ["launch_app"],
+//     "approve_keys": [["return"]],
+//     "approve_text": ["(?i)^ssh "],
+//     "approve_windows": ["Terminal"]
+//   }
+// Click regions are [x1, y1, x2, y2] in the pixels the input uses. When
+// regions or windows are given, clicks must land inside one of them.
+// The approve_* rules let an action run only once the operator approved it;
+// "*" in approve_actions asks for every action, and approve_windows for
+// any input while a window with one of the titles is active.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct PolicyFile {
+    deny_actions: Vec<String>,
+    deny_keys: Vec<Vec<String>>,
+    click_regions: Vec<[i32; 4]>,
+    click_windows: Vec<String>,
+    deny_text: Vec<String>,
+    approve_actions: Vec<String>,
+    approve_keys: Vec<Vec<String>>,
+    approve_text: Vec<String>,
+    approve_windows: Vec<String>,
+}
+
+// Rules every action is checked against before it runs. The default policy
+// allows everything.
+#[derive(Debug, Clone, Default)]
+pub struct Policy {
+    deny_actions: Vec<String>,
+    deny_keys: Vec<(String, Vec<Key>)>,
+    click_regions: Vec<[i32; 4]>,
+    click_windows: Vec<String>,
+    deny_text: Vec<Regex>,
+    approve_actions: Vec<String>,
+    approve_keys: Vec<(String, Vec<Key>)>,
+    approve_text: Vec<Regex>,
+    approve_windows: Vec<String>,
+}
+
+#[derive(Debug)]
+pub enum PolicyError {
+    Read(io::Error),
+    Parse(serde_json::Error),
+    // Each carries the rule it was found in, e.g. "approve_keys"
+    UnknownAction(&'static str, String),
+    Key(&'static str, UnknownKey),
+    Pattern(&'static str, regex::Error),
+}
+
+impl fmt::Display for PolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PolicyError::Read(e) => write!(f, "could not read the policy file: {}", e),
+            PolicyError::Parse(e) => write!(f, "invalid policy file: {}", e),
+            PolicyError::UnknownAction(field, name) => {
+                write!(f, "{} lists an unknown action `{}`", field, name)
+            }
+            PolicyError::Key(field, e) => write!(f, "{} lists an {}", field, e),
+            PolicyError::Pattern(field, e) => write!(f, "invalid {} pattern: {}", field, e),
+        }
+    }
+}
+
+impl std::error::Error for PolicyError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PolicyError::Read(e) => Some(e),
+            PolicyError::Parse(e) => Some(e),
+            PolicyError::UnknownAction(..) => None,
+            PolicyError::Key(_, e) => Some(e),
+            PolicyError::Pattern(_, e) => Some(e),
+        }
+    }
+}
+
+impl Policy {
+    pub fn load(path: &Path) -> Result<Self, PolicyError> {
+        Self::parse(&fs::read_to_string(path).map_err(PolicyError::Read)?)
+    }
+
+    pub fn parse(json: &str) -> Result<Self, PolicyError> {
+        let file: PolicyFile = serde_json::from_str(json).map_err(PolicyError::Parse)?;
+
+        let known: Vec<String> = action::action_parameter_schemas()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        let unknown = file
+            .deny_actions
+            .iter()
+            .map(|name| ("deny_actions", name))
+            .chain(
+                file.approve_actions
+                    .iter()
+                    .filter(|name| *name != "*")
+                    .map(|name| ("approve_actions", name)),
+            )
+            .find(|(_, name)| !known.contains(name));
+        if let Some((field, unknown)) = unknown {
+            return Err(PolicyError::UnknownAction(field, unknown.clone()));
+        }
+
+        Ok(Policy {
+            deny_keys: parse_combos("deny_keys", &file.deny_keys)?,
+            deny_text: parse_patterns("deny_text", &file.deny_text)?,
+            approve_keys: parse_combos("approve_keys", &file.approve_keys)?,
+            approve_text: parse_patterns("approve_text", &file.approve_text)?,
+            deny_actions: file.deny_actions,
+            click_regions: file.click_regions,
+            click_windows: file.click_windows,
+            approve_actions: file.approve_actions,
+            approve_windows: file.approve_windows,
+        })
+    }
+
+    // Whether clicks are only allowed inside some windows, which then have
+    // to be listed for `check`
+    pub fn restricts_clicks_to_windows(&self) -> bool {
+        !self.click_windows.is_empty()
+    }
+
+    // Whether `needs_approval` depends on the active window
+    pub fn watches_active_window(&self) -> bool {
+        !self.approve_windows.is_empty()
+    }
+
+    // Check an action as planned, and where it lands as `screen_action`:
+    // the same action resolved to input pixels, e.g. a click_element turned
+    // into a click_at. Action names, keys and text are checked on the
+    // planned action, clicks on the resolved one. Clicks at the cursor are
+    // placed with `cursor`, unknown if it could not be read. The error says
+    // which rule denied the action.
+    pub fn check(
+        &self,
+        action: &Action,
+        screen_action: &Action,
+        cursor: Option<(i32, i32)>,
+        windows: &[WindowInfo],
+    ) -> Result<(), String> {
+        let name = action.name();
+        if self.deny_actions.iter().any(|denied| denied == name) {
+            return Err(format!("{} actions are not allowed", name));
+        }
+
+        if let Some(combo) = pressed_combo(&self.deny_keys, action) {
+            return Err(format!("the key combination {} is not allowed", combo));
+        }
+        if let Some(pattern) = typed_pattern(&self.deny_text, action) {
+            return Err(format!("text matching `{}` must not be typed", pattern));
+        }
+
+        if self.click_regions.is_empty() && self.click_windows.is_empty() {
+            return Ok(());
+        }
+        for point in click_points(screen_action, cursor) {
+            let Some((x, y)) = point else {
+                return Err("the cursor position to click at is unknown".to_string());
+            };
+            if !self.allows_click(x, y, windows) {
+                return Err(format!(
+                    "({}, {}) is outside the regions and windows clicks are allowed in",
+                    x, y
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn allows_click(&self, x: i32, y: i32, windows: &[WindowInfo]) -> bool {
+        let in_region = self
+            .click_regions
+            .iter()
+            .any(|&[x1, y1, x2, y2]| (x1..=x2).contains(&x) && (y1..=y2).contains(&y));
+        let in_window = windows.iter().any(|window| {
+            let title = window.title.to_lowercase();
+            !window.minimized
+                && self
+                    .click_windows
+                    .iter()
+                    .any(|allowed| title.contains(&allowed.to_lowercase()))
+                && (window.x..window.x + window.width as i32).contains(&x)
+                && (window.y..window.y + window.height as i32).contains(&y)
+        });
+        in_region || in_window
+    }
+
+    // Why the operator has to approve an action before it runs, if they do.
+    // `active_window` is the title of the active window.
+    pub fn needs_approval(&self, action: &Action, active_window: &str) -> Option<String> {
+        let name = action.name();
+        if self
+            .approve_actions
+            .iter()
+            .any(|approved| approved == "*" || approved == name)
+        {
+            return Some(format!("{} actions need approval", name));
+        }
+        if let Some(combo) = pressed_combo(&self.approve_keys, action) {
+            return Some(format!("the key combination {} needs approval", combo));
+        }
+        if let Some(pattern) = typed_pattern(&self.approve_text, action) {
+            return Some(format!("text matching `{}` needs approval", pattern));
+        }
+        let active = active_window.to_lowercase();
+        if !sends_input(action) || self.approve_windows.is_empty() {
+            return None;
+        }
+        // Input to a window nobody identified might go to one of them
+        if active.is_empty() {
+            return Some("input to an unidentified window needs approval".to_string());
+        }
+        if self
+            .approve_windows
+            .iter()
+            .any(|title| active.contains(&title.to_lowercase()))
+        {
+            return Some(format!("input to \"{}\" needs approval", active_window));
+        }
+        None
+    }
+}
+
+fn parse_combos(
+    field: &'static str,
+    combos: &[Vec<String>],
+) -> Result<Vec<(String, Vec<Key>)>, PolicyError> {
+    combos
+        .iter()
+        .map(|combo| {
+            let keys = combo.iter().map(|name| keys::parse_key(name));
+            keys.collect::<Result<Vec<Key>, _>>()
+                .map(|keys| (combo.join("+"), keys))
+        })
+        .collect::<Result<_, _>>()
+        .map_err(|e| PolicyError::Key(field, e))
+}
+
+fn parse_patterns(field: &'static str, patterns: &[String]) -> Result<Vec<Regex>, PolicyError> {
+    patterns
+        .iter()
+        .map(|pattern| Regex::new(pattern))
+        .collect::<Result<_, _>>()
+        .map_err(|e| PolicyError::Pattern(field, e))
+}
+
+// The first of the combinations whose keys the action all presses, with
+// or without more keys
+fn pressed_combo<'a>(combos: &'a [(String, Vec<Key>)], action: &Action) -> Option<&'a str> {
+    let pressed = match action {
+        Action::KeyPress { key } => vec![key.clone()],
+        Action::KeyCombination { keys } => keys.clone(),
+        _ => return None,
+    };
+    // Keys that do not parse are rejected when the action runs
+    let pressed: Vec<Key> = pressed
+        .iter()
+        .filter_map(|name| keys::parse_key(name).ok())
+        .collect();
+    combos
+        .iter()
+        .find(|(_, keys)| keys.iter().all(|key| pressed.contains(key)))
+        .map(|(combo, _)| combo.as_str())
+}
+
+// The first pattern the text an action types or puts on the clipboard matches
+fn typed_pattern<'a>(patterns: &'a [Regex], action: &Action) -> Option<&'a str> {
+    let (Action::TextInput { text } | Action::PasteText { text } | Action::ClipboardSet { text }) =
+        action
+    else {
+        return None;
+    };
+    patterns
+        .iter()
+        .find(|pattern| pattern.is_match(text))
+        .map(Regex::as_str)
+}
+
+// Whether an action sends mouse or keyboard input to the active window
+fn sends_input(action: &Action) -> bool {
+    matches!(
+        action,
+        Action::MouseClick { .. }
+            | Action::ClickAt { .. }
+            | Action::ClickElement { .. }
+            | Action::DoubleClick { .. }
+            | Action::MouseDown { .. }
+            | Action::MouseUp { .. }
+            | Action::Drag { .. }
+            | Action::Scroll { .. }
+            | Action::KeyPress { .. }
+            | Action::KeyCombination { .. }
+            | Action::TextInput { .. }
+            | Action::PasteText { .. }
+    )
+}
+
+// Where an action presses a mouse button; None for a press at a cursor
+// position that is unknown
+fn click_points(action: &Action, cursor: Option<(i32, i32)>) -> Vec<Option<(i32, i32)>> {
+    match action {
+        Action::ClickAt { x, y, .. } => vec![Some((*x, *y))],
+        Action::MouseClick { .. } | Action::DoubleClick { .. } | Action::MouseDown { .. } => {
+            vec![cursor]
+        }
+        // The button is held from the start to the end of a drag
+        Action::Drag {
+            from_x,
+            from_y,
+            to_x,
+            to_y,
+            ..
+        } => vec![Some((*from_x, *from_y)), Some((*to_x, *to_y))],
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POLICY: &str = r#"{
+        "deny_actions": ["launch_app"],
+        "deny_keys": [["ctrl", "alt", "delete"], ["super", "l"]],
+        "click_regions": [[0, 0, 99, 99]],
+        "click_windows": ["Editor"],
+        "deny_text": ["rm\\s+-rf", "(?i)password"],
+        "approve_keys": [["return"]],
+        "approve_windows": ["Terminal"]
+    }"#;
+
+    #[test]
+    fn denies_actions_keys_and_text() {
+        let policy = Policy::parse(POLICY).unwrap();
+        let check = |json: serde_json::Value| {
+            let action = action::parse_single_action(&json).unwrap();
+            policy.check(&action, &action, None, &[])
+        };
+
+        assert!(check(serde_json::json!({"action": "launch_app", "name": "xterm"})).is_err());
+        assert!(
+            check(serde_json::json!({
+                "action": "key_combination",
+                "keys": ["Control", "Shift", "Alt", "Del"]
+            }))
+            .is_err()
+        );
+        assert!(
+            check(serde_json::json!({"action": "key_combination", "keys": ["control", "c"]}))
+                .is_ok()
+        );
+        assert!(
+            check(serde_json::json!({"action": "text_input", "text": "sudo rm  -rf /"})).is_err()
+        );
+        assert!(check(serde_json::json!({"action": "paste_text", "text": "My Password"})).is_err());
+        assert!(check(serde_json::json!({"action": "text_input", "text": "hello"})).is_ok());
+
+        assert!(matches!(
+            Policy::parse(r#"{"deny_actions": ["explode"]}"#),
+            Err(PolicyError::UnknownAction("deny_actions", _))
+        ));
+        // Errors name the rule they were found in
+        let error = |json: &str| Policy::parse(json).unwrap_err().to_string();
+        assert_eq!(
+            error(r#"{"approve_actions": ["explode"]}"#),
+            "approve_actions lists an unknown action `explode`"
+        );
+        assert_eq!(
+            error(r#"{"approve_keys": [["hyper"]]}"#),
+            "approve_keys lists an unknown key `hyper`"
+        );
+        assert!(error(r#"{"approve_text": ["("]}"#).starts_with("invalid approve_text pattern"));
+    }
+
+    #[test]
+    fn restricts_clicks_to_regions_and_windows() {
+        let policy = Policy::parse(POLICY).unwrap();
+        let editor = WindowInfo {
+            id: 1,
+            title: "notes.txt - Editor".to_string(),
+            instance: "editor".to_string(),
+            class: "Editor".to_string(),
+            x: 200,
+            y: 200,
+            width: 300,
+            height: 200,
+            active: true,
+            maximized: false,
+            minimized: false,
+        };
+        let click_at = |x, y| Action::ClickAt {
+            x,
+            y,
+            button: Default::default(),
+            monitor: None,
+        };
+        let windows = [editor];
+
+        assert!(
+            policy
+                .check(&click_at(50, 50), &click_at(50, 50), None, &windows)
+                .is_ok()
+        );
+        assert!(
+            policy
+                .check(&click_at(300, 300), &click_at(300, 300), None, &windows)
+                .is_ok()
+        );
+        assert!(
+            policy
+                .check(&click_at(150, 150), &click_at(150, 150), None, &windows)
+                .is_err()
+        );
+
+        let click = Action::MouseClick {
+            button: Default::default(),
+        };
+        assert!(
+            policy
+                .check(&click, &click, Some((10, 10)), &windows)
+                .is_ok()
+        );
+        assert!(policy.check(&click, &click, None, &windows).is_err());
+        assert!(Policy::default().check(&click, &click, None, &[]).is_ok());
+    }
+
+    #[test]
+    fn denies_marked_clicks_by_their_planned_name() {
+        let policy = Policy::parse(r#"{"deny_actions": ["click_element"]}"#).unwrap();
+        let planned = Action::ClickElement {
+            id: 3,
+            button: Default::default(),
+        };
+        let resolved = Action::ClickAt {
+            x: 10,
+            y: 10,
+            button: Default::default(),
+            monitor: None,
+        };
+        assert!(policy.check(&planned, &resolved, None, &[]).is_err());
+        assert!(policy.check(&resolved, &resolved, None, &[]).is_ok());
+
+        // Clicks are placed where the resolved action lands
+        let policy = Policy::parse(r#"{"click_regions": [[0, 0, 5, 5]]}"#).unwrap();
+        assert!(policy.check(&planned, &resolved, None, &[]).is_err());
+    }
+
+    #[test]
+    fn asks_approval_for_keys_and_windows() {
+        let policy = Policy::parse(POLICY).unwrap();
+        let enter = Action::KeyPress {
+            key: "Enter".to_string(),
+        };
+        let typing = Action::TextInput {
+            text: "ls".to_string(),
+        };
+
+        assert!(policy.needs_approval(&enter, "Editor").is_some());
+        assert!(policy.needs_approval(&typing, "Editor").is_none());
+        assert!(
+            policy
+                .needs_approval(&typing, "user@host: ~ - Terminal")
+                .is_some()
+        );
+        assert!(
+            policy
+                .needs_approval(&Action::Wait { ms: 100 }, "Terminal")
+                .is_none()
+        );
+        assert!(policy.needs_approval(&typing, "").is_some());
+    }
+}
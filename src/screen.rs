@@ -0,0 +1,562 @@
+use image::imageops;
+use image::{ImageError, ImageReader, Rgba, RgbaImage};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+use xcap::{Monitor, Window, XCapError};
+
+// Anything that can hand the agent a picture of the screen. The xcap source
+// captures a real monitor; the others make the loop reproducible without one.
+pub trait ScreenSource {
+    fn capture(&mut self) -> Result<RgbaImage, CaptureError>;
+
+    // Every monitor attached to the desktop, in the global coordinates the
+    // input backend uses, with the ones in the captured frame marked. Sources
+    // that only produce a frame return an empty list, meaning the frame is
+    // the whole desktop.
+    fn monitors(&self) -> Result<Vec<MonitorInfo>, CaptureError> {
+        Ok(Vec::new())
+    }
+
+    // Titles of the open windows. Sources without a window system behind
+    // them have none.
+    fn window_titles(&self) -> Result<Vec<String>, CaptureError> {
+        Ok(Vec::new())
+    }
+
+    // The X display the captured screen belongs to, when it is not the one
+    // DISPLAY names. Input, window management and launched applications
+    // have to use it too.
+    fn display(&self) -> Option<String> {
+        None
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MonitorInfo {
+    pub index: usize,
+    pub name: String,
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+    pub primary: bool,
+    pub captured: bool, // Whether the monitor is part of the captured frame
+}
+
+impl MonitorInfo {
+    // Smallest rectangle (x, y, width, height) containing all the monitors
+    pub fn bounds(monitors: &[&MonitorInfo]) -> Option<(i32, i32, u32, u32)> {
+        let x1 = monitors.iter().map(|m| m.x).min()?;
+        let y1 = monitors.iter().map(|m| m.y).min()?;
+        let x2 = monitors.iter().map(|m| m.x + m.width as i32).max()?;
+        let y2 = monitors.iter().map(|m| m.y + m.height as i32).max()?;
+        Some((x1, y1, (x2 - x1) as u32, (y2 - y1) as u32))
+    }
+}
+
+#[derive(Debug)]
+pub enum CaptureError {
+    Xcap(XCapError),
+    Io(io::Error),
+    Image(ImageError),
+    NoMonitor(usize),
+    NoFrames(PathBuf),
+    InvalidFrame(String),
+    InvalidSpec(String),
+}
+
+impl fmt::Display for CaptureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CaptureError::Xcap(e) => write!(f, "screen capture failed: {}", e),
+            CaptureError::Io(e) => write!(f, "screen capture I/O error: {}", e),
+            CaptureError::Image(e) => write!(f, "could not decode frame: {}", e),
+            CaptureError::NoMonitor(index) => write!(f, "no monitor with index {}", index),
+            CaptureError::NoFrames(dir) => {
+                write!(f, "no screenshots found under {}", dir.display())
+            }
+            CaptureError::InvalidFrame(message) => write!(f, "invalid frame: {}", message),
+            CaptureError::InvalidSpec(spec) => write!(f, "invalid screen source `{}`", spec),
+        }
+    }
+}
+
+impl std::error::Error for CaptureError {}
+
+impl From<XCapError> for CaptureError {
+    fn from(e: XCapError) -> Self {
+        CaptureError::Xcap(e)
+    }
+}
+
+impl From<io::Error> for CaptureError {
+    fn from(e: io::Error) -> Self {
+        CaptureError::Io(e)
+    }
+}
+
+impl From<ImageError> for CaptureError {
+    fn from(e: ImageError) -> Self {
+        CaptureError::Image(e)
+    }
+}
+
+// Build a source from a SCREEN_SOURCE style spec:
+//   xcap[:<monitor index>|all], replay:<dir>, synthetic[:<w>x<h>], xvfb[:<display>[:<w>x<h>]]
+pub fn from_spec(spec: &str) -> Result<Box<dyn ScreenSource>, CaptureError> {
+    let invalid = || CaptureError::InvalidSpec(spec.to_string());
+    let (kind, rest) = match spec.split_once(':') {
+        Some((kind, rest)) => (kind, Some(rest)),
+        None => (spec, None),
+    };
+
+    match kind {
+        "xcap" => match rest {
+            Some("all") => Ok(Box::new(XcapSource::all())),
+            Some(index) => Ok(Box::new(XcapSource::new(
+                index.parse().map_err(|_| invalid())?,
+            ))),
+            None => Ok(Box::new(XcapSource::new(0))),
+        },
+        "replay" => {
+            let dir = rest.filter(|dir| !dir.is_empty()).ok_or_else(invalid)?;
+            Ok(Box::new(ReplaySource::new(dir)?))
+        }
+        "synthetic" => {
+            let (width, height) = match rest {
+                Some(size) => parse_size(size).ok_or_else(invalid)?,
+                None => (1920, 1080),
+            };
+            Ok(Box::new(SyntheticSource::new(width, height)))
+        }
+        "xvfb" => {
+            let mut parts = rest.unwrap_or_default().split(':');
+            let display = match parts.next().filter(|d| !d.is_empty()) {
+                Some(display) => display.parse().map_err(|_| invalid())?,
+                None => 99,
+            };
+            let (width, height) = match parts.next() {
+                Some(size) => parse_size(size).ok_or_else(invalid)?,
+                None => (1920, 1080),
+            };
+            Ok(Box::new(XvfbSource::spawn(display, width, height)?))
+        }
+        _ => Err(invalid()),
+    }
+}
+
+fn parse_size(size: &str) -> Option<(u32, u32)> {
+    let (width, height) = size.split_once('x')?;
+    Some((width.parse().ok()?, height.parse().ok()?))
+}
+
+// Captures physical monitors through xcap: either a single one or all of
+// them stitched into one frame laid out like the virtual desktop
+pub struct XcapSource {
+    monitor_index: Option<usize>,
+}
+
+impl XcapSource {
+    pub fn new(monitor_index: usize) -> Self {
+        XcapSource {
+            monitor_index: Some(monitor_index),
+        }
+    }
+
+    pub fn all() -> Self {
+        XcapSource {
+            monitor_index: None,
+        }
+    }
+}
+
+impl ScreenSource for XcapSource {
+    fn capture(&mut self) -> Result<RgbaImage, CaptureError> {
+        let monitors = Monitor::all()?;
+        if let Some(index) = self.monitor_index {
+            let monitor = monitors.get(index).ok_or(CaptureError::NoMonitor(index))?;
+            return Ok(monitor.capture_image()?);
+        }
+
+        let infos = self.monitors()?;
+        let all: Vec<&MonitorInfo> = infos.iter().collect();
+        let (x, y, width, height) = MonitorInfo::bounds(&all).ok_or(CaptureError::NoMonitor(0))?;
+        let mut desktop = RgbaImage::from_pixel(width, height, Rgba([0, 0, 0, 255]));
+        for (monitor, info) in monitors.iter().zip(&infos) {
+            let image = monitor.capture_image()?;
+            imageops::overlay(
+                &mut desktop,
+                &image,
+                (info.x - x) as i64,
+                (info.y - y) as i64,
+            );
+        }
+        Ok(desktop)
+    }
+
+    fn monitors(&self) -> Result<Vec<MonitorInfo>, CaptureError> {
+        let monitors = Monitor::all()?;
+        if let Some(index) = self.monitor_index
+            && index >= monitors.len()
+        {
+            return Err(CaptureError::NoMonitor(index));
+        }
+
+        monitors
+            .iter()
+            .enumerate()
+            .map(|(index, monitor)| {
+                Ok(MonitorInfo {
+                    index,
+                    name: monitor.name()?,
+                    x: monitor.x()?,
+                    y: monitor.y()?,
+                    width: monitor.width()?,
+                    height: monitor.height()?,
+                    primary: monitor.is_primary()?,
+                    captured: self.monitor_index.is_none_or(|selected| selected == index),
+                })
+            })
+            .collect()
+    }
+
+    fn window_titles(&self) -> Result<Vec<String>, CaptureError> {
+        Window::all()?
+            .iter()
+            .map(|window| Ok(window.title()?))
+            .collect()
+    }
+}
+
+// Replays the screenshots of a previous run, oldest first. The directory can
+// be a single iteration or a parent holding many (e.g. target/iterations).
+// Once the frames run out the last one is returned again.
+pub struct ReplaySource {
+    frames: Vec<PathBuf>,
+    next: usize,
+}
+
+impl ReplaySource {
+    pub fn new(dir: impl AsRef<Path>) -> Result<Self, CaptureError> {
+        let dir = dir.as_ref();
+        let mut frames = Vec::new();
+
+        let single = dir.join("screenshot.png");
+        if single.is_file() {
+            frames.push(single);
+        } else {
+            let mut iterations: Vec<PathBuf> = fs::read_dir(dir)?
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .filter(|path| path.join("screenshot.png").is_file())
+                .collect();
+            iterations.sort();
+            frames.extend(iterations.iter().map(|path| path.join("screenshot.png")));
+        }
+
+        if frames.is_empty() {
+            return Err(CaptureError::NoFrames(dir.to_path_buf()));
+        }
+        Ok(ReplaySource { frames, next: 0 })
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+}
+
+impl ScreenSource for ReplaySource {
+    fn capture(&mut self) -> Result<RgbaImage, CaptureError> {
+        let index = self.next.min(self.frames.len() - 1);
+        self.next += 1;
+        let image = ImageReader::open(&self.frames[index])?.decode()?;
+        Ok(image.to_rgba8())
+    }
+}
+
+// Generates deterministic frames: a desktop background, a window and a
+// marker block that moves one step per capture so consecutive frames differ.
+pub struct SyntheticSource {
+    width: u32,
+    height: u32,
+    frame: u32,
+}
+
+impl SyntheticSource {
+    pub fn new(width: u32, height: u32) -> Self {
+        SyntheticSource {
+            width,
+            height,
+            frame: 0,
+        }
+    }
+
+    pub fn frame_index(&self) -> u32 {
+        self.frame
+    }
+}
+
+impl ScreenSource for SyntheticSource {
+    fn capture(&mut self) -> Result<RgbaImage, CaptureError> {
+        let (width, height) = (self.width, self.height);
+        let mut image = RgbaImage::from_pixel(width, height, Rgba([32, 48, 64, 255]));
+
+        // A "window" covering the middle of the screen with a title bar
+        let (wx1, wy1, wx2, wy2) = (width / 8, height / 8, width * 7 / 8, height * 7 / 8);
+        for y in wy1..wy2 {
+            for x in wx1..wx2 {
+                let color = if y < wy1 + 24 {
+                    Rgba([200, 200, 210, 255])
+                } else {
+                    Rgba([245, 245, 245, 255])
+                };
+                image.put_pixel(x, y, color);
+            }
+        }
+
+        // The moving marker
+        let size = (width.min(height) / 20).max(1);
+        let steps = ((wx2 - wx1).saturating_sub(size) / size).max(1);
+        let mx = wx1 + (self.frame % steps) * size;
+        let my = wy1 + 48;
+        for y in my..(my + size).min(height) {
+            for x in mx..(mx + size).min(width) {
+                image.put_pixel(x, y, Rgba([220, 40, 40, 255]));
+            }
+        }
+
+        self.frame += 1;
+        Ok(image)
+    }
+}
+
+// Runs a private Xvfb server and reads its framebuffer straight from the
+// XWD file Xvfb keeps in -fbdir, so no X connection is needed to capture.
+pub struct XvfbSource {
+    child: Child,
+    display: u32,
+    fbdir: PathBuf,
+}
+
+impl XvfbSource {
+    pub fn spawn(display: u32, width: u32, height: u32) -> Result<Self, CaptureError> {
+        let fbdir = std::env::temp_dir().join(format!("automation-xvfb-{}", display));
+        fs::create_dir_all(&fbdir)?;
+
+        let child = Command::new("Xvfb")
+            .arg(format!(":{}", display))
+            .args(["-screen", "0", &format!("{}x{}x24", width, height)])
+            .arg("-fbdir")
+            .arg(&fbdir)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()?;
+        let mut source = XvfbSource {
+            child,
+            display,
+            fbdir,
+        };
+
+        // Wait for the server to create its framebuffer file
+        let started = Instant::now();
+        while !source.framebuffer_path().exists() {
+            if let Some(status) = source.child.try_wait()? {
+                return Err(CaptureError::InvalidFrame(format!(
+                    "Xvfb exited during startup with {}",
+                    status
+                )));
+            }
+            if started.elapsed() > Duration::from_secs(10) {
+                return Err(CaptureError::InvalidFrame(
+                    "Xvfb did not create a framebuffer".to_string(),
+                ));
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+
+        Ok(source)
+    }
+
+    fn framebuffer_path(&self) -> PathBuf {
+        self.fbdir.join("Xvfb_screen0")
+    }
+}
+
+impl ScreenSource for XvfbSource {
+    fn capture(&mut self) -> Result<RgbaImage, CaptureError> {
+        decode_xwd(&fs::read(self.framebuffer_path())?)
+    }
+
+    fn display(&self) -> Option<String> {
+        Some(format!(":{}", self.display))
+    }
+}
+
+impl Drop for XvfbSource {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+// Decode a ZPixmap XWD image with 24 or 32 bits per pixel
+fn decode_xwd(data: &[u8]) -> Result<RgbaImage, CaptureError> {
+    let invalid = |message: &str| CaptureError::InvalidFrame(format!("XWD: {}", message));
+
+    // The header is 25 big-endian u32 fields followed by the window name
+    let field = |index: usize| -> Result<u32, CaptureError> {
+        data.get(index * 4..index * 4 + 4)
+            .map(|bytes| u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+            .ok_or_else(|| invalid("truncated header"))
+    };
+
+    let header_size = field(0)? as usize;
+    if field(1)? != 7 {
+        return Err(invalid("unsupported file version"));
+    }
+    if field(2)? != 2 {
+        return Err(invalid("only ZPixmap images are supported"));
+    }
+    let width = field(4)?;
+    let height = field(5)?;
+    let lsb_first = field(7)? == 0;
+    let bits_per_pixel = field(11)?;
+    let bytes_per_line = field(12)? as usize;
+    let masks = [field(14)?, field(15)?, field(16)?];
+    let ncolors = field(19)? as usize;
+
+    if bits_per_pixel != 24 && bits_per_pixel != 32 {
+        return Err(invalid("only 24 and 32 bits per pixel are supported"));
+    }
+    let bytes_per_pixel = bits_per_pixel as usize / 8;
+    if bytes_per_line < width as usize * bytes_per_pixel {
+        return Err(invalid("lines are shorter than the image is wide"));
+    }
+
+    // Pixel data follows the header and the 12-byte colormap entries
+    let offset = header_size + ncolors * 12;
+    let pixels = data
+        .get(offset..offset + bytes_per_line * height as usize)
+        .ok_or_else(|| invalid("truncated pixel data"))?;
+
+    let channel = |pixel: u32, mask: u32| -> u8 {
+        if mask == 0 {
+            return 0;
+        }
+        let value = (pixel & mask) >> mask.trailing_zeros();
+        let bits = (mask >> mask.trailing_zeros()).count_ones();
+        if bits >= 8 {
+            (value >> (bits - 8)) as u8
+        } else {
+            (value * 255 / ((1 << bits) - 1)) as u8
+        }
+    };
+
+    let mut image = RgbaImage::new(width, height);
+    for y in 0..height as usize {
+        let row = &pixels[y * bytes_per_line..];
+        for x in 0..width as usize {
+            let bytes = &row[x * bytes_per_pixel..(x + 1) * bytes_per_pixel];
+            let mut pixel = 0u32;
+            for (i, byte) in bytes.iter().enumerate() {
+                let shift = if lsb_first {
+                    i * 8
+                } else {
+                    (bytes_per_pixel - 1 - i) * 8
+                };
+                pixel |= (*byte as u32) << shift;
+            }
+            image.put_pixel(
+                x as u32,
+                y as u32,
+                Rgba([
+                    channel(pixel, masks[0]),
+                    channel(pixel, masks[1]),
+                    channel(pixel, masks[2]),
+                    255,
+                ]),
+            );
+        }
+    }
+
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A 2x1 XWD image, 32 bits per pixel, most significant byte first
+    fn xwd(bytes_per_line: u32, pixels: &[u8]) -> Vec<u8> {
+        let mut header = [0u32; 25];
+        header[0] = 100 + 16;
+        header[1] = 7;
+        header[2] = 2;
+        header[4] = 2;
+        header[5] = 1;
+        header[7] = 1;
+        header[11] = 32;
+        header[12] = bytes_per_line;
+        header[14] = 0xff0000;
+        header[15] = 0x00ff00;
+        header[16] = 0x0000ff;
+        let mut data: Vec<u8> = header
+            .iter()
+            .flat_map(|field| field.to_be_bytes())
+            .collect();
+        data.extend_from_slice(b"xwd window name\0");
+        data.extend_from_slice(pixels);
+        data
+    }
+
+    #[test]
+    fn decodes_xwd_frames() {
+        let image = decode_xwd(&xwd(8, &[0, 10, 20, 30, 0, 40, 50, 60])).unwrap();
+        assert_eq!(image.dimensions(), (2, 1));
+        assert_eq!(*image.get_pixel(0, 0), Rgba([10, 20, 30, 255]));
+        assert_eq!(*image.get_pixel(1, 0), Rgba([40, 50, 60, 255]));
+
+        assert!(decode_xwd(&xwd(4, &[0; 8])).is_err());
+        assert!(decode_xwd(&xwd(8, &[0; 4])).is_err());
+        assert!(decode_xwd(&[0; 10]).is_err());
+    }
+
+    #[test]
+    fn replays_frames_and_parses_specs() {
+        let dir = std::env::temp_dir().join(format!("automation-replay-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        for (name, shade) in [("b", 20), ("a", 10)] {
+            fs::create_dir_all(dir.join(name)).unwrap();
+            RgbaImage::from_pixel(4, 3, Rgba([shade, shade, shade, 255]))
+                .save(dir.join(name).join("screenshot.png"))
+                .unwrap();
+        }
+        let mut source = ReplaySource::new(&dir).unwrap();
+        assert_eq!(source.len(), 2);
+        let shades: Vec<u8> = (0..3)
+            .map(|_| source.capture().unwrap().get_pixel(0, 0)[0])
+            .collect();
+        assert_eq!(shades, [10, 20, 20]);
+        assert!(ReplaySource::new(dir.join("a").join("missing")).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+
+        let mut synthetic = from_spec("synthetic:64x48").unwrap();
+        assert_eq!(synthetic.capture().unwrap().dimensions(), (64, 48));
+        for spec in ["synthetic:64", "replay:", "xcap:first", "xvfb:x", "vnc"] {
+            assert!(
+                matches!(from_spec(spec), Err(CaptureError::InvalidSpec(_))),
+                "{}",
+                spec
+            );
+        }
+    }
+}
@@ -0,0 +1,200 @@
+use crate::state::{TaskState, load_task_state, save_task_state};
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// Root directory that holds one subdirectory per session
+pub const SESSIONS_DIR: &str = "target/sessions";
+
+// A session owns one persistent TaskState and every iteration run for it:
+//   target/sessions/<id>/session.json
+//   target/sessions/<id>/task_state.json
+//   target/sessions/<id>/iterations/<timestamp>_<iteration>/...
+pub struct Session {
+    id: String,
+    dir: PathBuf,
+}
+
+// What is needed besides the task state to pick a session back up
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct SessionInfo {
+    pub id: String,
+    pub instruction: String, // Instruction the operator gave last
+    pub created: String,     // Local time the session was started
+}
+
+impl Session {
+    // Start a new session named after the current time, with a numbered
+    // suffix when another one was started in the same second
+    pub fn create() -> io::Result<Self> {
+        Self::create_in(Path::new(SESSIONS_DIR))
+    }
+
+    // Start a new session in another sessions directory than SESSIONS_DIR
+    pub fn create_in(root: &Path) -> io::Result<Self> {
+        let timestamp = Local::now().format("%Y%m%d_%H%M%S").to_string();
+        fs::create_dir_all(root)?;
+        let mut id = timestamp.clone();
+        let mut suffix = 1;
+        loop {
+            match fs::create_dir(root.join(&id)) {
+                Ok(()) => break,
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    suffix += 1;
+                    id = format!("{}_{}", timestamp, suffix);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        let session = Session {
+            dir: root.join(&id),
+            id,
+        };
+        fs::create_dir_all(session.iterations_dir())?;
+
+        let info = SessionInfo {
+            id: session.id.clone(),
+            instruction: String::new(),
+            created: Local::now().to_rfc3339(),
+        };
+        session.write_info(&info)?;
+        Ok(session)
+    }
+
+    // Reopen an existing session, e.g. after a crash or restart. The id must
+    // name a directory directly inside SESSIONS_DIR.
+    pub fn open(id: &str) -> io::Result<Self> {
+        Self::open_in(Path::new(SESSIONS_DIR), id)
+    }
+
+    pub fn open_in(root: &Path, id: &str) -> io::Result<Self> {
+        if id.is_empty() || id.contains(['/', '\\']) || id.contains("..") {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid session id `{}`", id),
+            ));
+        }
+        let dir = root.join(id);
+        if !dir.join("session.json").exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no session `{}` in {}", id, root.display()),
+            ));
+        }
+        Ok(Session {
+            id: id.to_string(),
+            dir,
+        })
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn iterations_dir(&self) -> PathBuf {
+        self.dir.join("iterations")
+    }
+
+    // Number of iterations already recorded, used to continue the numbering
+    pub fn iteration_count(&self) -> usize {
+        fs::read_dir(self.iterations_dir())
+            .map(|entries| {
+                entries
+                    .filter_map(|entry| entry.ok())
+                    .filter(|entry| entry.path().is_dir())
+                    .count()
+            })
+            .unwrap_or(0)
+    }
+
+    // Create the directory for a new iteration. The iteration number keeps
+    // iterations started within the same second apart.
+    pub fn new_iteration_dir(&self, iteration: usize) -> io::Result<String> {
+        let timestamp = Local::now().format("%Y%m%d_%H%M%S").to_string();
+        let iteration_dir = self
+            .iterations_dir()
+            .join(format!("{}_{:04}", timestamp, iteration));
+        fs::create_dir(&iteration_dir)?;
+        Ok(iteration_dir.to_string_lossy().into_owned())
+    }
+
+    pub fn load_state(&self) -> TaskState {
+        load_task_state(&self.dir.to_string_lossy())
+    }
+
+    pub fn save_state(&self, state: &TaskState) {
+        save_task_state(&self.dir.to_string_lossy(), state);
+    }
+
+    pub fn info(&self) -> io::Result<SessionInfo> {
+        let text = fs::read_to_string(self.dir.join("session.json"))?;
+        serde_json::from_str(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    // Remember the current instruction so a resumed session can continue it
+    pub fn set_instruction(&self, instruction: &str) -> io::Result<()> {
+        let mut info = self.info().unwrap_or_else(|_| SessionInfo {
+            id: self.id.clone(),
+            ..Default::default()
+        });
+        if info.instruction == instruction {
+            return Ok(());
+        }
+        info.instruction = instruction.to_string();
+        self.write_info(&info)
+    }
+
+    fn write_info(&self, info: &SessionInfo) -> io::Result<()> {
+        let text = serde_json::to_string_pretty(info)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        fs::write(self.dir.join("session.json"), text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_ids_outside_the_sessions_directory() {
+        for id in ["", "../..", "/etc", "a/b", "..", "x\\y"] {
+            let error = Session::open(id).err().unwrap();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput, "{}", id);
+        }
+    }
+
+    #[test]
+    fn resumes_a_saved_session() {
+        let root = std::env::temp_dir().join(format!("sessions_{}", std::process::id()));
+        let session = Session::create_in(&root).unwrap();
+        let mut state = TaskState::new();
+        state.attempts = 3;
+        state.feedback.push("the button was disabled".to_string());
+        session.save_state(&state);
+        session.set_instruction("open the editor").unwrap();
+        session.new_iteration_dir(0).unwrap();
+        session.new_iteration_dir(1).unwrap();
+        // Sessions started in the same second are kept apart
+        let other = Session::create_in(&root).unwrap();
+        assert_ne!(other.id(), session.id());
+
+        let resumed = Session::open_in(&root, session.id()).unwrap();
+        let loaded = resumed.load_state();
+        assert_eq!(loaded.attempts, 3);
+        assert_eq!(loaded.feedback, state.feedback);
+        assert_eq!(resumed.info().unwrap().instruction, "open the editor");
+        // Iterations are numbered from 0, so the next one is 2
+        assert_eq!(resumed.iteration_count(), 2);
+        assert_eq!(
+            Session::open_in(&root, "missing").err().unwrap().kind(),
+            io::ErrorKind::NotFound
+        );
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
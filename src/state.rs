@@ -0,0 +1,252 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TaskState {
+    pub status: String, // "in_progress", "completed", "paused", "failed", "task_done"
+    pub attempts: u32,  // Number of attempts made
+    pub last_action: String, // Last action taken
+    pub success_criteria: Vec<String>, // Criteria for task completion
+    pub memory: HashMap<String, String>, // Persistent memory across iterations
+    pub feedback: Vec<String>, // Feedback from previous attempts
+    pub start_time: i64, // Unix timestamp when task started
+    pub last_update: i64, // Unix timestamp of last update
+    pub action_results: Vec<ActionResult>, // Results of previous actions
+    #[serde(default)]
+    pub resumed_at_attempt: u32, // Attempt count when the task was last resumed
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ActionResult {
+    pub action_type: String,           // Type of action performed
+    pub success: bool,                 // Whether the action was successful
+    pub timestamp: i64,                // When the action was performed
+    pub error_message: Option<String>, // Error message if the action failed
+    pub retry_count: u32,              // Number of retries attempted
+    #[serde(default)]
+    pub output: Option<String>, // What the action read, e.g. the clipboard text
+}
+
+impl ActionResult {
+    pub fn new(action_type: &str) -> Self {
+        ActionResult {
+            action_type: action_type.to_string(),
+            success: false,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs() as i64,
+            error_message: None,
+            retry_count: 0,
+            output: None,
+        }
+    }
+
+    pub fn success(mut self) -> Self {
+        self.success = true;
+        self
+    }
+
+    pub fn with_error(mut self, error: &str) -> Self {
+        self.error_message = Some(error.to_string());
+        self
+    }
+
+    pub fn with_output(mut self, output: &str) -> Self {
+        self.output = Some(output.to_string());
+        self
+    }
+
+    pub fn increment_retry(mut self) -> Self {
+        self.retry_count += 1;
+        self
+    }
+}
+
+impl TaskState {
+    pub fn new() -> Self {
+        TaskState {
+            status: "in_progress".to_string(),
+            attempts: 0,
+            last_action: String::new(),
+            success_criteria: vec![
+                "Task completed".to_string(),
+                "Information found".to_string(),
+                "Research complete".to_string(),
+                "Task done".to_string(), // Added new success criterion
+            ],
+            memory: HashMap::new(),
+            feedback: Vec::new(),
+            start_time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs() as i64,
+            last_update: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs() as i64,
+            action_results: Vec::new(),
+            resumed_at_attempt: 0,
+        }
+    }
+
+    pub fn update(&mut self, analysis: &str, actions: &str) {
+        self.attempts += 1;
+        self.last_update = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+
+        // Parse the last action from actions JSON
+        if let Ok(actions_json) = serde_json::from_str::<Vec<serde_json::Value>>(actions) {
+            if let Some(last_action) = actions_json.last() {
+                if let Some(action_type) = last_action["action"].as_str() {
+                    self.last_action = action_type.to_string();
+                }
+            }
+        }
+
+        // Update memory based on analysis
+        if let Ok(analysis_json) = serde_json::from_str::<serde_json::Value>(analysis) {
+            if let Some(context) = analysis_json["context"].as_str() {
+                self.memory
+                    .insert("last_context".to_string(), context.to_string());
+            }
+            if let Some(state) = analysis_json["state"].as_object() {
+                if let Some(window_title) = state["window_title"].as_str() {
+                    self.memory
+                        .insert("last_window".to_string(), window_title.to_string());
+                }
+            }
+            // Add feedback from challenges
+            if let Some(challenges) = analysis_json["challenges"].as_array() {
+                for challenge in challenges {
+                    if let Some(challenge_str) = challenge.as_str() {
+                        self.feedback.push(challenge_str.to_string());
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn should_pause(&self) -> bool {
+        // Only count attempts made since the operator last resumed the task
+        let attempts = self.attempts.saturating_sub(self.resumed_at_attempt);
+
+        // Pause if too many attempts
+        if attempts > 10 {
+            return true;
+        }
+
+        // Pause if stuck in a loop (same action repeated)
+        if attempts > 3 {
+            let last_actions: Vec<String> = self
+                .feedback
+                .iter()
+                .rev()
+                .take(3)
+                .filter_map(|f| f.split(":").next().map(|s| s.to_string()))
+                .collect();
+
+            if last_actions.len() == 3
+                && last_actions[0] == last_actions[1]
+                && last_actions[1] == last_actions[2]
+            {
+                return true;
+            }
+        }
+
+        false
+    }
+
+    pub fn is_complete(&self, analysis: &str) -> bool {
+        // Don't complete if we haven't taken any actions yet
+        if self.attempts < 2 {
+            return false;
+        }
+
+        // Check if all success criteria are met
+        for criterion in &self.success_criteria {
+            if !analysis.contains(criterion) {
+                return false;
+            }
+        }
+
+        // Check if there are no challenges
+        if let Ok(analysis_json) = serde_json::from_str::<serde_json::Value>(analysis) {
+            if let Some(challenges) = analysis_json["challenges"].as_array() {
+                if !challenges.is_empty() {
+                    return false;
+                }
+            }
+        }
+
+        // Check if we have performed meaningful actions
+        if self.last_action.is_empty() {
+            return false;
+        }
+
+        true
+    }
+
+    // Put the task on hold, remembering why
+    pub fn pause(&mut self, reason: &str) {
+        self.status = "paused".to_string();
+        self.feedback.push(format!("Paused: {}", reason));
+        self.last_update = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+    }
+
+    // Continue a paused task without discarding its memory and feedback
+    pub fn resume(&mut self) {
+        self.status = "in_progress".to_string();
+        self.resumed_at_attempt = self.attempts;
+        self.feedback
+            .push("Resumed: continuing from the paused state".to_string());
+        self.last_update = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+    }
+
+    // New method to explicitly set task to done state
+    pub fn set_task_done(&mut self) {
+        self.status = "task_done".to_string();
+        self.last_update = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+    }
+}
+
+impl Default for TaskState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Function to load or create task state
+pub fn load_task_state(state_dir: &str) -> TaskState {
+    let state_path = Path::new(state_dir).join("task_state.json");
+    if state_path.exists() {
+        if let Ok(state_json) = fs::read_to_string(&state_path) {
+            if let Ok(state) = serde_json::from_str::<TaskState>(&state_json) {
+                return state;
+            }
+        }
+    }
+    TaskState::new()
+}
+
+// Function to save task state
+pub fn save_task_state(state_dir: &str, state: &TaskState) {
+    let state_path = Path::new(state_dir).join("task_state.json");
+    if let Ok(state_json) = serde_json::to_string_pretty(state) {
+        let _ = fs::write(&state_path, state_json);
+    }
+}
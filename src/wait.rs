@@ -0,0 +1,174 @@
+use crate::diff;
+use crate::screen::{CaptureError, ScreenSource};
+use image::{RgbaImage, imageops};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+// Time between two captures while waiting
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+// All waits give up early once `interrupted` returns true, e.g. when the
+// task is paused or stopped, and report that as not having waited.
+
+// Sleep for `duration` unless interrupted first. Returns whether it slept
+// the whole time.
+pub fn sleep_for(duration: Duration, interrupted: &dyn Fn() -> bool) -> bool {
+    let started = Instant::now();
+    while started.elapsed() < duration {
+        if interrupted() {
+            return false;
+        }
+        sleep(POLL_INTERVAL.min(duration.saturating_sub(started.elapsed())));
+    }
+    true
+}
+
+// Wait until more than `threshold` percent of the region, in capture
+// pixels, or of the whole frame without one, differs from the first capture.
+// Returns whether it did before the timeout.
+pub fn wait_for_change(
+    screen: &mut dyn ScreenSource,
+    region: Option<[i32; 4]>,
+    threshold: f64,
+    timeout: Duration,
+    interrupted: &dyn Fn() -> bool,
+) -> Result<bool, CaptureError> {
+    let started = Instant::now();
+    let first = crop(screen.capture()?, region);
+    loop {
+        if interrupted() {
+            return Ok(false);
+        }
+        sleep(POLL_INTERVAL.min(timeout.saturating_sub(started.elapsed())));
+        let frame = crop(screen.capture()?, region);
+        if diff::compare(&first, &frame).changed_percent > threshold {
+            return Ok(true);
+        }
+        if started.elapsed() >= timeout {
+            return Ok(false);
+        }
+    }
+}
+
+// Wait until `frames` consecutive captures are identical, e.g. once a page
+// has finished loading and animating. Returns whether they were before the
+// timeout.
+pub fn wait_for_stable(
+    screen: &mut dyn ScreenSource,
+    frames: u32,
+    timeout: Duration,
+    interrupted: &dyn Fn() -> bool,
+) -> Result<bool, CaptureError> {
+    let started = Instant::now();
+    let mut previous = screen.capture()?;
+    let mut matching = 1;
+    while matching < frames {
+        if started.elapsed() >= timeout || interrupted() {
+            return Ok(false);
+        }
+        sleep(POLL_INTERVAL.min(timeout.saturating_sub(started.elapsed())));
+        let frame = screen.capture()?;
+        if diff::compare(&previous, &frame).is_unchanged() {
+            matching += 1;
+        } else {
+            matching = 1;
+        }
+        previous = frame;
+    }
+    Ok(true)
+}
+
+// Wait until a window whose title contains `title`, ignoring case, is open.
+// Returns its full title, or None after the timeout.
+pub fn wait_for_window(
+    screen: &mut dyn ScreenSource,
+    title: &str,
+    timeout: Duration,
+    interrupted: &dyn Fn() -> bool,
+) -> Result<Option<String>, CaptureError> {
+    let started = Instant::now();
+    let title = title.to_lowercase();
+    loop {
+        let found = screen
+            .window_titles()?
+            .into_iter()
+            .find(|window| window.to_lowercase().contains(&title));
+        if found.is_some() {
+            return Ok(found);
+        }
+        if started.elapsed() >= timeout || interrupted() {
+            return Ok(None);
+        }
+        sleep(POLL_INTERVAL.min(timeout.saturating_sub(started.elapsed())));
+    }
+}
+
+// The region [x1, y1, x2, y2] of a frame, clamped to it
+fn crop(image: RgbaImage, region: Option<[i32; 4]>) -> RgbaImage {
+    let Some([x1, y1, x2, y2]) = region else {
+        return image;
+    };
+    let (width, height) = image.dimensions();
+    let x1 = x1.clamp(0, width as i32) as u32;
+    let y1 = y1.clamp(0, height as i32) as u32;
+    let x2 = (x2.max(0) as u32 + 1).clamp(x1, width);
+    let y2 = (y2.max(0) as u32 + 1).clamp(y1, height);
+    imageops::crop_imm(&image, x1, y1, x2 - x1, y2 - y1).to_image()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+    use std::collections::VecDeque;
+
+    // Plays the given frames in order, then repeats the last one
+    struct Frames(VecDeque<RgbaImage>);
+
+    impl ScreenSource for Frames {
+        fn capture(&mut self) -> Result<RgbaImage, CaptureError> {
+            match self.0.len() {
+                1 => Ok(self.0[0].clone()),
+                _ => Ok(self.0.pop_front().unwrap()),
+            }
+        }
+    }
+
+    fn frame(shade: u8) -> RgbaImage {
+        RgbaImage::from_pixel(32, 32, Rgba([shade, shade, shade, 255]))
+    }
+
+    #[test]
+    fn change_outside_the_region_is_not_waited_for() {
+        let mut changed = frame(0);
+        changed.put_pixel(30, 30, Rgba([255, 255, 255, 255]));
+        let mut screen = Frames(VecDeque::from([frame(0), changed.clone()]));
+        let timeout = Duration::ZERO;
+        let region = Some([0, 0, 15, 15]);
+        assert!(!wait_for_change(&mut screen, region, 0.0, timeout, &|| false).unwrap());
+
+        let mut screen = Frames(VecDeque::from([frame(0), changed]));
+        let region = Some([16, 16, 31, 31]);
+        assert!(wait_for_change(&mut screen, region, 0.0, timeout, &|| false).unwrap());
+    }
+
+    #[test]
+    fn stable_after_the_frames_stop_changing() {
+        let timeout = Duration::from_secs(5);
+        let mut screen = Frames(VecDeque::from([frame(0), frame(100), frame(200)]));
+        assert!(wait_for_stable(&mut screen, 3, timeout, &|| false).unwrap());
+
+        let mut screen = Frames(VecDeque::from([frame(0), frame(100), frame(200)]));
+        assert!(!wait_for_stable(&mut screen, 3, Duration::ZERO, &|| false).unwrap());
+    }
+
+    #[test]
+    fn waits_end_when_interrupted() {
+        let timeout = Duration::from_secs(60);
+        let mut screen = Frames(VecDeque::from([frame(0)]));
+        assert!(!wait_for_stable(&mut screen, 3, timeout, &|| true).unwrap());
+        assert!(!wait_for_change(&mut screen, None, 0.0, timeout, &|| true).unwrap());
+        assert!(!sleep_for(timeout, &|| true));
+        assert!(sleep_for(Duration::from_millis(1), &|| false));
+    }
+}
@@ -0,0 +1,497 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use xcb::{Xid, x};
+
+// Finding and raising windows by name instead of cycling through them with
+// Alt+Tab. The X11 backend talks EWMH to the window manager; without one
+// (Wayland, no display) the agent falls back to the keyboard.
+pub trait WindowManager {
+    // Every managed top-level window, in the window manager's stacking order
+    fn list(&self) -> Result<Vec<WindowInfo>, WindowError>;
+
+    // Ask the window manager to raise and focus a window
+    fn activate(&self, id: u32) -> Result<(), WindowError>;
+
+    // Move the contents of a window to (x, y) and/or resize them; fields
+    // left as None keep their value
+    fn move_resize(
+        &self,
+        id: u32,
+        position: Option<(i32, i32)>,
+        size: Option<(u32, u32)>,
+    ) -> Result<(), WindowError>;
+
+    fn maximize(&self, id: u32) -> Result<(), WindowError>;
+
+    fn minimize(&self, id: u32) -> Result<(), WindowError>;
+
+    // Ask a window to close, as its close button would; the application may
+    // still ask for confirmation
+    fn close(&self, id: u32) -> Result<(), WindowError>;
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WindowInfo {
+    pub id: u32,
+    pub title: String,
+    // WM_CLASS instance and class names, e.g. "navigator" and "firefox"
+    pub instance: String,
+    pub class: String,
+    // Position and size of the window's contents on the desktop, in the
+    // coordinates the input backend uses
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+    pub active: bool,
+    #[serde(default)]
+    pub maximized: bool,
+    #[serde(default)]
+    pub minimized: bool,
+}
+
+impl WindowInfo {
+    fn matches_class(&self, class: &str) -> bool {
+        let class = class.to_lowercase();
+        self.class.to_lowercase().contains(&class) || self.instance.to_lowercase().contains(&class)
+    }
+}
+
+#[derive(Debug)]
+pub enum WindowError {
+    Connection(xcb::ConnError),
+    X11(xcb::Error),
+    // The window manager does not publish the EWMH properties we rely on
+    Unsupported(&'static str),
+    NoWindow(u32),
+}
+
+impl fmt::Display for WindowError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WindowError::Connection(e) => write!(f, "could not connect to the X server: {}", e),
+            WindowError::X11(e) => write!(f, "X11 request failed: {}", e),
+            WindowError::Unsupported(property) => {
+                write!(f, "the window manager does not support {}", property)
+            }
+            WindowError::NoWindow(id) => write!(f, "window 0x{:x} is gone", id),
+        }
+    }
+}
+
+impl std::error::Error for WindowError {}
+
+impl From<xcb::ConnError> for WindowError {
+    fn from(e: xcb::ConnError) -> Self {
+        WindowError::Connection(e)
+    }
+}
+
+impl From<xcb::Error> for WindowError {
+    fn from(e: xcb::Error) -> Self {
+        WindowError::X11(e)
+    }
+}
+
+// Pixels a window may end up off the requested position or size, since
+// window managers keep windows on screen and terminals resize by whole cells
+const TOLERANCE: u32 = 32;
+
+// What a window should look like once a request about it took effect
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WindowGoal {
+    Active,
+    Position(i32, i32),
+    Size(u32, u32),
+    Maximized,
+    Minimized,
+    Closed,
+}
+
+impl WindowGoal {
+    // Whether the window, as read back, is there; None means it is gone
+    pub fn reached(self, window: Option<&WindowInfo>) -> bool {
+        let Some(window) = window else {
+            return self == WindowGoal::Closed;
+        };
+        match self {
+            WindowGoal::Active => window.active,
+            WindowGoal::Position(x, y) => {
+                window.x.abs_diff(x) <= TOLERANCE && window.y.abs_diff(y) <= TOLERANCE
+            }
+            WindowGoal::Size(width, height) => {
+                window.width.abs_diff(width) <= TOLERANCE
+                    && window.height.abs_diff(height) <= TOLERANCE
+            }
+            WindowGoal::Maximized => window.maximized,
+            WindowGoal::Minimized => window.minimized,
+            WindowGoal::Closed => false,
+        }
+    }
+}
+
+// Pick the window a window_focus action means. A window matching both the
+// title and the class wins, then one matching the title alone, then one of
+// the class. Titles and classes match as case-insensitive substrings and an
+// exact title beats a partial one.
+pub fn find<'a>(windows: &'a [WindowInfo], title: &str, class: &str) -> Option<&'a WindowInfo> {
+    let title = title.to_lowercase();
+    windows
+        .iter()
+        .filter_map(|window| {
+            let window_title = window.title.to_lowercase();
+            let title_score = if window_title == title {
+                2
+            } else if !title.is_empty() && window_title.contains(&title) {
+                1
+            } else {
+                0
+            };
+            let class_score = (!class.is_empty() && window.matches_class(class)) as i32;
+            let score = title_score * 2 + class_score;
+            (score > 0).then_some((score, window))
+        })
+        // The first of equally good windows, i.e. the lowest in the stack
+        .rev()
+        .max_by_key(|(score, _)| *score)
+        .map(|(_, window)| window)
+}
+
+// List the windows for the prompt
+pub fn describe(windows: &[WindowInfo]) -> String {
+    windows
+        .iter()
+        .map(|window| {
+            let state = [
+                (window.active, " [active]"),
+                (window.maximized, " [maximized]"),
+                (window.minimized, " [minimized]"),
+            ]
+            .iter()
+            .filter(|(set, _)| *set)
+            .map(|(_, label)| *label)
+            .collect::<String>();
+            format!(
+                "- \"{}\" ({}) {}x{} at ({}, {}){}",
+                window.title, window.class, window.width, window.height, window.x, window.y, state
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// EWMH window management through a connection to the X server in $DISPLAY
+pub struct X11WindowManager {
+    connection: xcb::Connection,
+    root: x::Window,
+    atoms: Atoms,
+}
+
+struct Atoms {
+    client_list: x::Atom,
+    active_window: x::Atom,
+    wm_name: x::Atom,
+    utf8_string: x::Atom,
+    wm_state: x::Atom,
+    maximized_vert: x::Atom,
+    maximized_horz: x::Atom,
+    hidden: x::Atom,
+    moveresize_window: x::Atom,
+    close_window: x::Atom,
+    wm_change_state: x::Atom,
+}
+
+impl X11WindowManager {
+    // Connect to the given X display, or the one DISPLAY names
+    pub fn connect(display: Option<&str>) -> Result<Self, WindowError> {
+        let (connection, screen_number) = xcb::Connection::connect(display)?;
+        let root = connection
+            .get_setup()
+            .roots()
+            .nth(screen_number as usize)
+            .ok_or(WindowError::Unsupported("the default screen"))?
+            .root();
+        let intern = |name: &[u8]| -> Result<x::Atom, WindowError> {
+            let cookie = connection.send_request(&x::InternAtom {
+                only_if_exists: false,
+                name,
+            });
+            Ok(connection.wait_for_reply(cookie)?.atom())
+        };
+        let atoms = Atoms {
+            client_list: intern(b"_NET_CLIENT_LIST")?,
+            active_window: intern(b"_NET_ACTIVE_WINDOW")?,
+            wm_name: intern(b"_NET_WM_NAME")?,
+            utf8_string: intern(b"UTF8_STRING")?,
+            wm_state: intern(b"_NET_WM_STATE")?,
+            maximized_vert: intern(b"_NET_WM_STATE_MAXIMIZED_VERT")?,
+            maximized_horz: intern(b"_NET_WM_STATE_MAXIMIZED_HORZ")?,
+            hidden: intern(b"_NET_WM_STATE_HIDDEN")?,
+            moveresize_window: intern(b"_NET_MOVERESIZE_WINDOW")?,
+            close_window: intern(b"_NET_CLOSE_WINDOW")?,
+            wm_change_state: intern(b"WM_CHANGE_STATE")?,
+        };
+        let manager = X11WindowManager {
+            connection,
+            root,
+            atoms,
+        };
+
+        // A window manager without EWMH does not keep a client list
+        let client_list = manager.property(root, manager.atoms.client_list, x::ATOM_WINDOW)?;
+        if client_list.r#type() == x::ATOM_NONE {
+            return Err(WindowError::Unsupported("_NET_CLIENT_LIST"));
+        }
+        Ok(manager)
+    }
+
+    fn property(
+        &self,
+        window: x::Window,
+        property: x::Atom,
+        r#type: x::Atom,
+    ) -> Result<x::GetPropertyReply, WindowError> {
+        let cookie = self.connection.send_request(&x::GetProperty {
+            delete: false,
+            window,
+            property,
+            r#type,
+            long_offset: 0,
+            long_length: 4096,
+        });
+        Ok(self.connection.wait_for_reply(cookie)?)
+    }
+
+    fn windows(&self, property: x::Atom) -> Result<Vec<x::Window>, WindowError> {
+        let reply = self.property(self.root, property, x::ATOM_WINDOW)?;
+        if reply.format() != 32 {
+            return Ok(Vec::new());
+        }
+        Ok(reply.value::<x::Window>().to_vec())
+    }
+
+    fn states(&self, window: x::Window) -> Vec<x::Atom> {
+        match self.property(window, self.atoms.wm_state, x::ATOM_ATOM) {
+            Ok(reply) if reply.format() == 32 => reply.value::<x::Atom>().to_vec(),
+            _ => Vec::new(),
+        }
+    }
+
+    fn text(&self, window: x::Window, property: x::Atom, r#type: x::Atom) -> Option<Vec<u8>> {
+        let reply = self.property(window, property, r#type).ok()?;
+        (reply.format() == 8).then(|| reply.value::<u8>().to_vec())
+    }
+
+    fn info(
+        &self,
+        window: x::Window,
+        active: Option<x::Window>,
+    ) -> Result<WindowInfo, WindowError> {
+        let title = self
+            .text(window, self.atoms.wm_name, self.atoms.utf8_string)
+            .or_else(|| self.text(window, x::ATOM_WM_NAME, x::ATOM_STRING))
+            .map(|title| String::from_utf8_lossy(&title).into_owned())
+            .unwrap_or_default();
+        // WM_CLASS holds the instance and the class, each NUL-terminated
+        let wm_class = self
+            .text(window, x::ATOM_WM_CLASS, x::ATOM_STRING)
+            .unwrap_or_default();
+        let mut names = wm_class
+            .split(|byte| *byte == 0)
+            .map(|name| String::from_utf8_lossy(name).into_owned());
+        let instance = names.next().unwrap_or_default();
+        let class = names.next().unwrap_or_default();
+
+        let geometry = self.connection.send_request(&x::GetGeometry {
+            drawable: x::Drawable::Window(window),
+        });
+        let geometry = self.connection.wait_for_reply(geometry)?;
+        let position = self.connection.send_request(&x::TranslateCoordinates {
+            src_window: window,
+            dst_window: self.root,
+            src_x: 0,
+            src_y: 0,
+        });
+        let position = self.connection.wait_for_reply(position)?;
+        let states = self.states(window);
+
+        Ok(WindowInfo {
+            id: window.resource_id(),
+            title,
+            instance,
+            class,
+            x: position.dst_x() as i32,
+            y: position.dst_y() as i32,
+            width: geometry.width() as u32,
+            height: geometry.height() as u32,
+            active: active == Some(window),
+            maximized: states.contains(&self.atoms.maximized_vert)
+                && states.contains(&self.atoms.maximized_horz),
+            minimized: states.contains(&self.atoms.hidden),
+        })
+    }
+
+    // The client window with the given id, if it is still managed
+    fn client(&self, id: u32) -> Result<x::Window, WindowError> {
+        self.windows(self.atoms.client_list)?
+            .into_iter()
+            .find(|window| window.resource_id() == id)
+            .ok_or(WindowError::NoWindow(id))
+    }
+
+    // Send an EWMH request about a window to the window manager
+    fn request(
+        &self,
+        window: x::Window,
+        message: x::Atom,
+        data: [u32; 5],
+    ) -> Result<(), WindowError> {
+        let event = x::ClientMessageEvent::new(window, message, x::ClientMessageData::Data32(data));
+        self.connection
+            .send_and_check_request(&x::SendEvent {
+                propagate: false,
+                destination: x::SendEventDest::Window(self.root),
+                event_mask: x::EventMask::SUBSTRUCTURE_NOTIFY | x::EventMask::SUBSTRUCTURE_REDIRECT,
+                event: &event,
+            })
+            .map_err(|e| WindowError::X11(xcb::Error::Protocol(e)))
+    }
+}
+
+impl WindowManager for X11WindowManager {
+    fn list(&self) -> Result<Vec<WindowInfo>, WindowError> {
+        let active = self.windows(self.atoms.active_window)?.first().copied();
+        self.windows(self.atoms.client_list)?
+            .into_iter()
+            // Windows can close between listing and querying them
+            .filter_map(|window| match self.info(window, active) {
+                Err(WindowError::X11(xcb::Error::Protocol(_))) => None,
+                info => Some(info),
+            })
+            .collect()
+    }
+
+    fn activate(&self, id: u32) -> Result<(), WindowError> {
+        let window = self.client(id)?;
+        // Source indication 2 says the request comes from a pager-like tool,
+        // which window managers honour without focus-stealing prevention
+        self.request(window, self.atoms.active_window, [2, 0, 0, 0, 0])
+    }
+
+    fn move_resize(
+        &self,
+        id: u32,
+        position: Option<(i32, i32)>,
+        size: Option<(u32, u32)>,
+    ) -> Result<(), WindowError> {
+        let window = self.client(id)?;
+        // Static gravity places the window's contents rather than its frame
+        // at (x, y); bits 8 to 11 say which of x, y, width and height are set,
+        // and bit 13 marks a pager-like source
+        let mut flags = 10 | 2 << 12;
+        let (x, y) = position.unwrap_or_default();
+        let (width, height) = size.unwrap_or_default();
+        if position.is_some() {
+            flags |= 1 << 8 | 1 << 9;
+        }
+        if size.is_some() {
+            flags |= 1 << 10 | 1 << 11;
+        }
+        // A maximized window ignores moves and resizes
+        self.request(
+            window,
+            self.atoms.wm_state,
+            [
+                0,
+                self.atoms.maximized_vert.resource_id(),
+                self.atoms.maximized_horz.resource_id(),
+                2,
+                0,
+            ],
+        )?;
+        self.request(
+            window,
+            self.atoms.moveresize_window,
+            [flags, x as u32, y as u32, width, height],
+        )
+    }
+
+    fn maximize(&self, id: u32) -> Result<(), WindowError> {
+        let window = self.client(id)?;
+        // Action 1 adds both states to the window
+        self.request(
+            window,
+            self.atoms.wm_state,
+            [
+                1,
+                self.atoms.maximized_vert.resource_id(),
+                self.atoms.maximized_horz.resource_id(),
+                2,
+                0,
+            ],
+        )
+    }
+
+    fn minimize(&self, id: u32) -> Result<(), WindowError> {
+        let window = self.client(id)?;
+        // EWMH leaves minimizing to ICCCM: ask for the iconic state
+        self.request(window, self.atoms.wm_change_state, [3, 0, 0, 0, 0])
+    }
+
+    fn close(&self, id: u32) -> Result<(), WindowError> {
+        let window = self.client(id)?;
+        self.request(window, self.atoms.close_window, [0, 2, 0, 0, 0])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window(id: u32, title: &str, class: &str) -> WindowInfo {
+        WindowInfo {
+            id,
+            title: title.to_string(),
+            instance: class.to_lowercase(),
+            class: class.to_string(),
+            x: 0,
+            y: 0,
+            width: 800,
+            height: 600,
+            active: false,
+            maximized: false,
+            minimized: false,
+        }
+    }
+
+    #[test]
+    fn finds_the_best_matching_window() {
+        let windows = [
+            window(1, "Terminal", "Gnome-terminal"),
+            window(2, "Mozilla Firefox", "firefox"),
+            window(3, "Firefox Settings - Mozilla Firefox", "firefox"),
+            window(4, "notes.txt - Firefox notes", "Gedit"),
+        ];
+        let id = |title, class| find(&windows, title, class).map(|window| window.id);
+
+        assert_eq!(id("mozilla firefox", "firefox"), Some(2));
+        assert_eq!(id("Firefox", "firefox"), Some(2));
+        assert_eq!(id("notes", ""), Some(4));
+        assert_eq!(id("Untitled", "gnome-terminal"), Some(1));
+        assert_eq!(id("Untitled", ""), None);
+    }
+
+    #[test]
+    fn goals_are_read_back_from_the_window() {
+        let mut moved = window(1, "Terminal", "Gnome-terminal");
+        (moved.x, moved.y) = (980, 24);
+
+        assert!(WindowGoal::Position(960, 0).reached(Some(&moved)));
+        assert!(!WindowGoal::Position(0, 0).reached(Some(&moved)));
+        assert!(WindowGoal::Size(810, 590).reached(Some(&moved)));
+        assert!(!WindowGoal::Maximized.reached(Some(&moved)));
+        assert!(!WindowGoal::Closed.reached(Some(&moved)));
+        assert!(WindowGoal::Closed.reached(None));
+        assert!(!WindowGoal::Active.reached(None));
+    }
+}
@@ -0,0 +1,89 @@
+// Drives the whole agent loop offline: the model is a script, the screen is
+// synthetic and --dry-run records the input instead of sending it
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+const SCRIPT: &str = r#"{
+    "analysis": [{
+        "context": "An empty desktop",
+        "ui_elements": [{"type": "button", "coords": [10, 10, 50, 30]}],
+        "state": {
+            "focused_element": null,
+            "selected_text": null,
+            "active_window": "Desktop",
+            "window_title": "Desktop",
+            "window_class": "desktop",
+            "target_window": null
+        },
+        "challenges": []
+    }],
+    "planning": {
+        "0": [
+            {"action": "click_at", "x": 30, "y": 20},
+            {"action": "text_input", "text": "hello"}
+        ]
+    }
+}"#;
+
+#[test]
+fn replays_a_scripted_session_until_the_script_ends() {
+    let dir = std::env::temp_dir().join(format!("automation-scripted-run-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("script.json"), SCRIPT).unwrap();
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_automation"))
+        .arg("--dry-run")
+        .current_dir(&dir)
+        .env("LLM_SCRIPT", dir.join("script.json"))
+        .env("SCREEN_SOURCE", "synthetic:320x240")
+        .env("PLANNER", "prompt")
+        .env("VERIFY", "none")
+        .env_remove("MARKS")
+        .env_remove("GRID")
+        .env_remove("POLICY")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"click the button\n")
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}", stdout);
+    assert!(stdout.contains("no more responses"), "{}", stdout);
+
+    // One iteration ran the plan; the next ended the run at its analysis
+    let sessions: Vec<_> = fs::read_dir(dir.join("target/sessions"))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    assert_eq!(sessions.len(), 1);
+    let iterations = iteration_dirs(&sessions[0]);
+    assert_eq!(iterations.len(), 2, "{:?}", iterations);
+
+    let actions = fs::read_to_string(iterations[0].join("actions.json")).unwrap();
+    assert!(actions.contains("click_at") && actions.contains("hello"));
+    assert!(iterations[0].join("dry_run.png").exists());
+    let state = fs::read_to_string(sessions[0].join("task_state.json")).unwrap();
+    assert!(state.contains("no scripted response for Analysis in iteration 1"));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+fn iteration_dirs(session: &Path) -> Vec<std::path::PathBuf> {
+    let mut dirs: Vec<_> = fs::read_dir(session.join("iterations"))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.is_dir())
+        .collect();
+    dirs.sort();
+    dirs
+}